@@ -1,61 +1,53 @@
-use std::sync::Arc;
+use std::time::Instant;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use laminar::{Config, DeliveryMethod, ProtocolVersion, VirtualConnection};
+use laminar::{Config, DeliveryGuarantee, VirtualConnection, PROTOCOL_VERSION};
 
 const SERVER_ADDR: &str = "127.0.0.1:12345";
-const CLIENT_ADDR: &str = "127.0.0.1:12346";
-
-fn process_packet_before_send(
-    connection: &mut VirtualConnection,
-    _config: &Config,
-    delivery_method: DeliveryMethod,
-) {
-    let payload = vec![1, 2, 3, 4, 5];
-
-    let _packet_data = connection
-        .process_outgoing(&payload, delivery_method)
-        .unwrap();
-}
 
 fn process_packet_when_received(connection: &mut VirtualConnection, data: &[u8]) {
-    connection.process_incoming(&data).unwrap();
+    connection.process_incoming(data, Instant::now()).unwrap();
 }
 
 /// This is mimicking the `HeaderParser for StandardHeader` implementation which is no longer
 /// visible externally
-fn standard_header_bytes(delivery_method: DeliveryMethod) -> Vec<u8> {
+fn standard_header_bytes(delivery_guarantee: DeliveryGuarantee) -> Vec<u8> {
     let mut buffer = Vec::new();
-    buffer.write_u16::<BigEndian>(ProtocolVersion::get_crc16());
+    buffer.write_u16::<BigEndian>(PROTOCOL_VERSION).unwrap();
     // Represents a standard `Packet`
-    buffer.write_u8(0);
-    buffer.write_u8(delivery_method as u8);
+    buffer.write_u8(0).unwrap();
+    buffer.write_u8(delivery_guarantee as u8).unwrap();
+    // Represents `OrderingGuarantee::None`
+    buffer.write_u8(0).unwrap();
     buffer
 }
 
 /// This is mimicking the `HeaderParser for AckedPacketHeader` implementation which is no longer
 /// visible externally
 fn acked_header_bytes(
-    delivery_method: DeliveryMethod,
+    delivery_guarantee: DeliveryGuarantee,
     seq: u16,
     ack_seq: u16,
     ack_field: u32,
 ) -> Vec<u8> {
-    let mut buffer = standard_header_bytes(delivery_method);
-    buffer.write_u16::<BigEndian>(seq);
-    buffer.write_u16::<BigEndian>(ack_seq);
-    buffer.write_u32::<BigEndian>(ack_field);
+    let mut buffer = standard_header_bytes(delivery_guarantee);
+    buffer.write_u16::<BigEndian>(seq).unwrap();
+    buffer.write_u16::<BigEndian>(ack_seq).unwrap();
+    buffer.write_u32::<BigEndian>(ack_field).unwrap();
     buffer
 }
 
 fn receive_unreliable_benchmark(c: &mut Criterion) {
-    let mut connection =
-        VirtualConnection::new(SERVER_ADDR.parse().unwrap(), &Arc::new(Config::default()));
+    let mut connection = VirtualConnection::new(
+        SERVER_ADDR.parse().unwrap(),
+        &Config::default(),
+        Instant::now(),
+    );
 
     // setup fake received bytes.
-    let mut buffer = standard_header_bytes(DeliveryMethod::UnreliableUnordered);
+    let mut buffer = standard_header_bytes(DeliveryGuarantee::Unreliable);
     buffer.append(&mut vec![1; 500]);
 
     c.bench_function("process unreliable packet on receive", move |b| {
@@ -64,11 +56,14 @@ fn receive_unreliable_benchmark(c: &mut Criterion) {
 }
 
 fn receive_reliable_benchmark(c: &mut Criterion) {
-    let mut connection =
-        VirtualConnection::new(SERVER_ADDR.parse().unwrap(), &Arc::new(Config::default()));
+    let mut connection = VirtualConnection::new(
+        SERVER_ADDR.parse().unwrap(),
+        &Config::default(),
+        Instant::now(),
+    );
 
     // setup fake received bytes.
-    let mut buffer = acked_header_bytes(DeliveryMethod::ReliableUnordered, 0, 1, 2);
+    let mut buffer = acked_header_bytes(DeliveryGuarantee::Reliable, 0, 1, 2);
     buffer.append(&mut vec![1; 500]);
 
     c.bench_function("process reliable packet on receive", move |b| {
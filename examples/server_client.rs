@@ -62,6 +62,7 @@ fn client() -> Result<(), ErrorKind> {
     loop {
         s_buffer.clear();
         stdin.read_line(&mut s_buffer)?;
+        #[allow(clippy::manual_pattern_char_comparison)]
         let line = s_buffer.replace(|x| x == '\n' || x == '\r', "");
 
         socket.send(Packet::reliable_unordered(
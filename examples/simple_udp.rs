@@ -70,6 +70,7 @@ pub fn main() {
     // Coords { longitude: 5.4545, latitude: 3.344, altitude: 1.33 }
     // Text { string: "Some information" }
     while let Some(pkt) = server.recv() {
+        #[allow(clippy::single_match)]
         match pkt {
             SocketEvent::Packet(pkt) => {
                 println!["{:?}", deserialize::<DataType>(pkt.payload()).unwrap()]
@@ -27,7 +27,8 @@ pub fn send_data() -> Result<()> {
     let packet = construct_packet();
 
     // next send or packet to the endpoint we earlier putted into the packet.
-    socket.send(packet)
+    socket.send(packet)?;
+    Ok(())
 }
 
 /// This is an example of how to receive data over udp.
@@ -38,6 +39,7 @@ pub fn receive_data() {
     // Next start receiving.
     loop {
         if let Some(result) = socket.recv() {
+            #[allow(clippy::single_match)]
             match result {
                 SocketEvent::Packet(packet) => {
                     let endpoint: SocketAddr = packet.addr();
@@ -0,0 +1,75 @@
+#![no_main]
+
+use std::time::Instant;
+
+use laminar::{
+    header::{AckedPacketHeader, FragmentHeader, HeaderWriter, StandardHeader},
+    Config, DeliveryGuarantee, OrderingGuarantee, PacketType, VirtualConnection,
+};
+use libfuzzer_sys::fuzz_target;
+
+const FRAGMENT_SIZE: u16 = 8;
+
+// Splits a message into fragments and delivers them in the order given by the input, followed by a
+// duplicate of one of them. Input layout: the sequence number, the fragment that is duplicated, a
+// byte per possible fragment to order them by, and the message.
+fuzz_target!(|data: &[u8]| {
+    let config = Config {
+        fragment_size: FRAGMENT_SIZE,
+        ..Config::default()
+    };
+    let max_fragments = usize::from(config.max_fragments);
+
+    if data.len() < 2 + max_fragments {
+        return;
+    }
+    let (sequence, duplicate) = (u16::from(data[0]), usize::from(data[1]));
+    let (order_keys, message) = data[2..].split_at(max_fragments);
+
+    let fragments: Vec<&[u8]> = message
+        .chunks(usize::from(FRAGMENT_SIZE))
+        .take(max_fragments)
+        .collect();
+    if fragments.is_empty() {
+        return;
+    }
+    let message = fragments.concat();
+
+    let mut order: Vec<usize> = (0..fragments.len()).collect();
+    order.sort_by_key(|&id| order_keys[id]);
+    order.push(order[duplicate % order.len()]);
+
+    let time = Instant::now();
+    let mut connection = VirtualConnection::new("127.0.0.1:12345".parse().unwrap(), &config, time);
+
+    let mut reassembled = Vec::new();
+    let mut ack_sequence = 0u16;
+    for id in order {
+        let mut datagram = Vec::new();
+        StandardHeader::new(
+            DeliveryGuarantee::Reliable,
+            OrderingGuarantee::None,
+            PacketType::Fragment,
+        )
+        .parse(&mut datagram)
+        .unwrap();
+        FragmentHeader::new(sequence, id as u8, fragments.len() as u8)
+            .parse(&mut datagram)
+            .unwrap();
+        AckedPacketHeader::new(ack_sequence, 0, 0)
+            .parse(&mut datagram)
+            .unwrap();
+        ack_sequence = ack_sequence.wrapping_add(1);
+        datagram.extend_from_slice(fragments[id]);
+
+        if let Ok(packets) = connection.process_incoming(&datagram, time) {
+            reassembled.extend(packets.into_iter().map(|(packet, _)| packet));
+        }
+    }
+
+    // a duplicate of the last fragment of a single fragment message is delivered like a resend.
+    assert!(!reassembled.is_empty());
+    for packet in reassembled {
+        assert_eq!(packet.payload(), message.as_slice());
+    }
+});
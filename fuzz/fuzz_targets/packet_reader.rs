@@ -0,0 +1,23 @@
+#![no_main]
+
+use laminar::PacketReader;
+use libfuzzer_sys::fuzz_target;
+
+// Reads every header from the datagram, whatever its standard header says it contains.
+fuzz_target!(|datagram: &[u8]| {
+    let mut reader = PacketReader::new(datagram);
+    if reader.read_standard_header().is_err() {
+        return;
+    }
+
+    let _ = reader.read_acknowledge_header();
+    let _ = reader.read_ack_header();
+    let _ = reader.read_aggregated_packets();
+    let _ = reader.read_fragment();
+    let _ = reader.read_payload();
+
+    for offset in 0..=datagram.len().min(usize::from(u16::MAX)) as u16 {
+        let _ = reader.read_arranging_header(offset);
+        let _ = reader.read_payload();
+    }
+});
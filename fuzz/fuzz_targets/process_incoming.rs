@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::time::{Duration, Instant};
+
+use laminar::{Config, VirtualConnection};
+use libfuzzer_sys::fuzz_target;
+
+// Feeds a sequence of datagrams to a single connection, every datagram is prefixed with its
+// length in one byte.
+fuzz_target!(|data: &[u8]| {
+    let mut time = Instant::now();
+    let mut connection =
+        VirtualConnection::new("127.0.0.1:12345".parse().unwrap(), &Config::default(), time);
+
+    let mut data = data;
+    while let Some((&length, rest)) = data.split_first() {
+        let length = usize::from(length).min(rest.len());
+        let (datagram, rest) = rest.split_at(length);
+        data = rest;
+
+        if let Ok(packets) = connection.process_incoming(datagram, time) {
+            for (packet, _) in packets {
+                assert_eq!(packet.addr(), connection.remote_address);
+            }
+        }
+        time += Duration::from_millis(10);
+    }
+});
@@ -0,0 +1,365 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Read},
+    process::exit,
+};
+
+use clap::{Arg, ArgMatches, Command};
+
+use laminar::{
+    header::{AckedPacketHeader, HeaderReader, StandardHeader},
+    DeliveryGuarantee, ErrorKind, OrderingGuarantee, PacketReader, PcapReader, Result,
+};
+
+fn main() {
+    let matches = Command::new("laminar-inspect")
+        .about("Decodes datagrams of the laminar wire format")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("hex")
+                .about(
+                    "Decodes hex encoded datagrams, read one per line from stdin if none are given",
+                )
+                .arg(Arg::new("DATAGRAM").num_args(0..)),
+        )
+        .subcommand(
+            Command::new("pcap")
+                .about("Decodes the UDP datagrams of a pcap capture")
+                .arg(Arg::new("FILE").required(true)),
+        )
+        .subcommand(
+            Command::new("raw")
+                .about("Decodes a single binary datagram, read from stdin if no file is given")
+                .arg(Arg::new("FILE")),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        Some(("hex", m)) => inspect_hex(m),
+        Some(("pcap", m)) => inspect_pcap(m),
+        Some(("raw", m)) => inspect_raw(m),
+        _ => unreachable!("a subcommand is required"),
+    };
+
+    if let Err(e) = result {
+        eprintln!("laminar-inspect: {}", e);
+        exit(1);
+    }
+}
+
+fn inspect_hex(args: &ArgMatches) -> io::Result<()> {
+    let datagrams: Vec<String> = match args.get_many::<String>("DATAGRAM") {
+        Some(datagrams) => datagrams.cloned().collect(),
+        None => io::stdin()
+            .lock()
+            .lines()
+            .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .collect::<io::Result<_>>()?,
+    };
+
+    for (index, datagram) in datagrams.iter().enumerate() {
+        match parse_hex(datagram) {
+            Some(bytes) => print_datagram(&format!("datagram {}", index + 1), &bytes),
+            None => println!("datagram {}: invalid hex\n", index + 1),
+        }
+    }
+    Ok(())
+}
+
+fn inspect_pcap(args: &ArgMatches) -> io::Result<()> {
+    let path = args.get_one::<String>("FILE").expect("FILE is required");
+    let reader = PcapReader::new(io::BufReader::new(File::open(path)?))?;
+
+    for (index, datagram) in reader.enumerate() {
+        let datagram = datagram?;
+        let title = format!(
+            "datagram {} at {}.{:06}, {} -> {}",
+            index + 1,
+            datagram.timestamp.as_secs(),
+            datagram.timestamp.subsec_micros(),
+            datagram.source,
+            datagram.destination
+        );
+        print_datagram(&title, &datagram.payload);
+    }
+    Ok(())
+}
+
+fn inspect_raw(args: &ArgMatches) -> io::Result<()> {
+    let mut datagram = Vec::new();
+    match args.get_one::<String>("FILE") {
+        Some(path) => File::open(path)?.read_to_end(&mut datagram)?,
+        None => io::stdin().lock().read_to_end(&mut datagram)?,
+    };
+
+    print_datagram("datagram", &datagram);
+    Ok(())
+}
+
+// Parses hex digits, which may be separated by whitespace or colons and prefixed with `0x`.
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    let digits: Vec<u8> = text
+        .strip_prefix("0x")
+        .unwrap_or(text)
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn print_datagram(title: &str, datagram: &[u8]) {
+    println!("{}: {} bytes", title, datagram.len());
+    for line in dissect(datagram) {
+        println!("  {}", line);
+    }
+    println!();
+}
+
+// Decodes the headers of a datagram the way a `VirtualConnection` processes it, returns a line
+// per header. Decoding stops at the first header that could not be read.
+fn dissect(datagram: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Err(e) = dissect_into(datagram, &mut lines) {
+        lines.push(format!("error: {}", describe_error(&e)));
+    }
+    lines
+}
+
+fn dissect_into(datagram: &[u8], lines: &mut Vec<String>) -> Result<()> {
+    let mut reader = PacketReader::new(datagram);
+
+    let header = reader.read_standard_header()?;
+    lines.push(format!(
+        "standard header: protocol version {}, type {:?}, delivery {:?}, ordering {}{}{}",
+        header.protocol_version(),
+        header.packet_type(),
+        header.delivery_guarantee(),
+        ordering_name(header.ordering_guarantee()),
+        if header.delivery_guarantee() == DeliveryGuarantee::Unreliable && header.is_acknowledged()
+        {
+            ", acknowledged"
+        } else {
+            ""
+        },
+        if header.is_encrypted() {
+            ", encrypted"
+        } else {
+            ""
+        }
+    ));
+
+    if !header.is_current_protocol() {
+        return Err(ErrorKind::ProtocolVersionMismatch);
+    }
+
+    if header.is_encrypted() {
+        // everything after the packet number can only be read with the keys of the connection.
+        let encrypted = reader.read_payload();
+        match encrypted.get(..8) {
+            Some(packet_number) => lines.push(format!(
+                "encrypted: packet number {}, {} bytes of encrypted headers, payload and tag",
+                u64::from_be_bytes(packet_number.try_into().unwrap()),
+                encrypted.len() - 8
+            )),
+            None => lines.push(String::from(
+                "error: the datagram is too short for the packet number",
+            )),
+        }
+        return Ok(());
+    }
+
+    if header.is_aggregate() {
+        for (index, packet) in reader.read_aggregated_packets()?.into_iter().enumerate() {
+            lines.push(format!(
+                "aggregated packet {}: {} bytes",
+                index + 1,
+                packet.len()
+            ));
+            lines.extend(
+                dissect(packet)
+                    .into_iter()
+                    .map(|line| format!("  {}", line)),
+            );
+        }
+        return Ok(());
+    }
+
+    if header.is_ack() {
+        let ack_header = reader.read_ack_header()?;
+        lines.push(format!(
+            "ack header: ack sequence {}, ack field {:032b}",
+            ack_header.ack_seq(),
+            ack_header.ack_field()
+        ));
+    } else if header.is_fragment() && header.delivery_guarantee() == DeliveryGuarantee::Reliable {
+        let (fragment_header, acked_header) = reader.read_fragment()?;
+        lines.push(format!(
+            "fragment header: sequence {}, fragment {} of {}",
+            fragment_header.sequence(),
+            fragment_header.id(),
+            fragment_header.fragment_count()
+        ));
+        lines.push(describe_acked_header(&acked_header));
+    } else if header.is_acknowledged() {
+        let acked_header = reader.read_acknowledge_header()?;
+        lines.push(describe_acked_header(&acked_header));
+    }
+
+    let is_data = !header.is_ack()
+        && !header.is_heartbeat()
+        && !header.is_disconnect()
+        && !header.is_fragment()
+        && !header.packet_type().is_handshake();
+    if is_data && header.ordering_guarantee() != OrderingGuarantee::None {
+        let mut offset = StandardHeader::size();
+        if header.is_acknowledged() {
+            offset += AckedPacketHeader::size();
+        }
+        let arranging_header = reader.read_arranging_header(u16::from(offset))?;
+        lines.push(format!(
+            "arranging header: arranging id {}, stream {}",
+            arranging_header.arranging_id(),
+            arranging_header.stream_id()
+        ));
+    }
+
+    lines.push(format!("payload: {} bytes", reader.read_payload().len()));
+    Ok(())
+}
+
+fn ordering_name(ordering: OrderingGuarantee) -> &'static str {
+    match ordering {
+        OrderingGuarantee::None => "None",
+        OrderingGuarantee::Sequenced(_) => "Sequenced",
+        OrderingGuarantee::Ordered(_) => "Ordered",
+    }
+}
+
+fn describe_acked_header(header: &AckedPacketHeader) -> String {
+    format!(
+        "acknowledgment header: sequence {}, ack sequence {}, ack field {:032b}",
+        header.sequence(),
+        header.ack_seq(),
+        header.ack_field()
+    )
+}
+
+fn describe_error(error: &ErrorKind) -> String {
+    match error {
+        ErrorKind::DecodingError(kind) => format!("malformed standard header, {}", kind),
+        ErrorKind::CouldNotReadHeader(header) => {
+            format!("the datagram is too short for the {} header", header)
+        }
+        ErrorKind::IOError(_) => String::from("the datagram ended in the middle of a header"),
+        error => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use laminar::{
+        header::{AckedPacketHeader, ArrangingHeader, HeaderWriter, StandardHeader},
+        DeliveryGuarantee, OrderingGuarantee, PacketType,
+    };
+
+    use super::{dissect, parse_hex};
+
+    fn standard_header(
+        packet_type: PacketType,
+        delivery: DeliveryGuarantee,
+        ordering: OrderingGuarantee,
+    ) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        StandardHeader::new(delivery, ordering, packet_type)
+            .parse(&mut buffer)
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn hex_is_parsed() {
+        assert_eq!(parse_hex("0x00 01:fF"), Some(vec![0, 1, 255]));
+        assert_eq!(parse_hex("123"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn reliable_ordered_packet_is_dissected() {
+        let mut datagram = standard_header(
+            PacketType::Packet,
+            DeliveryGuarantee::Reliable,
+            OrderingGuarantee::Ordered(None),
+        );
+        AckedPacketHeader::new(7, 6, 0b101)
+            .parse(&mut datagram)
+            .unwrap();
+        ArrangingHeader::new(3, 1).parse(&mut datagram).unwrap();
+        datagram.extend_from_slice(&[1, 2, 3, 4]);
+
+        let lines = dissect(&datagram);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("type Packet, delivery Reliable, ordering Ordered"));
+        assert_eq!(
+            lines[1],
+            format!(
+                "acknowledgment header: sequence 7, ack sequence 6, ack field {:032b}",
+                0b101
+            )
+        );
+        assert_eq!(lines[2], "arranging header: arranging id 3, stream 1");
+        assert_eq!(lines[3], "payload: 4 bytes");
+    }
+
+    #[test]
+    fn encrypted_packet_is_not_dissected() {
+        let mut datagram = Vec::new();
+        StandardHeader::new(
+            DeliveryGuarantee::Reliable,
+            OrderingGuarantee::None,
+            PacketType::Packet,
+        )
+        .with_encryption()
+        .parse(&mut datagram)
+        .unwrap();
+        datagram.extend_from_slice(&42u64.to_be_bytes());
+        datagram.extend_from_slice(&[0; 30]);
+
+        let lines = dissect(&datagram);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("ordering None, encrypted"));
+        assert_eq!(
+            lines[1],
+            "encrypted: packet number 42, 30 bytes of encrypted headers, payload and tag"
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_reported() {
+        let mut datagram = standard_header(
+            PacketType::Packet,
+            DeliveryGuarantee::Reliable,
+            OrderingGuarantee::None,
+        );
+        datagram.extend_from_slice(&[0, 1]);
+        let lines = dissect(&datagram);
+        assert_eq!(
+            lines.last().unwrap(),
+            "error: the datagram is too short for the acknowledgment header"
+        );
+
+        datagram[3] = 9;
+        assert_eq!(
+            dissect(&datagram),
+            vec!["error: malformed standard header, The delivery guarantee could not be read."]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::{default::Default, time::Duration};
 
+use crate::infrastructure::CongestionControl;
 use crate::net::constants::{DEFAULT_MTU, FRAGMENT_SIZE_DEFAULT, MAX_FRAGMENTS_DEFAULT};
+use crate::net::RateLimit;
 
 #[derive(Clone, Debug)]
 /// Contains the configuration options to configure laminar for special use-cases.
@@ -41,6 +43,21 @@ pub struct Config {
     ///
     /// Value which specifies the maximum round trip time before we consider it a problem. This is expressed in milliseconds.
     pub rtt_max_value: u16,
+    /// Value which specifies the maximum ratio of lost packets before we consider it a problem,
+    /// with 0 equal to 0% and 1 equal to 100%. Defaults to `0.05`.
+    pub packet_loss_max_value: f32,
+    /// Value which specifies how far the round trip time and packet loss have to drop below
+    /// `rtt_max_value` and `packet_loss_max_value` before the network quality is considered good
+    /// again. It is expressed as a ratio of those values and prevents flapping between a good and
+    /// bad network quality. Defaults to `0.8`.
+    pub network_quality_hysteresis: f32,
+    /// Value which specifies how long the network conditions have to stay good before a bad
+    /// network quality is considered good again. Defaults to `1s`.
+    pub network_quality_recovery_time: Duration,
+    /// Value which specifies how long the round trip time or packet loss have to stay above
+    /// `rtt_max_value` or `packet_loss_max_value` before a good network quality is considered bad,
+    /// so a single late or lost packet does not change it. Defaults to `500ms`.
+    pub network_quality_degradation_time: Duration,
     /// Value which can specify the event buffer we read socket events into.
     ///
     /// Value that specifies the size of the event buffer into which we receive socket events, in bytes. Defaults to 1024.
@@ -59,6 +76,117 @@ pub struct Config {
     /// The maximum number of unestablished connections that laminar will track internally. This is
     /// used to prevent malicious packet flooding from consuming an unbounded amount of memory.
     pub max_unestablished_connections: u16,
+    /// The number of times as many bytes that are sent to an address that has not completed the
+    /// handshake yet, as were received from it. This keeps hosts that spoof their source address
+    /// from using laminar to flood the address with datagrams.
+    ///
+    /// Only applies with `handshake_enabled`, as only the handshake proves that the remote host
+    /// receives the packets sent to its address. Defaults to `3`.
+    pub amplification_factor: usize,
+    /// Limits the number of datagrams that are received from a single IP address without a
+    /// connection, see `RateLimit`. Datagrams that exceed it are dropped before a connection is
+    /// created for them, so a single host cannot use up `max_unestablished_connections`.
+    /// Defaults to `None`.
+    pub address_rate_limit: Option<RateLimit>,
+    /// Limits the number of datagrams that are received from all IP addresses of a subnet without
+    /// a connection, like `address_rate_limit`. Defaults to `None`.
+    pub subnet_rate_limit: Option<RateLimit>,
+    /// The number of leading bits IPv4 addresses of the same subnet share, see
+    /// `subnet_rate_limit`. Defaults to `24`.
+    pub subnet_prefix_v4: u8,
+    /// The number of leading bits IPv6 addresses of the same subnet share, see
+    /// `subnet_rate_limit`. Defaults to `48`.
+    pub subnet_prefix_v6: u8,
+
+    /// Requires remote hosts to complete a challenge-response handshake before a connection is
+    /// established with them. Packets from unknown addresses will not create a connection until
+    /// the remote host has proven that it can receive packets on its source address. Disconnect
+    /// packets carry the challenge of the handshake, without it anyone who spoofs the address of
+    /// the remote host can close the connection.
+    ///
+    /// Both endpoints need to have this enabled. Defaults to `false`.
+    pub handshake_enabled: bool,
+    /// Value which specifies at which interval a handshake packet is resent while waiting for the
+    /// remote host to answer. Defaults to `100ms`.
+    pub handshake_resend_interval: Duration,
+    /// Value which specifies how long a handshake challenge stays valid after it has been issued.
+    /// Defaults to `5s`.
+    pub handshake_challenge_lifetime: Duration,
+    /// Encrypts and authenticates every packet of a connection, except the handshake packets,
+    /// so hosts on the network path can neither read nor alter them. The keys are established by
+    /// a key exchange during the handshake and are different for every connection.
+    ///
+    /// Enables the handshake as well, both endpoints need to have this enabled. Every encrypted
+    /// packet is 24 bytes larger. Defaults to `false`.
+    pub encryption_enabled: bool,
+    /// A secret key that the application shares with the remote hosts in advance, which is mixed
+    /// into the keys established by the handshake when `encryption_enabled` is set.
+    ///
+    /// Without it, a host that is able to intercept and alter the handshake packets can still
+    /// establish keys with both endpoints. With it, only hosts that know this key can read or
+    /// send packets. Defaults to `None`.
+    pub encryption_key: Option<[u8; 32]>,
+    /// The private key that connect tokens are signed with, see `ConnectToken`. When set, only
+    /// clients that present a valid connect token in their connection request are answered. A
+    /// token is rejected once it expired, or if it was already used from another address.
+    ///
+    /// This lets a backend decide which clients may connect to a server. Enables the handshake
+    /// as well. Defaults to `None`.
+    pub connect_token_key: Option<[u8; 32]>,
+    /// The connect token a client presents to the server in its connection request, which it got
+    /// from the backend that issued it, see `ConnectToken::to_bytes`. Defaults to `None`.
+    pub connect_token: Option<Vec<u8>>,
+    /// Value which specifies how long a connection that is being disconnected keeps resending
+    /// unacknowledged reliable packets before the disconnect packet is sent. Defaults to `1s`.
+    pub disconnect_flush_timeout: Duration,
+    /// Value which specifies how long to wait for an acknowledgment before a reliable packet is
+    /// resent, as long as the round trip time has not been measured yet. Defaults to `500ms`.
+    pub initial_retransmission_timeout: Duration,
+    /// Value which specifies the lower bound of the retransmission timeout, which is otherwise
+    /// derived from the measured round trip time. Defaults to `100ms`.
+    pub min_retransmission_timeout: Duration,
+    /// Value which specifies the upper bound of the retransmission timeout, which doubles every
+    /// time a packet has to be resent. Defaults to `2s`.
+    pub max_retransmission_timeout: Duration,
+    /// Value which specifies how the rate at which packets are sent to a remote host adapts to
+    /// the network conditions. Packets that exceed the send rate are queued on the connection
+    /// until they can be sent. Defaults to `CongestionControl::Disabled`.
+    pub congestion_control: CongestionControl,
+    /// Value which specifies the number of packets per second that are sent to a remote host on
+    /// a bad network, see `CongestionControl`. It is at least `1`. Defaults to `20`.
+    pub min_send_rate: u32,
+    /// Value which specifies the number of packets per second that are sent to a remote host on
+    /// a good network, see `CongestionControl`. Defaults to `250`.
+    pub max_send_rate: u32,
+    /// Value which specifies how many user packets are queued per remote host while congestion
+    /// control holds them back. Packets that are sent while the queue is full are dropped.
+    /// Defaults to `1024`.
+    pub max_paced_packets: usize,
+    /// Lets unreliable packets and heartbeats carry a sequence number and acknowledgment
+    /// information, like reliable packets do. This allows `SocketEvent::Acked` and
+    /// `SocketEvent::Lost` to be emitted for unreliable packets, at the cost of 8 additional bytes
+    /// per packet.
+    ///
+    /// With this enabled, a connection that mostly sends unreliable packets still acknowledges
+    /// the reliable packets of the remote host right away, and measures the round trip time and
+    /// packet loss of its unreliable packets as well.
+    ///
+    /// The remote host acknowledges those packets along with its reliable packets, or with all of
+    /// its packets if it has this enabled as well. Defaults to `false`.
+    pub ack_unreliable_packets: bool,
+    /// Value which specifies how long received packets wait for an outgoing packet to carry
+    /// their acknowledgment, before an ack-only packet is sent instead. Set to `None` to never
+    /// send ack-only packets. Defaults to 20 milliseconds.
+    ///
+    /// Without ack-only packets, a remote host that only receives reliable packets never
+    /// acknowledges them, so the connection is eventually dropped because of
+    /// `max_packets_in_flight`.
+    pub ack_delay: Option<Duration>,
+    /// Coalesces the packets that are sent to a remote host during a single poll into as few
+    /// datagrams as possible, each of them no larger than `receive_buffer_max_size`. This saves
+    /// bandwidth and system calls when many small packets are sent at once. Handshake packets are
+    /// always sent on their own. Defaults to `false`.
+    pub aggregate_packets: bool,
 }
 
 impl Default for Config {
@@ -74,10 +202,37 @@ impl Default for Config {
             receive_buffer_max_size: DEFAULT_MTU as usize,
             rtt_smoothing_factor: 0.10,
             rtt_max_value: 250,
+            packet_loss_max_value: 0.05,
+            network_quality_hysteresis: 0.8,
+            network_quality_recovery_time: Duration::from_secs(1),
+            network_quality_degradation_time: Duration::from_millis(500),
             socket_event_buffer_size: 1024,
             socket_polling_timeout: Some(Duration::from_millis(1)),
             max_packets_in_flight: 512,
             max_unestablished_connections: 50,
+            amplification_factor: 3,
+            address_rate_limit: None,
+            subnet_rate_limit: None,
+            subnet_prefix_v4: 24,
+            subnet_prefix_v6: 48,
+            handshake_enabled: false,
+            handshake_resend_interval: Duration::from_millis(100),
+            handshake_challenge_lifetime: Duration::from_secs(5),
+            encryption_enabled: false,
+            encryption_key: None,
+            connect_token_key: None,
+            connect_token: None,
+            disconnect_flush_timeout: Duration::from_secs(1),
+            initial_retransmission_timeout: Duration::from_millis(500),
+            min_retransmission_timeout: Duration::from_millis(100),
+            max_retransmission_timeout: Duration::from_secs(2),
+            congestion_control: CongestionControl::Disabled,
+            min_send_rate: 20,
+            max_send_rate: 250,
+            max_paced_packets: 1024,
+            ack_unreliable_packets: false,
+            ack_delay: Some(Duration::from_millis(20)),
+            aggregate_packets: false,
         }
     }
 }
@@ -22,6 +22,8 @@ pub enum ErrorKind {
     FragmentError(FragmentErrorKind),
     /// Error relating to receiving or parsing a packet
     PacketError(PacketErrorKind),
+    /// Error relating to encrypting or decrypting a packet
+    EncryptionError(EncryptionErrorKind),
     /// Wrapper around a std io::Error
     IOError(io::Error),
     /// Did not receive enough data
@@ -52,6 +54,11 @@ impl Display for ErrorKind {
                 "Something went wrong with receiving/parsing packets. Reason: {:?}.",
                 e
             ),
+            ErrorKind::EncryptionError(e) => write!(
+                fmt,
+                "Something went wrong with encrypting/decrypting packets. Reason: {:?}.",
+                e
+            ),
             ErrorKind::IOError(e) => write!(fmt, "An IO Error occurred. Reason: {:?}.", e),
             ErrorKind::ReceivedDataToShort => {
                 write!(fmt, "The received data did not have any length.")
@@ -139,6 +146,12 @@ pub enum FragmentErrorKind {
     MultipleAckHeaders,
     /// Ack header is missing from a finished set of fragments
     MissingAckHeader,
+    /// Fragment header announces a sequence of zero fragments
+    EmptyFragmentCount,
+    /// Fragment payload is bigger than the configured receive buffer
+    FragmentTooLarge,
+    /// Fragment payloads do not add up to the length of the reassembled packet
+    UnexpectedFragmentLength,
 }
 
 impl Display for FragmentErrorKind {
@@ -170,6 +183,49 @@ impl Display for FragmentErrorKind {
                 fmt,
                 "No ack headers were registered with any of the fragments."
             ),
+            FragmentErrorKind::EmptyFragmentCount => {
+                write!(fmt, "The fragment header announces zero fragments.")
+            }
+            FragmentErrorKind::FragmentTooLarge => write!(
+                fmt,
+                "The fragment payload is bigger than the receive buffer."
+            ),
+            FragmentErrorKind::UnexpectedFragmentLength => write!(
+                fmt,
+                "The fragment payloads do not add up to the length of the packet."
+            ),
+        }
+    }
+}
+
+/// Errors that could occur while encrypting or decrypting packets
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EncryptionErrorKind {
+    /// The keys of the connection have not been established yet
+    MissingKeys,
+    /// An unencrypted packet was received on a connection that requires encryption
+    UnencryptedPacket,
+    /// The packet was not encrypted with the key of the connection, or it was tampered with
+    AuthenticationFailed,
+    /// All packet numbers of the connection have been used
+    PacketNumberExhausted,
+}
+
+impl Display for EncryptionErrorKind {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            EncryptionErrorKind::MissingKeys => {
+                write!(fmt, "The keys of the connection are not established yet.")
+            }
+            EncryptionErrorKind::UnencryptedPacket => {
+                write!(fmt, "The packet was expected to be encrypted.")
+            }
+            EncryptionErrorKind::AuthenticationFailed => {
+                write!(fmt, "The packet could not be authenticated.")
+            }
+            EncryptionErrorKind::PacketNumberExhausted => {
+                write!(fmt, "No packet numbers are left for the connection.")
+            }
         }
     }
 }
@@ -192,6 +248,12 @@ impl From<FragmentErrorKind> for ErrorKind {
     }
 }
 
+impl From<EncryptionErrorKind> for ErrorKind {
+    fn from(inner: EncryptionErrorKind) -> Self {
+        ErrorKind::EncryptionError(inner)
+    }
+}
+
 impl From<crossbeam_channel::SendError<SocketEvent>> for ErrorKind {
     fn from(inner: SendError<SocketEvent>) -> Self {
         ErrorKind::SendError(inner)
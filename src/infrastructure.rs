@@ -3,9 +3,15 @@
 
 pub use self::acknowledgment::AcknowledgmentHandler;
 pub use self::acknowledgment::SentPacket;
+pub use self::aggregator::Aggregator;
+pub use self::congestion::{CongestionControl, CongestionHandler};
 pub use self::fragmenter::Fragmentation;
+pub use self::replay_protection::ReplayWindow;
 
 mod acknowledgment;
+mod aggregator;
+mod congestion;
 mod fragmenter;
+mod replay_protection;
 
 pub mod arranging;
@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
-use crate::packet::{OrderingGuarantee, PacketType, SequenceNumber};
+use crate::net::constants::REDUNDANT_PACKET_ACKS_SIZE;
+use crate::packet::{
+    header::FragmentHeader, MessageId, OrderingGuarantee, PacketType, SequenceNumber,
+};
 use crate::sequence_buffer::{sequence_greater_than, sequence_less_than, SequenceBuffer};
 
-const REDUNDANT_PACKET_ACKS_SIZE: u16 = 32;
 const DEFAULT_SEND_PACKETS_SIZE: usize = 256;
 
 /// Responsible for handling the acknowledgment of packets.
@@ -18,6 +21,14 @@ pub struct AcknowledgmentHandler {
     // However, we can only reasonably ack up to `REDUNDANT_PACKET_ACKS_SIZE + 1` packets on each
     // message we send so this should be that large.
     received_packets: SequenceBuffer<ReceivedPacket>,
+    // Unreliable packets that carry an acknowledgment header, they are never resent but we notify
+    // about their delivery and loss.
+    sent_unreliable_packets: HashMap<u16, SentUnreliablePacket>,
+    // Number of fragments of each fragmented message that are not acknowledged yet, by the
+    // sequence number of the message. A message is acknowledged once all its fragments are.
+    unacked_fragments: HashMap<SequenceNumber, u8>,
+    // Message ids of the packets that got acknowledged since they were last taken.
+    acked_messages: Vec<MessageId>,
 }
 
 impl AcknowledgmentHandler {
@@ -28,6 +39,9 @@ impl AcknowledgmentHandler {
             remote_ack_sequence_num: u16::max_value(),
             sent_packets: HashMap::with_capacity(DEFAULT_SEND_PACKETS_SIZE),
             received_packets: SequenceBuffer::with_capacity(REDUNDANT_PACKET_ACKS_SIZE + 1),
+            sent_unreliable_packets: HashMap::new(),
+            unacked_fragments: HashMap::new(),
+            acked_messages: Vec::new(),
         }
     }
 
@@ -74,28 +88,33 @@ impl AcknowledgmentHandler {
         &mut self,
         remote_seq_num: u16,
         remote_ack_seq: u16,
-        mut remote_ack_field: u32,
+        remote_ack_field: u32,
     ) {
+        self.received_packets
+            .insert(remote_seq_num, ReceivedPacket {});
+
+        self.process_acknowledgments(remote_ack_seq, remote_ack_field);
+    }
+
+    /// Acknowledges the packets the remote host received, according to the last sequence number
+    /// and the bitfield of the 32 sequence numbers before it.
+    pub fn process_acknowledgments(&mut self, remote_ack_seq: u16, remote_ack_field: u32) {
         // ensure that `self.remote_ack_sequence_num` is always increasing (with wrapping)
         if sequence_greater_than(remote_ack_seq, self.remote_ack_sequence_num) {
             self.remote_ack_sequence_num = remote_ack_seq;
         }
 
-        self.received_packets
-            .insert(remote_seq_num, ReceivedPacket {});
-
-        // the current `remote_ack_seq` was (clearly) received so we should remove it
-        self.sent_packets.remove(&remote_ack_seq);
-
         // The `remote_ack_field` is going to include whether or not the past 32 packets have been
-        // received successfully. If so, we have no need to resend old packets.
-        for i in 1..=REDUNDANT_PACKET_ACKS_SIZE {
-            let ack_sequence = remote_ack_seq.wrapping_sub(i);
-            if remote_ack_field & 1 == 1 {
-                self.sent_packets.remove(&ack_sequence);
+        // received successfully. If so, we have no need to resend old packets. Oldest packets
+        // are acknowledged first, so acknowledged messages are reported in the order they were sent.
+        for i in (1..=REDUNDANT_PACKET_ACKS_SIZE).rev() {
+            if remote_ack_field & (1 << (i - 1)) != 0 {
+                self.acknowledge(remote_ack_seq.wrapping_sub(i));
             }
-            remote_ack_field >>= 1;
         }
+
+        // the current `remote_ack_seq` was (clearly) received so we should remove it
+        self.acknowledge(remote_ack_seq);
     }
 
     /// Enqueues the outgoing packet for acknowledgment.
@@ -105,6 +124,8 @@ impl AcknowledgmentHandler {
         payload: &[u8],
         ordering_guarantee: OrderingGuarantee,
         item_identifier: Option<SequenceNumber>,
+        message_id: Option<MessageId>,
+        time: Instant,
     ) {
         self.sent_packets.insert(
             self.sequence_number,
@@ -113,6 +134,54 @@ impl AcknowledgmentHandler {
                 payload: Box::from(payload),
                 ordering_guarantee,
                 item_identifier,
+                message_id,
+                sent_time: time,
+                fragment: None,
+            },
+        );
+
+        // bump the local sequence number for the next outgoing packet
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+    }
+
+    /// Enqueues the outgoing fragment for acknowledgment, it is resent on its own when dropped.
+    pub fn process_outgoing_fragment(
+        &mut self,
+        fragment: FragmentHeader,
+        payload: &[u8],
+        ordering_guarantee: OrderingGuarantee,
+        message_id: Option<MessageId>,
+        time: Instant,
+    ) {
+        // a resent fragment is still counted from when it was sent first.
+        self.unacked_fragments
+            .entry(fragment.sequence())
+            .or_insert_with(|| fragment.fragment_count());
+
+        self.sent_packets.insert(
+            self.sequence_number,
+            SentPacket {
+                packet_type: PacketType::Fragment,
+                payload: Box::from(payload),
+                ordering_guarantee,
+                item_identifier: None,
+                message_id,
+                sent_time: time,
+                fragment: Some(fragment),
+            },
+        );
+
+        // bump the local sequence number for the next outgoing packet
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+    }
+
+    /// Enqueues the outgoing unreliable packet for acknowledgment, it will never be resent.
+    pub fn process_outgoing_unreliable(&mut self, message_id: Option<MessageId>, time: Instant) {
+        self.sent_unreliable_packets.insert(
+            self.sequence_number,
+            SentUnreliablePacket {
+                message_id,
+                sent_time: time,
             },
         );
 
@@ -120,24 +189,110 @@ impl AcknowledgmentHandler {
         self.sequence_number = self.sequence_number.wrapping_add(1);
     }
 
+    /// Returns the message ids of the packets that got acknowledged since the last call.
+    pub fn take_acked_messages(&mut self) -> Vec<MessageId> {
+        std::mem::take(&mut self.acked_messages)
+    }
+
     /// Returns a `Vec` of packets we believe have been dropped.
-    pub fn dropped_packets(&mut self) -> Vec<SentPacket> {
+    ///
+    /// A packet is considered dropped when it can no longer be acknowledged because the remote host
+    /// acknowledged more than `REDUNDANT_PACKET_ACKS_SIZE` newer packets, or when it has not been
+    /// acknowledged within the given retransmission `timeout`.
+    pub fn dropped_packets(&mut self, time: Instant, timeout: Duration) -> Vec<SentPacket> {
+        // dropped fragments are resent before the next call, so a message none of whose fragments
+        // are in flight anymore was given up and can no longer be acknowledged.
+        let fragmented_messages: HashSet<SequenceNumber> = self
+            .sent_packets
+            .values()
+            .filter_map(|packet| packet.fragment.map(|fragment| fragment.sequence()))
+            .collect();
+        self.unacked_fragments
+            .retain(|sequence, _| fragmented_messages.contains(sequence));
+
         let mut sent_sequences: Vec<SequenceNumber> = self.sent_packets.keys().cloned().collect();
         sent_sequences.sort_unstable();
 
-        let remote_ack_sequence = self.remote_ack_sequence_num;
-        sent_sequences
+        let dropped_sequences: Vec<SequenceNumber> = sent_sequences
+            .into_iter()
+            .filter(|s| self.is_dropped(*s, self.sent_packets[s].sent_time, time, timeout))
+            .collect();
+        dropped_sequences
             .into_iter()
-            .filter(|s| {
-                if sequence_less_than(*s, remote_ack_sequence) {
-                    remote_ack_sequence.wrapping_sub(*s) > REDUNDANT_PACKET_ACKS_SIZE
-                } else {
-                    false
-                }
-            })
             .flat_map(|s| self.sent_packets.remove(&s))
             .collect()
     }
+
+    /// Returns the unreliable packets we believe have been lost, by the same rules as
+    /// [`dropped_packets`](#method.dropped_packets).
+    pub fn lost_unreliable_packets(
+        &mut self,
+        time: Instant,
+        timeout: Duration,
+    ) -> Vec<SentUnreliablePacket> {
+        let mut lost_sequences: Vec<SequenceNumber> = self
+            .sent_unreliable_packets
+            .iter()
+            .filter(|(s, packet)| self.is_dropped(**s, packet.sent_time, time, timeout))
+            .map(|(s, _)| *s)
+            .collect();
+        lost_sequences.sort_unstable();
+
+        lost_sequences
+            .into_iter()
+            .flat_map(|s| self.sent_unreliable_packets.remove(&s))
+            .collect()
+    }
+
+    fn is_dropped(
+        &self,
+        sequence: SequenceNumber,
+        sent_time: Instant,
+        time: Instant,
+        timeout: Duration,
+    ) -> bool {
+        let remote_ack_sequence = self.remote_ack_sequence_num;
+        (sequence_less_than(sequence, remote_ack_sequence)
+            && remote_ack_sequence.wrapping_sub(sequence) > REDUNDANT_PACKET_ACKS_SIZE)
+            || time.saturating_duration_since(sent_time) >= timeout
+    }
+
+    fn acknowledge(&mut self, sequence: SequenceNumber) {
+        let message_id = match self.sent_packets.remove(&sequence) {
+            Some(SentPacket {
+                fragment: Some(fragment),
+                message_id,
+                ..
+            }) => {
+                if self.acknowledge_fragment(fragment) {
+                    message_id
+                } else {
+                    None
+                }
+            }
+            Some(packet) => packet.message_id,
+            None => self
+                .sent_unreliable_packets
+                .remove(&sequence)
+                .and_then(|packet| packet.message_id),
+        };
+        self.acked_messages.extend(message_id);
+    }
+
+    // Counts the fragment as acknowledged, returns whether all fragments of its message are.
+    fn acknowledge_fragment(&mut self, fragment: FragmentHeader) -> bool {
+        match self.unacked_fragments.get_mut(&fragment.sequence()) {
+            Some(unacked) if *unacked > 1 => {
+                *unacked -= 1;
+                false
+            }
+            Some(_) => {
+                self.unacked_fragments.remove(&fragment.sequence());
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -146,6 +301,16 @@ pub struct SentPacket {
     pub payload: Box<[u8]>,
     pub ordering_guarantee: OrderingGuarantee,
     pub item_identifier: Option<SequenceNumber>,
+    pub message_id: Option<MessageId>,
+    pub sent_time: Instant,
+    // The fragment header the packet was sent with, if it is a fragment of a larger message.
+    pub fragment: Option<FragmentHeader>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SentUnreliablePacket {
+    pub message_id: Option<MessageId>,
+    pub sent_time: Instant,
 }
 
 // TODO: At some point we should put something useful here. Possibly timing information or total
@@ -155,14 +320,19 @@ pub struct ReceivedPacket;
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, Instant};
+
     use log::debug;
 
     use crate::infrastructure::acknowledgment::ReceivedPacket;
     use crate::infrastructure::{AcknowledgmentHandler, SentPacket};
-    use crate::packet::{OrderingGuarantee, PacketType};
+    use crate::packet::{header::FragmentHeader, MessageId, OrderingGuarantee, PacketType};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
 
     #[test]
     fn increment_local_seq_num_on_process_outgoing() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
         assert_eq!(handler.local_sequence_num(), 0);
         for i in 0..10 {
@@ -171,6 +341,8 @@ mod test {
                 vec![].as_slice(),
                 OrderingGuarantee::None,
                 None,
+                None,
+                time,
             );
             assert_eq!(handler.local_sequence_num(), i + 1);
         }
@@ -178,6 +350,7 @@ mod test {
 
     #[test]
     fn local_seq_num_wraps_on_overflow() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
         handler.sequence_number = u16::max_value();
         handler.process_outgoing(
@@ -185,6 +358,8 @@ mod test {
             vec![].as_slice(),
             OrderingGuarantee::None,
             None,
+            None,
+            time,
         );
         assert_eq!(handler.local_sequence_num(), 0);
     }
@@ -198,21 +373,16 @@ mod test {
     #[test]
     fn ack_bitfield_with_some_values() {
         let mut handler = AcknowledgmentHandler::new();
-        handler
-            .received_packets
-            .insert(0, ReceivedPacket::default());
-        handler
-            .received_packets
-            .insert(1, ReceivedPacket::default());
-        handler
-            .received_packets
-            .insert(3, ReceivedPacket::default());
+        handler.received_packets.insert(0, ReceivedPacket);
+        handler.received_packets.insert(1, ReceivedPacket);
+        handler.received_packets.insert(3, ReceivedPacket);
         assert_eq!(handler.remote_sequence_num(), 3);
         assert_eq!(handler.ack_bitfield(), 0b110)
     }
 
     #[test]
     fn packet_is_not_acked() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
 
         handler.sequence_number = 0;
@@ -221,6 +391,8 @@ mod test {
             vec![1, 2, 3].as_slice(),
             OrderingGuarantee::None,
             None,
+            None,
+            time,
         );
         handler.sequence_number = 40;
         handler.process_outgoing(
@@ -228,24 +400,30 @@ mod test {
             vec![1, 2, 4].as_slice(),
             OrderingGuarantee::None,
             None,
+            None,
+            time,
         );
 
         static ARBITRARY: u16 = 23;
         handler.process_incoming(ARBITRARY, 40, 0);
 
         assert_eq!(
-            handler.dropped_packets(),
+            handler.dropped_packets(time, TIMEOUT),
             vec![SentPacket {
                 packet_type: PacketType::Packet,
                 payload: vec![1, 2, 3].into_boxed_slice(),
                 ordering_guarantee: OrderingGuarantee::None,
-                item_identifier: None
+                item_identifier: None,
+                message_id: None,
+                sent_time: time,
+                fragment: None,
             }]
         );
     }
 
     #[test]
     fn acking_500_packets_without_packet_drop() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
         let mut other = AcknowledgmentHandler::new();
 
@@ -256,17 +434,20 @@ mod test {
                 vec![1, 2, 3].as_slice(),
                 OrderingGuarantee::None,
                 None,
+                None,
+                time,
             );
 
             other.process_incoming(i, handler.remote_sequence_num(), handler.ack_bitfield());
             handler.process_incoming(i, other.remote_sequence_num(), other.ack_bitfield());
         }
 
-        assert_eq!(handler.dropped_packets().len(), 0);
+        assert_eq!(handler.dropped_packets(time, TIMEOUT).len(), 0);
     }
 
     #[test]
     fn acking_many_packets_with_packet_drop() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
         let mut other = AcknowledgmentHandler::new();
 
@@ -278,6 +459,8 @@ mod test {
                 vec![1, 2, 3].as_slice(),
                 OrderingGuarantee::None,
                 None,
+                None,
+                time,
             );
             handler.sequence_number = i;
 
@@ -302,7 +485,7 @@ mod test {
             handler.ack_bitfield(),
             0b1011_1011_1011_1011_1011_1011_1011_1011
         );
-        assert_eq!(handler.dropped_packets().len(), 17);
+        assert_eq!(handler.dropped_packets(time, TIMEOUT).len(), 17);
     }
 
     #[test]
@@ -327,12 +510,15 @@ mod test {
 
     #[test]
     fn test_process_outgoing() {
+        let time = Instant::now();
         let mut handler = AcknowledgmentHandler::new();
         handler.process_outgoing(
             PacketType::Packet,
             vec![1, 2, 3].as_slice(),
             OrderingGuarantee::None,
             None,
+            None,
+            time,
         );
         assert_eq!(handler.sent_packets.len(), 1);
         assert_eq!(handler.local_sequence_num(), 1);
@@ -359,4 +545,137 @@ mod test {
         handler.process_incoming(0, u16::max_value(), 0);
         assert_eq!(handler.remote_ack_sequence_num, 0);
     }
+
+    #[test]
+    fn packet_is_dropped_after_retransmission_timeout() {
+        let time = Instant::now();
+        let mut handler = AcknowledgmentHandler::new();
+
+        handler.process_outgoing(
+            PacketType::Packet,
+            vec![1, 2, 3].as_slice(),
+            OrderingGuarantee::None,
+            None,
+            None,
+            time,
+        );
+
+        assert!(handler
+            .dropped_packets(time + TIMEOUT - Duration::from_millis(1), TIMEOUT)
+            .is_empty());
+        assert_eq!(handler.dropped_packets(time + TIMEOUT, TIMEOUT).len(), 1);
+        assert_eq!(handler.packets_in_flight(), 0);
+    }
+
+    #[test]
+    fn acknowledged_messages_are_reported_once() {
+        let time = Instant::now();
+        let mut handler = AcknowledgmentHandler::new();
+
+        handler.process_outgoing(
+            PacketType::Packet,
+            vec![1, 2, 3].as_slice(),
+            OrderingGuarantee::None,
+            None,
+            Some(MessageId(0)),
+            time,
+        );
+        handler.process_outgoing_unreliable(Some(MessageId(1)), time);
+        handler.process_outgoing_unreliable(None, time);
+
+        handler.process_incoming(0, 2, 0b11);
+        assert_eq!(
+            handler.take_acked_messages(),
+            vec![MessageId(0), MessageId(1)]
+        );
+
+        handler.process_incoming(1, 2, 0b11);
+        assert!(handler.take_acked_messages().is_empty());
+        assert_eq!(handler.packets_in_flight(), 0);
+    }
+
+    #[test]
+    fn unreliable_message_is_lost_after_timeout() {
+        let time = Instant::now();
+        let mut handler = AcknowledgmentHandler::new();
+
+        handler.process_outgoing_unreliable(Some(MessageId(7)), time);
+
+        handler.process_outgoing_unreliable(None, time);
+
+        assert!(handler
+            .lost_unreliable_packets(time + TIMEOUT - Duration::from_millis(1), TIMEOUT)
+            .is_empty());
+        let lost = handler.lost_unreliable_packets(time + TIMEOUT, TIMEOUT);
+        assert_eq!(
+            lost.into_iter()
+                .map(|packet| packet.message_id)
+                .collect::<Vec<_>>(),
+            vec![Some(MessageId(7)), None]
+        );
+        assert!(handler
+            .lost_unreliable_packets(time + TIMEOUT, TIMEOUT)
+            .is_empty());
+        assert!(handler.dropped_packets(time + TIMEOUT, TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn fragmented_message_is_acked_once_all_fragments_are() {
+        let time = Instant::now();
+        let mut handler = AcknowledgmentHandler::new();
+
+        for id in 0..3 {
+            handler.process_outgoing_fragment(
+                FragmentHeader::new(0, id, 3),
+                &[id],
+                OrderingGuarantee::None,
+                Some(MessageId(5)),
+                time,
+            );
+        }
+
+        // the second fragment is lost and resent on its own
+        handler.process_incoming(0, 2, 0b10);
+        assert!(handler.take_acked_messages().is_empty());
+        let dropped = handler.dropped_packets(time + TIMEOUT, TIMEOUT);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].fragment, Some(FragmentHeader::new(0, 1, 3)));
+        assert_eq!(&*dropped[0].payload, &[1]);
+
+        handler.process_outgoing_fragment(
+            FragmentHeader::new(0, 1, 3),
+            &dropped[0].payload,
+            OrderingGuarantee::None,
+            Some(MessageId(5)),
+            time + TIMEOUT,
+        );
+        handler.process_incoming(1, 3, 0b111);
+        assert_eq!(handler.take_acked_messages(), vec![MessageId(5)]);
+        assert_eq!(handler.packets_in_flight(), 0);
+        assert!(handler.unacked_fragments.is_empty());
+    }
+
+    #[test]
+    fn fragmented_message_that_is_not_resent_is_forgotten() {
+        let time = Instant::now();
+        let mut handler = AcknowledgmentHandler::new();
+
+        for id in 0..2 {
+            handler.process_outgoing_fragment(
+                FragmentHeader::new(0, id, 2),
+                &[id],
+                OrderingGuarantee::None,
+                Some(MessageId(5)),
+                time,
+            );
+        }
+        assert_eq!(handler.dropped_packets(time + TIMEOUT, TIMEOUT).len(), 2);
+        assert_eq!(handler.unacked_fragments.len(), 1);
+
+        // the dropped fragments were not resent
+        assert!(handler
+            .dropped_packets(time + TIMEOUT * 2, TIMEOUT)
+            .is_empty());
+        assert!(handler.unacked_fragments.is_empty());
+    }
 }
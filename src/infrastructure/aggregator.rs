@@ -0,0 +1,144 @@
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::{
+    config::Config,
+    net::constants::{AGGREGATE_LENGTH_SIZE, STANDARD_HEADER_SIZE},
+    packet::{DeliveryGuarantee, OrderingGuarantee, OutgoingPacketBuilder, PacketType},
+};
+
+/// Coalesces packets into aggregate datagrams, so that many small packets share a single system
+/// call and the overhead of the IP and UDP headers.
+///
+/// An aggregate datagram starts with a `StandardHeader` of type `PacketType::Aggregate`, followed by
+/// the packets it contains, each prefixed with its length. Use `PacketReader::read_aggregated_packets`
+/// to split them back out.
+pub struct Aggregator {
+    max_datagram_size: usize,
+    datagram: Vec<u8>,
+    aggregated_packets: usize,
+    datagrams: Vec<Box<[u8]>>,
+}
+
+impl Aggregator {
+    /// Creates an `Aggregator` whose datagrams do not exceed the receive buffer of the remote host.
+    pub fn new(config: &Config) -> Aggregator {
+        Aggregator {
+            max_datagram_size: config.receive_buffer_max_size,
+            datagram: Vec::new(),
+            aggregated_packets: 0,
+            datagrams: Vec::new(),
+        }
+    }
+
+    /// Adds a packet to the current aggregate datagram, or starts a new one if it does not fit.
+    /// Packets that are too large to be aggregated at all end up in a datagram of their own.
+    pub fn push(&mut self, packet: &[u8]) {
+        let entry_size = usize::from(AGGREGATE_LENGTH_SIZE) + packet.len();
+
+        if usize::from(STANDARD_HEADER_SIZE) + entry_size > self.max_datagram_size {
+            // datagrams keep the order of the packets.
+            self.finish_datagram();
+            self.datagrams.push(Box::from(packet));
+            return;
+        }
+
+        if self.datagram.len() + entry_size > self.max_datagram_size {
+            self.finish_datagram();
+        }
+
+        if self.datagram.is_empty() {
+            let header = OutgoingPacketBuilder::new(&[])
+                .with_default_header(
+                    PacketType::Aggregate,
+                    DeliveryGuarantee::Unreliable,
+                    OrderingGuarantee::None,
+                )
+                .build();
+            self.datagram.extend_from_slice(&header.contents());
+        }
+
+        self.datagram
+            .write_u16::<BigEndian>(packet.len() as u16)
+            .expect("Could not write packet length to buffer");
+        self.datagram.extend_from_slice(packet);
+        self.aggregated_packets += 1;
+    }
+
+    /// Returns the datagrams that are ready to be sent, in the order their packets were pushed.
+    pub fn take_datagrams(&mut self) -> Vec<Box<[u8]>> {
+        self.finish_datagram();
+        std::mem::take(&mut self.datagrams)
+    }
+
+    fn finish_datagram(&mut self) {
+        match self.aggregated_packets {
+            0 => {}
+            // a single packet is sent without the aggregate header.
+            1 => self.datagrams.push(Box::from(
+                &self.datagram[usize::from(STANDARD_HEADER_SIZE + AGGREGATE_LENGTH_SIZE)..],
+            )),
+            _ => self.datagrams.push(Box::from(self.datagram.as_slice())),
+        }
+        self.datagram.clear();
+        self.aggregated_packets = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::packet::PacketReader;
+
+    use super::Aggregator;
+
+    fn aggregator(max_datagram_size: usize) -> Aggregator {
+        Aggregator::new(&Config {
+            receive_buffer_max_size: max_datagram_size,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn single_packet_is_not_wrapped() {
+        let mut aggregator = aggregator(100);
+        aggregator.push(&[1, 2, 3]);
+
+        assert_eq!(aggregator.take_datagrams(), vec![Box::from(&[1, 2, 3][..])]);
+        assert!(aggregator.take_datagrams().is_empty());
+    }
+
+    #[test]
+    fn packets_are_aggregated_and_split_back_out() {
+        let mut aggregator = aggregator(100);
+        aggregator.push(&[1, 2, 3]);
+        aggregator.push(&[4]);
+
+        let datagrams = aggregator.take_datagrams();
+        assert_eq!(datagrams.len(), 1);
+
+        let mut reader = PacketReader::new(&datagrams[0]);
+        assert!(reader.read_standard_header().unwrap().is_aggregate());
+        assert_eq!(
+            reader.read_aggregated_packets().unwrap(),
+            vec![&[1, 2, 3][..], &[4][..]]
+        );
+    }
+
+    #[test]
+    fn datagrams_do_not_exceed_the_maximum_size() {
+        // standard header, followed by two packets with length prefix
+        let mut aggregator = aggregator(5 + 2 * (2 + 10));
+        for i in 0..5 {
+            aggregator.push(&[i; 10]);
+        }
+        aggregator.push(&[5; 100]);
+        aggregator.push(&[6; 10]);
+
+        let datagrams = aggregator.take_datagrams();
+        assert_eq!(
+            datagrams.iter().map(|d| d.len()).collect::<Vec<_>>(),
+            vec![29, 29, 10, 100, 10]
+        );
+        assert_eq!(datagrams[2], Box::from(&[4; 10][..]));
+    }
+}
@@ -122,11 +122,11 @@ impl<T> ArrangingSystem for OrderingSystem<T> {
 ///
 /// There are three scenarios that are important to us.
 /// 1. `incoming_index` == `expected_index`.
-/// This package meets the expected order, so we can return it immediately.
+///    This package meets the expected order, so we can return it immediately.
 /// 2. `incoming_index` > `expected_index`.
-/// This package is newer than we expect, so we have to hold it temporarily until we have received all previous packages.
+///    This package is newer than we expect, so we have to hold it temporarily until we have received all previous packages.
 /// 3. `incoming_index`< `expected_index`
-/// This can only happen in cases where we have a duplicated package. Again we don't give anything back.
+///    This can only happen in cases where we have a duplicated package. Again we don't give anything back.
 /// # Remarks
 /// - See [super-module](../index.html) for more information about streams.
 pub struct OrderingStream<T> {
@@ -235,11 +235,11 @@ impl<T> Arranging for OrderingStream<T> {
     ///
     /// There are three scenarios that are important to us.
     /// 1. `incoming_index` == `expected_index`.
-    /// This package meets the expected order, so we can return it immediately.
+    ///    This package meets the expected order, so we can return it immediately.
     /// 2. `incoming_index` > `expected_index`.
-    /// This package is newer than we expect, so we have to hold it temporarily until we have received all previous packages.
+    ///    This package is newer than we expect, so we have to hold it temporarily until we have received all previous packages.
     /// 3. `incoming_index` < `expected_index`
-    /// This can only happen in cases where we have a duplicated package. Again we don't give anything back.
+    ///    This can only happen in cases where we have a duplicated package. Again we don't give anything back.
     ///
     /// # Remark
     /// - When we receive an item there is a possibility that a gap is filled and one or more items will could be returned.
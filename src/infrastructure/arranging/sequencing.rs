@@ -60,11 +60,11 @@ impl<T> ArrangingSystem for SequencingSystem<T> {
 ///
 /// There are two scenarios that are important to us.
 /// 1. `incoming_index` >= `top_index`.
-/// This item is the newest or newer than the last one we have seen.
-/// Because of that we should return it back to the user.
+///    This item is the newest or newer than the last one we have seen.
+///    Because of that we should return it back to the user.
 /// 2. `incoming_index` < `top_index`.
-/// This item is older than the newest item we have seen so far.
-/// Since we don't care about old items we can toss it a way.
+///    This item is older than the newest item we have seen so far.
+///    Since we don't care about old items we can toss it a way.
 ///
 /// # Remarks
 /// - See [super-module](../index.html) for more information about streams.
@@ -123,11 +123,11 @@ impl<T> Arranging for SequencingStream<T> {
     ///
     /// There are two scenarios that are important to us.
     /// 1. `incoming_index` >= `top_index`.
-    /// This item is the newest or newer than the last one we have seen.
-    /// Because of that we should return it back to the user.
+    ///    This item is the newest or newer than the last one we have seen.
+    ///    Because of that we should return it back to the user.
     /// 2. `incoming_index` < `top_index`.
-    /// This item is older than we the newest packet we have seen so far.
-    /// Since we don't care about old items we can toss it a way.
+    ///    This item is older than we the newest packet we have seen so far.
+    ///    Since we don't care about old items we can toss it a way.
     ///
     /// # Remark
     /// - All old packets will be tossed away.
@@ -1,34 +1,109 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{
-    net::{NetworkQuality, RttMeasurer},
+    net::{constants::REDUNDANT_PACKET_ACKS_SIZE, ConnectionStats, NetworkQuality, RttMeasurer},
     sequence_buffer::{CongestionData, SequenceBuffer},
     Config,
 };
 
+// The number of sent packets we remember the sending time of.
+const CONGESTION_DATA_SIZE: u16 = 1024;
+// The weight of a single sent packet in the smoothed packet loss.
+const PACKET_LOSS_SMOOTHING_FACTOR: f32 = 0.1;
+// The maximal number of times the retransmission timeout is doubled.
+const MAX_BACKOFF: u32 = 16;
+// The maximal time worth of packets that is sent at once after not sending for a while.
+const MAX_PACING_BURST: Duration = Duration::from_millis(50);
+
+/// Specifies how the rate at which packets are sent to a remote host is controlled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CongestionControl {
+    /// Packets are sent immediately, regardless of the network conditions.
+    Disabled,
+    /// Packets are sent at `Config::max_send_rate` while the network quality is good and at
+    /// `Config::min_send_rate` while it is bad.
+    GoodBad,
+    /// The send rate is increased by one packet per round trip time and halved whenever packets
+    /// get lost (additive increase, multiplicative decrease), bounded by `Config::min_send_rate`
+    /// and `Config::max_send_rate`.
+    Aimd,
+}
+
 /// Keeps track of congestion information.
+///
+/// This measures the round trip time and packet loss of acknowledged packets and computes the
+/// retransmission timeout from them as described in RFC 6298.
 pub struct CongestionHandler {
     rtt_measurer: RttMeasurer,
     congestion_data: SequenceBuffer<CongestionData>,
-    _quality: NetworkQuality,
+    packet_loss: f32,
+    // The number of times the retransmission timeout was doubled since the last measurement.
+    backoff: u32,
+    initial_timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    mode: CongestionControl,
+    // The number of packets per second that may be sent.
+    send_rate: f32,
+    min_send_rate: f32,
+    max_send_rate: f32,
+    // The number of packets that may be sent right now, negative if we sent too many.
+    send_budget: f32,
+    last_budget_update: Option<Instant>,
+    last_rate_change: Option<Instant>,
 }
 
 impl CongestionHandler {
     /// Constructs a new `CongestionHandler` which you can use for keeping track of congestion information.
     pub fn new(config: &Config) -> CongestionHandler {
+        // a send rate of zero would hold back the queued packets forever.
+        let min_send_rate = config.min_send_rate.max(1);
+        let max_send_rate = config.max_send_rate.max(min_send_rate);
         CongestionHandler {
             rtt_measurer: RttMeasurer::new(config),
-            congestion_data: SequenceBuffer::with_capacity(<u16>::max_value()),
-            _quality: NetworkQuality::Good,
+            congestion_data: SequenceBuffer::with_capacity(CONGESTION_DATA_SIZE),
+            packet_loss: 0.,
+            backoff: 0,
+            initial_timeout: config.initial_retransmission_timeout,
+            min_timeout: config.min_retransmission_timeout,
+            max_timeout: config.max_retransmission_timeout,
+            mode: config.congestion_control,
+            send_rate: max_send_rate as f32,
+            min_send_rate: min_send_rate as f32,
+            max_send_rate: max_send_rate as f32,
+            send_budget: 0.,
+            last_budget_update: None,
+            last_rate_change: None,
         }
     }
 
-    /// Processes incoming sequence number.
+    /// Processes the acknowledgment information of an incoming packet.
     ///
-    /// This will calculate the RTT-time and smooth down the RTT-value to prevent uge RTT-spikes.
-    pub fn process_incoming(&mut self, incoming_seq: u16) {
-        let congestion_data = self.congestion_data.get_mut(incoming_seq);
-        self.rtt_measurer.calculate_rrt(congestion_data);
+    /// This will calculate the RTT-time of the packet acknowledged by `ack_seq` and smooth down the
+    /// RTT-value to prevent huge RTT-spikes. Every packet acknowledged for the first time lowers
+    /// the packet loss.
+    pub fn process_incoming(&mut self, ack_seq: u16, mut ack_field: u32, time: Instant) {
+        if let Some(congestion_data) = self.congestion_data.remove(ack_seq) {
+            // every resend gets a new sequence number, so the measurement is never ambiguous.
+            self.rtt_measurer
+                .add_sample(time.saturating_duration_since(congestion_data.sending_time));
+            self.backoff = 0;
+            self.add_loss_sample(0.);
+        }
+
+        // packets acknowledged by the `ack_field` might have been acknowledged late, so they only
+        // count as delivered.
+        for i in 1..=REDUNDANT_PACKET_ACKS_SIZE {
+            if ack_field & 1 == 1
+                && self
+                    .congestion_data
+                    .remove(ack_seq.wrapping_sub(i))
+                    .is_some()
+            {
+                self.add_loss_sample(0.);
+            }
+            ack_field >>= 1;
+        }
     }
 
     /// Processes outgoing sequence number.
@@ -36,16 +111,134 @@ impl CongestionHandler {
     /// This will insert an entry which is used for keeping track of the sending time.
     /// Once we process incoming sequence numbers we can calculate the `RTT` time.
     pub fn process_outgoing(&mut self, seq: u16, time: Instant) {
-        self.congestion_data
-            .insert(seq, CongestionData::new(seq, time));
+        self.congestion_data.insert(seq, CongestionData::new(time));
+    }
+
+    /// Processes packets that are considered dropped, this raises the packet loss.
+    ///
+    /// When `timed_out` is set the packets were not acknowledged within the retransmission
+    /// timeout, which doubles the timeout until the next round trip time measurement.
+    pub fn process_dropped(&mut self, dropped: usize, timed_out: bool, time: Instant) {
+        for _ in 0..dropped {
+            self.add_loss_sample(1.);
+        }
+
+        // packets lost within the same round trip are most likely caused by the same congestion.
+        if self.mode == CongestionControl::Aimd && dropped > 0 && self.may_change_rate(time) {
+            self.send_rate = (self.send_rate / 2.).max(self.min_send_rate);
+            self.last_rate_change = Some(time);
+        }
+
+        if timed_out && self.retransmission_timeout() < self.max_timeout {
+            self.backoff = (self.backoff + 1).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Returns how long we currently wait for an acknowledgment before a packet is considered dropped.
+    pub fn retransmission_timeout(&self) -> Duration {
+        let timeout = match self.rtt_measurer.smoothed_rtt() {
+            Some(smoothed_rtt) => smoothed_rtt + self.rtt_measurer.rtt_variance() * 4,
+            None => self.initial_timeout,
+        };
+        timeout
+            .max(self.min_timeout)
+            .checked_mul(1 << self.backoff)
+            .map_or(self.max_timeout, |timeout| timeout.min(self.max_timeout))
+    }
+
+    /// Returns whether outgoing packets are paced, i.e. congestion control is enabled.
+    pub fn is_pacing(&self) -> bool {
+        self.mode != CongestionControl::Disabled
+    }
+
+    /// Adapts the send rate to the current network quality and the absence of packet loss.
+    pub fn update_send_rate(&mut self, quality: NetworkQuality, time: Instant) {
+        match self.mode {
+            CongestionControl::Disabled => {}
+            CongestionControl::GoodBad => {
+                self.send_rate = match quality {
+                    NetworkQuality::Good => self.max_send_rate,
+                    NetworkQuality::Bad => self.min_send_rate,
+                };
+            }
+            CongestionControl::Aimd => {
+                if self.may_change_rate(time) {
+                    let round_trip = self.round_trip().max(Duration::from_millis(1));
+                    self.send_rate =
+                        (self.send_rate + 1. / round_trip.as_secs_f32()).min(self.max_send_rate);
+                    self.last_rate_change = Some(time);
+                }
+            }
+        }
+    }
+
+    /// Returns whether a packet may be sent right now without exceeding the send rate.
+    pub fn can_send(&mut self, time: Instant) -> bool {
+        if !self.is_pacing() {
+            return true;
+        }
+
+        let max_budget = (self.send_rate * MAX_PACING_BURST.as_secs_f32()).max(1.);
+        self.send_budget = match self.last_budget_update {
+            Some(last_update) => {
+                let elapsed = time.saturating_duration_since(last_update).as_secs_f32();
+                (self.send_budget + elapsed * self.send_rate).min(max_budget)
+            }
+            None => max_budget,
+        };
+        self.last_budget_update = Some(time);
+
+        self.send_budget >= 1.
+    }
+
+    /// Processes the number of datagrams that were sent, which are subtracted from the send budget.
+    pub fn process_sent(&mut self, datagrams: usize) {
+        if self.is_pacing() {
+            self.send_budget -= datagrams as f32;
+        }
+    }
+
+    /// Returns the current network statistics, `packets_in_flight` is provided by the caller.
+    pub fn stats(&self, packets_in_flight: u16) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.rtt_measurer.smoothed_rtt(),
+            jitter: self.rtt_measurer.rtt_variance(),
+            packet_loss: self.packet_loss,
+            packets_in_flight,
+            retransmission_timeout: self.retransmission_timeout(),
+            send_rate: if self.is_pacing() {
+                Some(self.send_rate)
+            } else {
+                None
+            },
+            replayed_packets: 0,
+        }
+    }
+
+    // The send rate is changed at most once per round trip.
+    fn may_change_rate(&self, time: Instant) -> bool {
+        self.last_rate_change.is_none_or(|last_change| {
+            time.saturating_duration_since(last_change) >= self.round_trip()
+        })
+    }
+
+    fn round_trip(&self) -> Duration {
+        self.rtt_measurer
+            .smoothed_rtt()
+            .unwrap_or(self.initial_timeout)
+    }
+
+    fn add_loss_sample(&mut self, sample: f32) {
+        self.packet_loss += (sample - self.packet_loss) * PACKET_LOSS_SMOOTHING_FACTOR;
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Instant;
+    use std::time::{Duration, Instant};
 
-    use crate::infrastructure::CongestionHandler;
+    use crate::infrastructure::{CongestionControl, CongestionHandler};
+    use crate::net::NetworkQuality;
     use crate::Config;
 
     #[test]
@@ -58,13 +251,223 @@ mod test {
     }
 
     #[test]
-    #[allow(clippy::float_cmp)]
     fn rtt_value_is_updated() {
         let mut congestion_handler = CongestionHandler::new(&Config::default());
+        let time = Instant::now();
 
-        assert!(congestion_handler.rtt_measurer.get_rtt().abs() < f32::EPSILON);
-        congestion_handler.process_outgoing(1, Instant::now());
-        congestion_handler.process_incoming(1);
-        assert_ne!(congestion_handler.rtt_measurer.get_rtt(), 0.);
+        assert_eq!(congestion_handler.stats(0).rtt, None);
+        congestion_handler.process_outgoing(1, time);
+        congestion_handler.process_incoming(1, 0, time + Duration::from_millis(30));
+        assert_eq!(
+            congestion_handler.stats(0).rtt,
+            Some(Duration::from_millis(30))
+        );
+    }
+
+    #[test]
+    fn acknowledgment_is_measured_once() {
+        let mut congestion_handler = CongestionHandler::new(&Config::default());
+        let time = Instant::now();
+
+        congestion_handler.process_outgoing(1, time);
+        congestion_handler.process_incoming(1, 0, time + Duration::from_millis(30));
+        // the same acknowledgment is repeated by every packet until a newer packet is acknowledged.
+        congestion_handler.process_incoming(1, 0, time + Duration::from_millis(500));
+
+        assert_eq!(
+            congestion_handler.stats(0).rtt,
+            Some(Duration::from_millis(30))
+        );
+    }
+
+    #[test]
+    fn packet_loss_follows_dropped_packets() {
+        let mut congestion_handler = CongestionHandler::new(&Config::default());
+        let time = Instant::now();
+
+        for seq in 0..100 {
+            congestion_handler.process_outgoing(seq, time);
+            if seq % 4 == 0 {
+                congestion_handler.process_dropped(1, false, time);
+            } else {
+                congestion_handler.process_incoming(seq, 0, time);
+            }
+        }
+
+        let packet_loss = congestion_handler.stats(0).packet_loss;
+        assert!(packet_loss > 0.15 && packet_loss < 0.35);
+    }
+
+    #[test]
+    fn ack_field_lowers_packet_loss() {
+        let mut congestion_handler = CongestionHandler::new(&Config::default());
+        let time = Instant::now();
+
+        congestion_handler.process_dropped(10, false, time);
+        let packet_loss = congestion_handler.stats(0).packet_loss;
+
+        for seq in 0..33 {
+            congestion_handler.process_outgoing(seq, time);
+        }
+        congestion_handler.process_incoming(32, !0, time);
+
+        assert!(congestion_handler.stats(0).packet_loss < packet_loss / 10.);
+        assert!(!congestion_handler.congestion_data.exists(0));
+    }
+
+    #[test]
+    fn retransmission_timeout_backs_off_exponentially() {
+        let config = Config::default();
+        let time = Instant::now();
+        let mut congestion_handler = CongestionHandler::new(&config);
+
+        let mut expected = config.initial_retransmission_timeout;
+        while expected < config.max_retransmission_timeout {
+            assert_eq!(congestion_handler.retransmission_timeout(), expected);
+
+            congestion_handler.process_dropped(1, true, time);
+            expected = (expected * 2).min(config.max_retransmission_timeout);
+        }
+        congestion_handler.process_dropped(1, true, time);
+        assert_eq!(
+            congestion_handler.retransmission_timeout(),
+            config.max_retransmission_timeout
+        );
+    }
+
+    #[test]
+    fn retransmission_timeout_backoff_is_bounded() {
+        let config = Config {
+            min_retransmission_timeout: Duration::default(),
+            ..Default::default()
+        };
+        let time = Instant::now();
+        let mut congestion_handler = CongestionHandler::new(&config);
+
+        // a round trip time of zero never reaches the maximal timeout, no matter how often it doubles.
+        congestion_handler.process_outgoing(0, time);
+        congestion_handler.process_incoming(0, 0, time);
+        for _ in 0..100 {
+            congestion_handler.process_dropped(1, true, time);
+        }
+
+        assert_eq!(
+            congestion_handler.retransmission_timeout(),
+            Duration::default()
+        );
+    }
+
+    #[test]
+    fn retransmission_timeout_follows_measured_rtt() {
+        let config = Config::default();
+        let mut time = Instant::now();
+        let mut congestion_handler = CongestionHandler::new(&config);
+        let rtt = Duration::from_millis(80);
+
+        congestion_handler.process_dropped(1, true, time);
+        for seq in 0..50 {
+            congestion_handler.process_outgoing(seq, time);
+            time += rtt;
+            congestion_handler.process_incoming(seq, 0, time);
+        }
+
+        let stats = congestion_handler.stats(0);
+        assert!(stats.rtt.unwrap().abs_diff(rtt) < Duration::from_micros(1));
+        assert!(stats.jitter < Duration::from_millis(1));
+        // without any variance the timeout is clamped to the minimal timeout
+        assert_eq!(
+            stats.retransmission_timeout,
+            config.min_retransmission_timeout.max(rtt)
+        );
+    }
+
+    #[test]
+    fn pacing_limits_send_rate() {
+        let config = Config {
+            congestion_control: CongestionControl::GoodBad,
+            max_send_rate: 100,
+            ..Default::default()
+        };
+        let mut congestion_handler = CongestionHandler::new(&config);
+        let time = Instant::now();
+
+        // a burst of 50ms worth of packets is allowed at once.
+        let mut sent = 0;
+        while congestion_handler.can_send(time) {
+            congestion_handler.process_sent(1);
+            sent += 1;
+        }
+        assert_eq!(sent, 5);
+
+        // after that a packet may be sent every 10ms.
+        assert!(!congestion_handler.can_send(time + Duration::from_millis(9)));
+        assert!(congestion_handler.can_send(time + Duration::from_millis(11)));
+    }
+
+    #[test]
+    fn pacing_is_disabled_by_default() {
+        let mut congestion_handler = CongestionHandler::new(&Config::default());
+        let time = Instant::now();
+
+        congestion_handler.process_sent(10_000);
+
+        assert!(!congestion_handler.is_pacing());
+        assert!(congestion_handler.can_send(time));
+        assert_eq!(congestion_handler.stats(0).send_rate, None);
+    }
+
+    #[test]
+    fn good_bad_mode_follows_network_quality() {
+        let config = Config {
+            congestion_control: CongestionControl::GoodBad,
+            ..Default::default()
+        };
+        let mut congestion_handler = CongestionHandler::new(&config);
+        let time = Instant::now();
+
+        congestion_handler.update_send_rate(NetworkQuality::Bad, time);
+        assert_eq!(
+            congestion_handler.stats(0).send_rate,
+            Some(config.min_send_rate as f32)
+        );
+
+        congestion_handler.update_send_rate(NetworkQuality::Good, time);
+        assert_eq!(
+            congestion_handler.stats(0).send_rate,
+            Some(config.max_send_rate as f32)
+        );
+    }
+
+    #[test]
+    fn aimd_mode_halves_rate_on_loss_once_per_round_trip() {
+        let config = Config {
+            congestion_control: CongestionControl::Aimd,
+            max_send_rate: 200,
+            min_send_rate: 20,
+            ..Default::default()
+        };
+        let mut congestion_handler = CongestionHandler::new(&config);
+        let time = Instant::now();
+        let rtt = Duration::from_millis(100);
+        congestion_handler.process_outgoing(0, time);
+        congestion_handler.process_incoming(0, 0, time + rtt);
+
+        congestion_handler.process_dropped(3, false, time + rtt);
+        congestion_handler.process_dropped(1, false, time + rtt * 3 / 2);
+        assert_eq!(congestion_handler.stats(0).send_rate, Some(100.));
+
+        // one packet per round trip time is added back.
+        congestion_handler.update_send_rate(NetworkQuality::Good, time + rtt * 3 / 2);
+        assert_eq!(congestion_handler.stats(0).send_rate, Some(100.));
+        congestion_handler.update_send_rate(NetworkQuality::Good, time + rtt * 2);
+        assert_eq!(congestion_handler.stats(0).send_rate, Some(110.));
+
+        for i in 0..10 {
+            congestion_handler.process_dropped(1, false, time + rtt * (3 + i));
+        }
+        assert_eq!(
+            congestion_handler.stats(0).send_rate,
+            Some(config.min_send_rate as f32)
+        );
     }
 }
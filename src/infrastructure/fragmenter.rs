@@ -1,10 +1,7 @@
-use std::io::Write;
-
 use crate::{
     config::Config,
     error::{FragmentErrorKind, Result},
-    net::constants::FRAGMENT_HEADER_SIZE,
-    packet::header::{AckedPacketHeader, FragmentHeader},
+    packet::header::FragmentHeader,
     sequence_buffer::{ReassemblyData, SequenceBuffer},
 };
 
@@ -28,6 +25,7 @@ impl Fragmentation {
     /// For example we have two numbers:
     /// - number 1 = 4000;
     /// - number 2 = 1024;
+    ///
     /// If you do it the easy way the answer will be 4000/1024 = 3.90625.
     /// But since we care about how how many whole times the number fits in we need the result 4.
     ///
@@ -53,7 +51,7 @@ impl Fragmentation {
     /// So for 4000 bytes we need 4 fragments
     /// [fragment: 1024] [fragment: 1024] [fragment: 1024] [fragment: 928]
     pub fn fragments_needed(payload_length: u16, fragment_size: u16) -> u16 {
-        let remainder = if payload_length % fragment_size > 0 {
+        let remainder = if !payload_length.is_multiple_of(fragment_size) {
             1
         } else {
             0
@@ -94,22 +92,32 @@ impl Fragmentation {
     }
 
     /// Reads fragment data and return the complete packet when all fragments are received.
+    ///
+    /// Every fragment is acknowledged on its own, so the acknowledgment header of the fragment is
+    /// processed by the caller.
     pub fn handle_fragment(
         &mut self,
         fragment_header: FragmentHeader,
         fragment_payload: &[u8],
-        acked_header: Option<AckedPacketHeader>,
-    ) -> Result<Option<(Vec<u8>, AckedPacketHeader)>> {
-        // read fragment packet
+    ) -> Result<Option<Vec<u8>>> {
+        // validate the header before it takes up a slot of the reassembly buffer, its contents come
+        // straight from the network.
+        if fragment_header.fragment_count() == 0 {
+            return Err(FragmentErrorKind::EmptyFragmentCount.into());
+        }
 
-        self.create_fragment_if_not_exists(fragment_header);
+        if fragment_header.fragment_count() > self.config.max_fragments {
+            return Err(FragmentErrorKind::ExceededMaxFragments.into());
+        }
 
-        let num_fragments_received;
-        let num_fragments_total;
-        let sequence;
-        let total_buffer;
+        // the remote host may use another fragment size, but no fragment is larger than a datagram.
+        if fragment_payload.len() > self.config.receive_buffer_max_size {
+            return Err(FragmentErrorKind::FragmentTooLarge.into());
+        }
 
-        {
+        self.create_fragment_if_not_exists(fragment_header);
+
+        let sequence = {
             // get entry of previous received fragments
             let reassembly_data = match self.fragments.get_mut(fragment_header.sequence()) {
                 Some(val) => val,
@@ -121,60 +129,61 @@ impl Fragmentation {
                 return Err(FragmentErrorKind::FragmentWithUnevenNumberOfFragments.into());
             }
 
-            if usize::from(fragment_header.id()) >= reassembly_data.fragments_received.len() {
+            let id = usize::from(fragment_header.id());
+            if id >= reassembly_data.fragments.len() {
                 return Err(FragmentErrorKind::ExceededMaxFragments.into());
             }
 
-            if reassembly_data.fragments_received[usize::from(fragment_header.id())] {
+            if reassembly_data.fragments[id].is_some() {
                 return Err(FragmentErrorKind::AlreadyProcessedFragment.into());
             }
 
-            // increase number of received fragments and set the specific fragment to received
-            reassembly_data.num_fragments_received += 1;
-            reassembly_data.fragments_received[usize::from(fragment_header.id())] = true;
-
-            // add the payload from the fragment to the buffer whe have in cache
-            reassembly_data.buffer.write_all(fragment_payload)?;
-
-            if let Some(acked_header) = acked_header {
-                if reassembly_data.acked_header.is_none() {
-                    reassembly_data.acked_header = Some(acked_header);
-                } else {
-                    return Err(FragmentErrorKind::MultipleAckHeaders.into());
+            // all fragments but the last one are filled up to the fragment size of the remote host,
+            // which may differ from ours.
+            let is_last = id + 1 == usize::from(reassembly_data.num_fragments_total);
+            if !is_last {
+                match reassembly_data.fragment_size {
+                    Some(size) if size != fragment_payload.len() => {
+                        return Err(FragmentErrorKind::UnexpectedFragmentLength.into());
+                    }
+                    _ => reassembly_data.fragment_size = Some(fragment_payload.len()),
                 }
             }
 
-            num_fragments_received = reassembly_data.num_fragments_received;
-            num_fragments_total = reassembly_data.num_fragments_total;
-            sequence = reassembly_data.sequence;
-            total_buffer = reassembly_data.buffer.clone();
-        }
-
-        // if we received all fragments then remove entry and return the total received bytes.
-        if num_fragments_received == num_fragments_total {
-            if let Some(mut reassembly_data) = self.fragments.remove(sequence) {
-                if reassembly_data.acked_header.is_none() {
-                    return Err(FragmentErrorKind::MissingAckHeader.into());
-                }
+            // increase number of received fragments and keep the payload until all arrived, they
+            // may arrive in any order.
+            reassembly_data.num_fragments_received += 1;
+            reassembly_data.fragments[id] = Some(fragment_payload.to_vec());
 
-                let acked_header = reassembly_data.acked_header.take().unwrap();
-                return Ok(Some((total_buffer, acked_header)));
-            } else {
-                return Err(FragmentErrorKind::CouldNotFindFragmentById.into());
+            if reassembly_data.num_fragments_received != reassembly_data.num_fragments_total {
+                return Ok(None);
             }
+            reassembly_data.sequence
+        };
+
+        // we received all fragments, so remove the entry and return the reassembled packet.
+        let reassembly_data = match self.fragments.remove(sequence) {
+            Some(val) => val,
+            None => return Err(FragmentErrorKind::CouldNotFindFragmentById.into()),
+        };
+
+        let fragments: Vec<Vec<u8>> = reassembly_data.fragments.into_iter().flatten().collect();
+        let last_length = fragments.last().map_or(0, Vec::len);
+        if reassembly_data
+            .fragment_size
+            .is_some_and(|size| last_length > size)
+        {
+            return Err(FragmentErrorKind::UnexpectedFragmentLength.into());
         }
 
-        Ok(None)
+        Ok(Some(fragments.concat()))
     }
 
     /// If fragment does not exist we need to insert a new entry.
     fn create_fragment_if_not_exists(&mut self, fragment_header: FragmentHeader) {
         if !self.fragments.exists(fragment_header.sequence()) {
-            let reassembly_data = ReassemblyData::new(
-                fragment_header.sequence(),
-                fragment_header.fragment_count(),
-                (u16::from(FRAGMENT_HEADER_SIZE) + self.config.fragment_size) as usize,
-            );
+            let reassembly_data =
+                ReassemblyData::new(fragment_header.sequence(), fragment_header.fragment_count());
 
             self.fragments
                 .insert(fragment_header.sequence(), reassembly_data);
@@ -185,6 +194,9 @@ impl Fragmentation {
 #[cfg(test)]
 mod test {
     use super::Fragmentation;
+    use crate::config::Config;
+    use crate::error::{ErrorKind, FragmentErrorKind};
+    use crate::packet::header::FragmentHeader;
 
     #[test]
     pub fn expect_right_number_of_fragments() {
@@ -194,4 +206,130 @@ mod test {
         assert_eq!(fragment_number, 4);
         assert_eq!(fragment_number1, 1);
     }
+
+    #[test]
+    fn invalid_fragment_headers_are_rejected() {
+        let config = Config::default();
+        let mut fragmentation = Fragmentation::new(&config);
+
+        let zero_count = fragmentation.handle_fragment(FragmentHeader::new(1, 0, 0), &[0]);
+        assert!(matches!(
+            zero_count,
+            Err(ErrorKind::FragmentError(
+                FragmentErrorKind::EmptyFragmentCount
+            ))
+        ));
+
+        let too_many = fragmentation
+            .handle_fragment(FragmentHeader::new(1, 0, config.max_fragments + 1), &[0]);
+        assert!(matches!(
+            too_many,
+            Err(ErrorKind::FragmentError(
+                FragmentErrorKind::ExceededMaxFragments
+            ))
+        ));
+
+        let oversized = vec![0; config.receive_buffer_max_size + 1];
+        let too_large = fragmentation.handle_fragment(FragmentHeader::new(1, 0, 2), &oversized);
+        assert!(matches!(
+            too_large,
+            Err(ErrorKind::FragmentError(
+                FragmentErrorKind::FragmentTooLarge
+            ))
+        ));
+
+        let out_of_range = fragmentation.handle_fragment(FragmentHeader::new(1, 2, 2), &[0]);
+        assert!(matches!(
+            out_of_range,
+            Err(ErrorKind::FragmentError(
+                FragmentErrorKind::ExceededMaxFragments
+            ))
+        ));
+    }
+
+    #[test]
+    fn more_fragments_than_the_default_can_be_reassembled() {
+        let config = Config {
+            max_fragments: 32,
+            fragment_size: 1,
+            ..Config::default()
+        };
+        let mut fragmentation = Fragmentation::new(&config);
+
+        for id in 0..31 {
+            let result = fragmentation.handle_fragment(FragmentHeader::new(1, id, 32), &[id]);
+            assert!(result.unwrap().is_none());
+        }
+        let payload = fragmentation
+            .handle_fragment(FragmentHeader::new(1, 31, 32), &[31])
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, (0..32).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn out_of_order_fragments_are_reassembled_in_order() {
+        let config = Config {
+            fragment_size: 2,
+            ..Config::default()
+        };
+        let mut fragmentation = Fragmentation::new(&config);
+
+        let fragments: [(u8, &[u8]); 3] = [(2, &[5]), (0, &[1, 2]), (1, &[3, 4])];
+        let mut reassembled = None;
+        for (id, payload) in fragments.iter() {
+            reassembled = fragmentation
+                .handle_fragment(FragmentHeader::new(1, *id, 3), payload)
+                .unwrap();
+        }
+
+        let payload = reassembled.unwrap();
+        assert_eq!(payload, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn fragments_with_another_fragment_size_are_reassembled() {
+        let sender_config = Config {
+            fragment_size: 100,
+            max_fragments: 32,
+            ..Config::default()
+        };
+        let receiver_config = Config {
+            max_fragments: 32,
+            ..Config::default()
+        };
+        let mut fragmentation = Fragmentation::new(&receiver_config);
+
+        let payload: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let fragments = Fragmentation::spit_into_fragments(&payload, &sender_config).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let mut reassembled = None;
+        for id in [2, 0, 1] {
+            reassembled = fragmentation
+                .handle_fragment(FragmentHeader::new(1, id, 3), fragments[usize::from(id)])
+                .unwrap();
+        }
+        assert_eq!(reassembled.unwrap(), payload);
+    }
+
+    #[test]
+    fn fragments_of_different_length_before_the_last_are_rejected() {
+        let config = Config {
+            fragment_size: 2,
+            ..Config::default()
+        };
+        let mut fragmentation = Fragmentation::new(&config);
+
+        fragmentation
+            .handle_fragment(FragmentHeader::new(1, 0, 3), &[1, 2])
+            .unwrap();
+        let result = fragmentation.handle_fragment(FragmentHeader::new(1, 1, 3), &[3]);
+        assert!(matches!(
+            result,
+            Err(ErrorKind::FragmentError(
+                FragmentErrorKind::UnexpectedFragmentLength
+            ))
+        ));
+    }
 }
@@ -0,0 +1,115 @@
+use crate::packet::SequenceNumber;
+use crate::sequence_buffer::SequenceBuffer;
+
+/// Remembers the packet numbers of the most recent encrypted packets that were received, to drop
+/// packets that an attacker captured and sent again, or that the network duplicated.
+///
+/// A packet is accepted once, as long as it is not older than the size of the window relative to
+/// the newest packet that was received. Older packets are dropped, since it cannot be told whether
+/// they were received before.
+///
+/// The sender increments the packet number by one for every packet, so the newest packet number
+/// never jumps by more than half the range of a `SequenceNumber`, which the buffer relies on.
+/// That would take more lost packets in a row than a connection survives.
+pub struct ReplayWindow {
+    // The full packet numbers of the received packets, by their lower 16 bits.
+    received: SequenceBuffer<u64>,
+    newest: Option<u64>,
+    size: u16,
+    dropped: u64,
+}
+
+impl ReplayWindow {
+    /// Creates a window that remembers the given number of packets.
+    pub fn new(size: u16) -> Self {
+        Self {
+            received: SequenceBuffer::with_capacity(size),
+            newest: None,
+            size,
+            dropped: 0,
+        }
+    }
+
+    /// Records a received packet number, returns false if the packet has to be dropped because
+    /// it was received before or is too old.
+    pub fn accept(&mut self, packet_number: u64) -> bool {
+        let sequence = packet_number as SequenceNumber;
+        if let Some(newest) = self.newest {
+            let too_old =
+                newest >= u64::from(self.size) && packet_number <= newest - u64::from(self.size);
+            let received =
+                self.received.get_mut(sequence).map(|entry| *entry) == Some(packet_number);
+            if too_old || received {
+                self.dropped += 1;
+                return false;
+            }
+        }
+
+        // a packet that jumped more than half the range ahead is taken for an old one by the
+        // buffer, it cannot be recorded and would be accepted again.
+        if self.received.insert(sequence, packet_number).is_none() {
+            self.dropped += 1;
+            return false;
+        }
+        self.newest = self.newest.max(Some(packet_number));
+        true
+    }
+
+    /// Returns the number of packets that were dropped.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayWindow;
+
+    #[test]
+    fn packets_are_accepted_once() {
+        let mut window = ReplayWindow::new(8);
+
+        assert!(window.accept(0));
+        assert!(window.accept(2));
+        assert!(!window.accept(2));
+        // packets may arrive out of order
+        assert!(window.accept(1));
+        assert!(!window.accept(0));
+        assert_eq!(window.dropped_packets(), 2);
+    }
+
+    #[test]
+    fn packets_older_than_the_window_are_dropped() {
+        let mut window = ReplayWindow::new(8);
+
+        assert!(window.accept(20));
+        assert!(!window.accept(12));
+        assert!(window.accept(13));
+        assert_eq!(window.dropped_packets(), 1);
+    }
+
+    #[test]
+    fn packets_are_told_apart_after_the_sequence_wrapped() {
+        let mut window = ReplayWindow::new(8);
+
+        for packet_number in 0..=70_000 {
+            assert!(window.accept(packet_number));
+        }
+        // the lower 16 bits of this packet match a recent one
+        assert!(!window.accept(70_000 - 65_536));
+        assert!(!window.accept(69_999));
+        assert!(window.accept(70_002));
+        assert!(window.accept(70_001));
+    }
+
+    #[test]
+    fn packets_that_cannot_be_recorded_are_dropped() {
+        let mut window = ReplayWindow::new(8);
+
+        assert!(window.accept(0));
+        assert!(!window.accept(40_000));
+        assert!(!window.accept(40_000));
+        assert!(window.accept(1));
+        assert_eq!(window.dropped_packets(), 2);
+    }
+}
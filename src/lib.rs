@@ -27,20 +27,24 @@
     unused
 )]
 #![warn(missing_docs)]
-#![allow(clippy::trivially_copy_pass_by_ref)]
+#![allow(clippy::trivially_copy_pass_by_ref, clippy::legacy_numeric_constants)]
 
 pub use self::config::Config;
-pub use self::error::{ErrorKind, Result};
+pub use self::error::{DecodingErrorKind, ErrorKind, Result};
+pub use self::infrastructure::CongestionControl;
 pub use self::net::{
-    Connection, ConnectionManager, ConnectionMessenger, DatagramSocket, LinkConditioner, Socket,
-    SocketEvent, VirtualConnection,
-    constants::PROTOCOL_VERSION
+    constants::PROTOCOL_VERSION, BandwidthLimit, CaptureSocket, CapturedDatagram, ConnectToken,
+    Connection, ConnectionManager, ConnectionMessenger, ConnectionStats, DatagramSocket,
+    DisconnectReason, DroppedDatagrams, LinkConditioner, LossModel, NetworkQuality, PacketSender,
+    PcapReader, PcapWriter, RateLimit, ReplaySocket, Socket, SocketCommand, SocketEvent,
+    VirtualConnection,
+};
+pub use self::packet::{
+    header, DeliveryGuarantee, MessageId, OrderingGuarantee, Packet, PacketReader, PacketType,
 };
-pub use self::packet::{DeliveryGuarantee, OrderingGuarantee, Packet};
 #[cfg(feature = "tester")]
 pub use self::throughput::ThroughputMonitoring;
 
-
 mod config;
 mod either;
 mod error;
@@ -52,5 +56,9 @@ mod sequence_buffer;
 #[cfg(feature = "tester")]
 mod throughput;
 
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
+
+/// Emulated sockets and networks used by the unit tests.
 #[cfg(test)]
 pub mod test_utils;
@@ -1,18 +1,30 @@
 //! This module provides the logic between the low-level abstract types and the types that the user will be interacting with.
 //! You can think of the socket, connection management, congestion control.
 
+pub use self::address_filter::{DroppedDatagrams, RateLimit};
+pub use self::capture::{CaptureSocket, ReplaySocket};
+pub use self::connect_token::ConnectToken;
 pub use self::connection::{Connection, ConnectionEventAddress, ConnectionMessenger};
 pub use self::connection_manager::{ConnectionManager, DatagramSocket};
-pub use self::events::SocketEvent;
-pub use self::link_conditioner::LinkConditioner;
-pub use self::socket::Socket;
+pub use self::events::{DisconnectReason, SocketCommand, SocketEvent};
+pub use self::link_conditioner::{BandwidthLimit, LinkConditioner, LossModel};
+pub use self::pcap::{CapturedDatagram, PcapReader, PcapWriter};
+pub use self::quality::{ConnectionStats, NetworkQuality, QualityMonitor, RttMeasurer};
+pub use self::socket::{PacketSender, Socket};
 pub use self::virtual_connection::VirtualConnection;
 
+mod address_filter;
+mod capture;
+mod connect_token;
 mod connection;
 mod connection_impl;
 mod connection_manager;
+mod encryption;
 mod events;
+mod handshake;
 mod link_conditioner;
+mod pcap;
+mod quality;
 mod socket;
 mod virtual_connection;
 
@@ -0,0 +1,419 @@
+//! Module with the filter that drops datagrams from banned addresses, and from addresses that send
+//! too many datagrams without having a connection, before `ConnectionManager` processes them.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+use super::constants::MAX_RATE_LIMITED_ADDRESSES;
+
+// How often the buckets that filled up again and the expired bans are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A rate limit for the datagrams received from an address or subnet, modelled as a token bucket.
+///
+/// The bucket fills with `datagrams_per_second` and holds up to `burst_size` datagrams, a datagram
+/// is dropped if the bucket is empty.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Rate at which datagrams are accepted.
+    pub datagrams_per_second: u32,
+    /// Number of datagrams that are accepted at once after the address was quiet.
+    pub burst_size: u32,
+}
+
+/// The number of received datagrams a `ConnectionManager` dropped before they reached a connection.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DroppedDatagrams {
+    /// Datagrams from banned addresses.
+    pub banned: u64,
+    /// Datagrams that exceeded `Config::address_rate_limit`.
+    pub address_rate_limited: u64,
+    /// Datagrams that exceeded `Config::subnet_rate_limit`.
+    pub subnet_rate_limited: u64,
+    /// Datagrams that would have created a connection while `Config::max_unestablished_connections`
+    /// connections were unestablished already.
+    pub unestablished_limit: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, time: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(limit.burst_size),
+            last_update: time,
+        }
+    }
+
+    // Refills the bucket up to the given time.
+    fn refill(&mut self, limit: RateLimit, time: Instant) {
+        let elapsed = time.saturating_duration_since(self.last_update);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * f64::from(limit.datagrams_per_second))
+            .min(f64::from(limit.burst_size));
+        self.last_update = self.last_update.max(time);
+    }
+
+    // Takes a token for a datagram, returns false if there is none left.
+    fn take(&mut self, limit: RateLimit, time: Instant) -> bool {
+        self.refill(limit, time);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, limit: RateLimit, time: Instant) -> bool {
+        self.refill(limit, time);
+        self.tokens >= f64::from(limit.burst_size)
+    }
+}
+
+// The token buckets of the addresses or subnets, at most `MAX_RATE_LIMITED_ADDRESSES` of them.
+#[derive(Debug, Default)]
+struct TokenBuckets {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // The keys of the buckets, in the order they are checked for eviction
+    order: VecDeque<IpAddr>,
+}
+
+impl TokenBuckets {
+    // Takes a token from the bucket of the given key. While too many buckets are tracked, a new
+    // key only gets a bucket if the next one in line filled up again and can be forgotten. Otherwise
+    // the datagram is dropped, so a flood of spoofed addresses neither allocates unbounded memory
+    // nor resets the buckets of the addresses that are rate limited.
+    fn take(&mut self, key: IpAddr, limit: RateLimit, time: Instant) -> bool {
+        if !self.buckets.contains_key(&key) {
+            if self.buckets.len() >= MAX_RATE_LIMITED_ADDRESSES && !self.evict_full(limit, time) {
+                return false;
+            }
+            self.buckets.insert(key, TokenBucket::new(limit, time));
+            self.order.push_back(key);
+        }
+        self.buckets
+            .get_mut(&key)
+            .is_some_and(|bucket| bucket.take(limit, time))
+    }
+
+    // Forgets the next bucket in line if it filled up again, otherwise moves it to the back of
+    // the line. Returns whether a bucket was forgotten.
+    fn evict_full(&mut self, limit: RateLimit, time: Instant) -> bool {
+        let key = match self.order.pop_front() {
+            Some(key) => key,
+            None => return false,
+        };
+        let is_full = self
+            .buckets
+            .get_mut(&key)
+            .is_some_and(|bucket| bucket.is_full(limit, time));
+        if is_full {
+            self.buckets.remove(&key);
+        } else {
+            self.order.push_back(key);
+        }
+        is_full
+    }
+
+    // Forgets the buckets that filled up again.
+    fn prune(&mut self, limit: RateLimit, time: Instant) {
+        self.buckets
+            .retain(|_, bucket| !bucket.is_full(limit, time));
+        let buckets = &self.buckets;
+        self.order.retain(|key| buckets.contains_key(key));
+    }
+}
+
+/// Decides which received datagrams are processed, see `Config::address_rate_limit`,
+/// `Config::subnet_rate_limit` and `ConnectionManager::ban`.
+#[derive(Debug)]
+pub struct AddressFilter {
+    address_limit: Option<RateLimit>,
+    subnet_limit: Option<RateLimit>,
+    subnet_prefix_v4: u8,
+    subnet_prefix_v6: u8,
+    address_buckets: TokenBuckets,
+    // The buckets of the subnets, by the first address of the subnet
+    subnet_buckets: TokenBuckets,
+    // The time every ban expires at, `None` for bans that never expire
+    bans: HashMap<IpAddr, Option<Instant>>,
+    allowed: HashSet<IpAddr>,
+    last_prune: Option<Instant>,
+    dropped: DroppedDatagrams,
+}
+
+impl AddressFilter {
+    /// Creates a filter with the rate limits of the given config and empty ban and allow lists.
+    pub fn new(config: &Config) -> AddressFilter {
+        AddressFilter {
+            address_limit: config.address_rate_limit,
+            subnet_limit: config.subnet_rate_limit,
+            subnet_prefix_v4: config.subnet_prefix_v4,
+            subnet_prefix_v6: config.subnet_prefix_v6,
+            address_buckets: TokenBuckets::default(),
+            subnet_buckets: TokenBuckets::default(),
+            bans: HashMap::new(),
+            allowed: HashSet::new(),
+            last_prune: None,
+            dropped: DroppedDatagrams::default(),
+        }
+    }
+
+    /// Drops all datagrams from the given address for the given duration, starting at the given time.
+    /// Durations that cannot be represented, like `Duration::MAX`, ban the address permanently.
+    pub fn ban(&mut self, address: IpAddr, duration: Duration, time: Instant) {
+        self.bans.insert(address, time.checked_add(duration));
+    }
+
+    /// Lifts the ban of the given address.
+    pub fn unban(&mut self, address: IpAddr) {
+        self.bans.remove(&address);
+    }
+
+    /// Exempts the given address from the rate limits.
+    pub fn allow(&mut self, address: IpAddr) {
+        self.allowed.insert(address);
+    }
+
+    /// Removes the given address from the addresses exempted from the rate limits.
+    pub fn remove_allowed(&mut self, address: IpAddr) {
+        self.allowed.remove(&address);
+    }
+
+    /// Once in a while, forgets the expired bans and the buckets that filled up again. Gets called
+    /// at the start of every poll.
+    pub fn update(&mut self, time: Instant) {
+        if self
+            .last_prune
+            .is_some_and(|last_prune| time.saturating_duration_since(last_prune) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.last_prune = Some(time);
+        self.bans
+            .retain(|_, expires| expires.is_none_or(|expires| time < expires));
+        if let Some(limit) = self.address_limit {
+            self.address_buckets.prune(limit, time);
+        }
+        if let Some(limit) = self.subnet_limit {
+            self.subnet_buckets.prune(limit, time);
+        }
+    }
+
+    /// Returns whether a datagram received from the given address should be processed.
+    /// Datagrams from banned addresses are always dropped, the rate limits only apply to addresses
+    /// without a connection.
+    pub fn accept(&mut self, address: IpAddr, is_connected: bool, time: Instant) -> bool {
+        if self
+            .bans
+            .get(&address)
+            .is_some_and(|expires| expires.is_none_or(|expires| time < expires))
+        {
+            self.dropped.banned += 1;
+            return false;
+        }
+        if is_connected || self.allowed.contains(&address) {
+            return true;
+        }
+
+        if let Some(limit) = self.address_limit {
+            if !self.address_buckets.take(address, limit, time) {
+                self.dropped.address_rate_limited += 1;
+                return false;
+            }
+        }
+        if let Some(limit) = self.subnet_limit {
+            let subnet = subnet_of(address, self.subnet_prefix_v4, self.subnet_prefix_v6);
+            if !self.subnet_buckets.take(subnet, limit, time) {
+                self.dropped.subnet_rate_limited += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the number of datagrams that were dropped by this filter.
+    pub fn dropped_datagrams(&self) -> DroppedDatagrams {
+        self.dropped
+    }
+}
+
+// Returns the first address of the subnet the given address belongs to.
+fn subnet_of(address: IpAddr, prefix_v4: u8, prefix_v6: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_v4.min(32)))
+                .unwrap_or(0);
+            IpAddr::from((u32::from(address) & mask).to_be_bytes())
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_v6.min(128)))
+                .unwrap_or(0);
+            IpAddr::from((u128::from(address) & mask).to_be_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::net::constants::MAX_RATE_LIMITED_ADDRESSES;
+    use crate::Config;
+
+    use super::{subnet_of, AddressFilter, RateLimit};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn filter() -> AddressFilter {
+        AddressFilter::new(&Config {
+            address_rate_limit: Some(RateLimit {
+                datagrams_per_second: 10,
+                burst_size: 2,
+            }),
+            subnet_rate_limit: Some(RateLimit {
+                datagrams_per_second: 10,
+                burst_size: 3,
+            }),
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn addresses_are_rate_limited() {
+        let mut filter = filter();
+        let time = Instant::now();
+
+        assert!(filter.accept(ip("10.0.0.1"), false, time));
+        assert!(filter.accept(ip("10.0.0.1"), false, time));
+        assert!(!filter.accept(ip("10.0.0.1"), false, time));
+        // connected addresses are not limited
+        assert!(filter.accept(ip("10.0.0.1"), true, time));
+
+        // the bucket refills with 10 datagrams per second
+        assert!(filter.accept(ip("10.0.0.1"), false, time + Duration::from_millis(100)));
+        assert!(!filter.accept(ip("10.0.0.1"), false, time + Duration::from_millis(100)));
+        assert_eq!(filter.dropped_datagrams().address_rate_limited, 2);
+    }
+
+    #[test]
+    fn subnets_are_rate_limited() {
+        let mut filter = filter();
+        let time = Instant::now();
+
+        assert!(filter.accept(ip("10.0.0.1"), false, time));
+        assert!(filter.accept(ip("10.0.0.2"), false, time));
+        assert!(filter.accept(ip("10.0.0.3"), false, time));
+        assert!(!filter.accept(ip("10.0.0.4"), false, time));
+        assert!(filter.accept(ip("10.0.1.1"), false, time));
+
+        // allowed addresses are not limited
+        filter.allow(ip("10.0.0.4"));
+        assert!(filter.accept(ip("10.0.0.4"), false, time));
+        filter.remove_allowed(ip("10.0.0.4"));
+        assert!(!filter.accept(ip("10.0.0.4"), false, time));
+        assert_eq!(filter.dropped_datagrams().subnet_rate_limited, 2);
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut filter = filter();
+        let time = Instant::now();
+
+        filter.ban(ip("10.0.0.1"), Duration::from_secs(10), time);
+        assert!(!filter.accept(ip("10.0.0.1"), true, time));
+        assert!(!filter.accept(ip("10.0.0.1"), true, time + Duration::from_secs(9)));
+        assert!(filter.accept(ip("10.0.0.1"), true, time + Duration::from_secs(10)));
+
+        filter.ban(ip("10.0.0.1"), Duration::from_secs(10), time);
+        filter.unban(ip("10.0.0.1"));
+        assert!(filter.accept(ip("10.0.0.1"), true, time));
+        assert_eq!(filter.dropped_datagrams().banned, 2);
+    }
+
+    #[test]
+    fn unlimited_bans_are_permanent() {
+        let mut filter = filter();
+        let time = Instant::now();
+
+        filter.ban(ip("10.0.0.1"), Duration::MAX, time);
+        filter.update(time + Duration::from_secs(3600));
+        assert!(!filter.accept(ip("10.0.0.1"), true, time + Duration::from_secs(3600)));
+
+        filter.unban(ip("10.0.0.1"));
+        assert!(filter.accept(ip("10.0.0.1"), true, time));
+    }
+
+    #[test]
+    fn full_buckets_are_forgotten() {
+        let mut filter = filter();
+        let time = Instant::now();
+
+        assert!(filter.accept(ip("10.0.0.1"), false, time));
+        filter.update(time);
+        assert_eq!(filter.address_buckets.buckets.len(), 1);
+        filter.update(time + Duration::from_secs(1));
+        assert_eq!(filter.address_buckets.buckets.len(), 0);
+        assert_eq!(filter.subnet_buckets.buckets.len(), 0);
+        assert!(filter.address_buckets.order.is_empty());
+    }
+
+    #[test]
+    fn only_full_buckets_are_evicted() {
+        let mut filter = AddressFilter::new(&Config {
+            address_rate_limit: Some(RateLimit {
+                datagrams_per_second: 10,
+                burst_size: 1,
+            }),
+            ..Config::default()
+        });
+        let time = Instant::now();
+        let address = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+
+        for i in 0..MAX_RATE_LIMITED_ADDRESSES {
+            assert!(filter.accept(address(i), false, time));
+        }
+        assert!(!filter.accept(address(0), false, time));
+
+        // spoofed addresses cannot push the buckets of rate limited addresses out
+        for i in MAX_RATE_LIMITED_ADDRESSES..2 * MAX_RATE_LIMITED_ADDRESSES {
+            assert!(!filter.accept(address(i), false, time));
+        }
+        assert!(!filter.accept(address(0), false, time));
+        assert_eq!(
+            filter.address_buckets.buckets.len(),
+            MAX_RATE_LIMITED_ADDRESSES
+        );
+
+        // buckets that filled up again make room for new addresses
+        let time = time + Duration::from_millis(100);
+        assert!(filter.accept(address(2 * MAX_RATE_LIMITED_ADDRESSES), false, time));
+        assert_eq!(
+            filter.address_buckets.buckets.len(),
+            MAX_RATE_LIMITED_ADDRESSES
+        );
+    }
+
+    #[test]
+    fn subnets_are_masked() {
+        assert_eq!(subnet_of(ip("192.168.17.42"), 24, 48), ip("192.168.17.0"));
+        assert_eq!(subnet_of(ip("192.168.17.42"), 0, 48), ip("0.0.0.0"));
+        assert_eq!(subnet_of(ip("192.168.17.42"), 32, 48), ip("192.168.17.42"));
+        assert_eq!(
+            subnet_of(ip("2001:db8:1:2:3::1"), 24, 48),
+            ip("2001:db8:1::")
+        );
+    }
+}
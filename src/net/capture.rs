@@ -0,0 +1,345 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+
+use crate::{
+    error::Result,
+    net::{
+        pcap::{CapturedDatagram, PcapReader, PcapWriter},
+        DatagramSocket,
+    },
+};
+
+/// Wraps a `DatagramSocket` and records every datagram it sends and receives to a pcap capture,
+/// which can be inspected with tools like Wireshark or fed back into a `ConnectionManager` with
+/// [`ReplaySocket`].
+///
+/// Datagrams are timestamped with the time of the poll they were sent or received in.
+pub struct CaptureSocket<S: DatagramSocket, W: Write> {
+    socket: S,
+    capture: Option<Capture<W>>,
+    // The time of the current poll
+    time: Option<Instant>,
+}
+
+struct Capture<W: Write> {
+    writer: PcapWriter<W>,
+    // The time the capture was started, as instant and as time since the Unix epoch
+    started: Instant,
+    started_timestamp: Duration,
+}
+
+impl<S: DatagramSocket, W: Write> CaptureSocket<S, W> {
+    /// Wraps the socket, without recording anything until a capture is started.
+    pub fn new(socket: S) -> Self {
+        CaptureSocket {
+            socket,
+            capture: None,
+            time: None,
+        }
+    }
+
+    /// Starts to record the datagrams to the given writer, a previous capture is stopped.
+    pub fn start_capture(&mut self, writer: W) -> Result<()> {
+        self.stop_capture()?;
+        self.capture = Some(Capture {
+            writer: PcapWriter::new(writer)?,
+            started: self.time.unwrap_or_else(Instant::now),
+            started_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    /// Stops the capture and returns its writer, once everything was written to it.
+    pub fn stop_capture(&mut self) -> Result<Option<W>> {
+        match self.capture.take() {
+            Some(mut capture) => {
+                capture.writer.flush()?;
+                Ok(Some(capture.writer.into_inner()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the wrapped socket.
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    /// Returns the wrapped socket mutably.
+    pub fn socket_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    fn record(&mut self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        if let Some(ref mut capture) = self.capture {
+            let time = self.time.unwrap_or_else(Instant::now);
+            let datagram = CapturedDatagram {
+                timestamp: capture.started_timestamp
+                    + time.saturating_duration_since(capture.started),
+                source,
+                destination,
+                payload: payload.to_vec(),
+            };
+            if let Err(e) = capture.writer.write_datagram(&datagram) {
+                error!("Error occured writing a datagram to the capture: {}", e);
+            }
+        }
+    }
+}
+
+impl<S: DatagramSocket, W: Write> fmt::Debug for CaptureSocket<S, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureSocket")
+            .field("socket", &self.socket)
+            .field("capturing", &self.capture.is_some())
+            .finish()
+    }
+}
+
+impl<S: DatagramSocket, W: Write> DatagramSocket for CaptureSocket<S, W> {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        let sent = self.socket.send_packet(addr, payload)?;
+        if self.capture.is_some() {
+            let local_addr = self.socket.local_addr()?;
+            self.record(local_addr, *addr, payload);
+        }
+        Ok(sent)
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        let (payload, addr) = self.socket.receive_packet(buffer)?;
+        if self.capture.is_some() {
+            let local_addr = self.socket.local_addr()?;
+            self.record(addr, local_addr, payload);
+        }
+        Ok((payload, addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.socket.is_blocking_mode()
+    }
+
+    fn poll(&mut self, time: Instant) {
+        self.time = Some(time);
+        self.socket.poll(time);
+    }
+}
+
+/// Feeds the datagrams that a socket received in a capture back into a `ConnectionManager`, with
+/// their original timing. Datagrams sent by the replayed `ConnectionManager` are discarded, the
+/// packets that the application sent during the capture have to be sent again to reproduce it.
+///
+/// The capture starts at the time given to [`new`](#method.new), call `manual_poll` with the times
+/// returned by [`next_poll_time`](#method.next_poll_time) until it returns `None` to replay it:
+///
+/// ```no_run
+/// # use std::{fs::File, time::Instant};
+/// # use laminar::{Config, ConnectionManager, ReplaySocket, VirtualConnection};
+/// let file = File::open("session.pcap").unwrap();
+/// let address = "127.0.0.1:12345".parse().unwrap();
+/// let socket = ReplaySocket::new(file, address, Instant::now()).unwrap();
+///
+/// let mut manager: ConnectionManager<_, VirtualConnection> =
+///     ConnectionManager::new(socket, Config::default());
+/// while let Some(time) = manager.socket().next_poll_time() {
+///     manager.manual_poll(time);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ReplaySocket {
+    address: SocketAddr,
+    // Datagrams that were sent or received by the socket, in the order they were captured
+    datagrams: VecDeque<CapturedDatagram>,
+    // The time the capture started, and the timestamp of its first datagram
+    start: Instant,
+    start_timestamp: Duration,
+    // The time of the current poll
+    time: Instant,
+}
+
+impl ReplaySocket {
+    /// Reads the datagrams of the capture that were sent or received by the given address.
+    pub fn new<R: Read>(capture: R, address: SocketAddr, start: Instant) -> Result<Self> {
+        let datagrams = PcapReader::new(capture)?.collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::from_datagrams(datagrams, address, start))
+    }
+
+    /// Replays the given datagrams that were sent or received by the given address.
+    pub fn from_datagrams(
+        datagrams: Vec<CapturedDatagram>,
+        address: SocketAddr,
+        start: Instant,
+    ) -> Self {
+        let datagrams: VecDeque<CapturedDatagram> = datagrams
+            .into_iter()
+            .filter(|datagram| datagram.source == address || datagram.destination == address)
+            .collect();
+        let start_timestamp = datagrams
+            .front()
+            .map_or_else(Duration::default, |datagram| datagram.timestamp);
+
+        ReplaySocket {
+            address,
+            datagrams,
+            start,
+            start_timestamp,
+            time: start,
+        }
+    }
+
+    /// Returns the time of the next poll of the capture, which is `None` once all datagrams were
+    /// replayed. Polls of the capture are recognised by the datagrams sent or received in them.
+    pub fn next_poll_time(&self) -> Option<Instant> {
+        self.datagrams
+            .front()
+            .map(|datagram| self.time_of(datagram))
+    }
+
+    fn time_of(&self, datagram: &CapturedDatagram) -> Instant {
+        self.start + datagram.timestamp.saturating_sub(self.start_timestamp)
+    }
+
+    // Skips the datagrams that were sent by the socket before the current poll.
+    fn skip_sent_datagrams(&mut self) {
+        while let Some(datagram) = self.datagrams.front() {
+            if datagram.source != self.address || self.time_of(datagram) > self.time {
+                break;
+            }
+            self.datagrams.pop_front();
+        }
+    }
+}
+
+impl DatagramSocket for ReplaySocket {
+    fn send_packet(&mut self, _addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        Ok(payload.len())
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        self.skip_sent_datagrams();
+        match self.datagrams.front() {
+            Some(datagram)
+                if datagram.destination == self.address && self.time_of(datagram) <= self.time => {}
+            _ => return Err(io::ErrorKind::WouldBlock.into()),
+        }
+
+        let datagram = self.datagrams.pop_front().unwrap();
+        let length = datagram.payload.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&datagram.payload[..length]);
+        Ok((&buffer[..length], datagram.source))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        false
+    }
+
+    fn poll(&mut self, time: Instant) {
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::net::{ConnectionManager, VirtualConnection};
+    use crate::test_utils::{EmulatedSocket, NetworkEmulator};
+    use crate::{Config, Packet, SocketEvent};
+
+    use super::{CaptureSocket, ReplaySocket};
+
+    fn server_address() -> SocketAddr {
+        "127.0.0.1:10001".parse().unwrap()
+    }
+
+    fn client_address() -> SocketAddr {
+        "127.0.0.1:10002".parse().unwrap()
+    }
+
+    type Manager<S> = ConnectionManager<S, VirtualConnection>;
+
+    fn events<S: crate::net::DatagramSocket>(manager: &Manager<S>) -> Vec<SocketEvent> {
+        manager.event_receiver().try_iter().collect()
+    }
+
+    #[test]
+    fn captured_session_replays_the_same_events() {
+        let network = NetworkEmulator::default();
+        let mut server: Manager<CaptureSocket<EmulatedSocket, Vec<u8>>> = ConnectionManager::new(
+            CaptureSocket::new(network.new_socket(server_address()).unwrap()),
+            Config::default(),
+        );
+        let mut client: Manager<EmulatedSocket> = ConnectionManager::new(
+            network.new_socket(client_address()).unwrap(),
+            Config::default(),
+        );
+        server.socket_mut().start_capture(Vec::new()).unwrap();
+
+        let mut time = Instant::now();
+        let mut server_events = Vec::new();
+        for i in 0..10 {
+            client
+                .event_sender()
+                .send(Packet::reliable_ordered(server_address(), vec![i], None))
+                .unwrap();
+            if i == 5 {
+                server
+                    .event_sender()
+                    .send(Packet::unreliable(client_address(), vec![i]))
+                    .unwrap();
+            }
+            client.manual_poll(time);
+            server.manual_poll(time);
+            server_events.extend(events(&server));
+            time += Duration::from_millis(100);
+        }
+        let capture = server.socket_mut().stop_capture().unwrap().unwrap();
+        assert_eq!(server_events.len(), 11);
+
+        let start = Instant::now();
+        let mut replay: Manager<ReplaySocket> = ConnectionManager::new(
+            ReplaySocket::new(capture.as_slice(), server_address(), start).unwrap(),
+            Config::default(),
+        );
+        let mut replayed_events = Vec::new();
+        let mut poll_times = Vec::new();
+        while let Some(poll_time) = replay.socket().next_poll_time() {
+            // the packets sent by the application are not part of the replay
+            if poll_times.len() == 5 {
+                replay
+                    .event_sender()
+                    .send(Packet::unreliable(client_address(), vec![5]))
+                    .unwrap();
+            }
+            poll_times.push(poll_time - start);
+            replay.manual_poll(poll_time);
+            replayed_events.extend(events(&replay));
+        }
+
+        assert_eq!(replayed_events, server_events);
+        assert_eq!(
+            poll_times,
+            (0..10)
+                .map(|i| Duration::from_millis(100 * i))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Module with the connect tokens a backend hands out to the clients that may connect to a server.
+//!
+//! A connect token is signed with a private key that only the backend and the server know. The
+//! client cannot read or alter it, it presents the token in its `ConnectionRequest`. The server
+//! only answers requests carrying a valid token that has not expired, was not used from another
+//! address before and did not establish a connection yet.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The size of a connect token.
+pub const CONNECT_TOKEN_SIZE: usize = 56;
+// The size of the signed part of a connect token.
+const SIGNED_SIZE: usize = 24;
+const MAC_SIZE: usize = CONNECT_TOKEN_SIZE - SIGNED_SIZE;
+
+/// A token that allows a single client to connect to servers that share the private key it was
+/// signed with, until it expires.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ConnectToken {
+    client_id: u64,
+    // Seconds since the Unix epoch
+    expires_at: u64,
+    // Random number that makes every token unique
+    nonce: u64,
+    mac: [u8; MAC_SIZE],
+}
+
+impl ConnectToken {
+    /// Creates a new token for the given client, signed with the given private key.
+    ///
+    /// Tokens are usually created by the backend that decides which clients may join a server,
+    /// which passes them on to the client. See `Config::connect_token_key`.
+    pub fn new(private_key: &[u8; 32], client_id: u64, expires: SystemTime) -> ConnectToken {
+        let mut token = ConnectToken {
+            client_id,
+            expires_at: expires
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            nonce: rand::random(),
+            mac: [0; MAC_SIZE],
+        };
+        token.mac = token.sign(private_key);
+        token
+    }
+
+    /// Creates a new token for the given client which is valid for the given duration.
+    pub fn with_lifetime(
+        private_key: &[u8; 32],
+        client_id: u64,
+        lifetime: Duration,
+    ) -> ConnectToken {
+        Self::new(private_key, client_id, SystemTime::now() + lifetime)
+    }
+
+    /// Returns the id of the client the token was issued for.
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// Returns the time the token expires at.
+    pub fn expires(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires_at)
+    }
+
+    /// Returns whether the token was signed with the given private key and has not expired at the
+    /// given time.
+    pub fn is_valid(&self, private_key: &[u8; 32], time: SystemTime) -> bool {
+        let mut mac = hmac(private_key);
+        mac.update(&self.signed_bytes());
+        time <= self.expires() && mac.verify_slice(&self.mac).is_ok()
+    }
+
+    /// Writes this token to the bytes that are handed to the client.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = self.signed_bytes();
+        buffer.extend_from_slice(&self.mac);
+        buffer
+    }
+
+    /// Reads a token, returns `None` if the bytes have the wrong size.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ConnectToken> {
+        if bytes.len() != CONNECT_TOKEN_SIZE {
+            return None;
+        }
+
+        let mut rdr = Cursor::new(bytes);
+        let mut token = ConnectToken {
+            client_id: rdr.read_u64::<BigEndian>().ok()?,
+            expires_at: rdr.read_u64::<BigEndian>().ok()?,
+            nonce: rdr.read_u64::<BigEndian>().ok()?,
+            mac: [0; MAC_SIZE],
+        };
+        token.mac.copy_from_slice(&bytes[SIGNED_SIZE..]);
+        Some(token)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(CONNECT_TOKEN_SIZE);
+        // writing into a `Vec` cannot fail.
+        buffer.write_u64::<BigEndian>(self.client_id).unwrap();
+        buffer.write_u64::<BigEndian>(self.expires_at).unwrap();
+        buffer.write_u64::<BigEndian>(self.nonce).unwrap();
+        buffer
+    }
+
+    fn sign(&self, private_key: &[u8; 32]) -> [u8; MAC_SIZE] {
+        let mut mac = hmac(private_key);
+        mac.update(&self.signed_bytes());
+        mac.finalize().into_bytes().into()
+    }
+}
+
+fn hmac(private_key: &[u8; 32]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(private_key).expect("HMAC accepts keys of any size")
+}
+
+/// Verifies the connect tokens presented by clients and remembers which address used which
+/// token, so a token that leaked cannot be used by anyone else.
+#[derive(Debug, Default)]
+pub struct ConnectTokenVerifier {
+    // The address every token was first used from and whether it established a connection, until
+    // the token expires
+    used_tokens: HashMap<[u8; MAC_SIZE], UsedToken>,
+}
+
+#[derive(Debug)]
+struct UsedToken {
+    address: SocketAddr,
+    expires: SystemTime,
+    consumed: bool,
+}
+
+impl ConnectTokenVerifier {
+    /// Verifies a token presented by the given address, returns the token if it is valid.
+    ///
+    /// A token can be presented any number of times from the address that presented it first,
+    /// as requests may get lost and have to be resent, until it is consumed.
+    pub fn verify(
+        &mut self,
+        private_key: &[u8; 32],
+        token: &[u8],
+        address: SocketAddr,
+        time: SystemTime,
+    ) -> Option<ConnectToken> {
+        let token = ConnectToken::from_bytes(token)?;
+        if !token.is_valid(private_key, time) {
+            return None;
+        }
+
+        self.used_tokens.retain(|_, used| time <= used.expires);
+        let used = self.used_tokens.entry(token.mac).or_insert(UsedToken {
+            address,
+            expires: token.expires(),
+            consumed: false,
+        });
+        if used.address != address || used.consumed {
+            return None;
+        }
+        Some(token)
+    }
+
+    /// Consumes the tokens presented by the given address once its connection is established, so
+    /// they cannot establish another one.
+    pub fn consume(&mut self, address: SocketAddr) {
+        for used in self.used_tokens.values_mut() {
+            if used.address == address {
+                used.consumed = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    use super::{ConnectToken, ConnectTokenVerifier, CONNECT_TOKEN_SIZE};
+
+    const KEY: [u8; 32] = [3; 32];
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:12345".parse().unwrap()
+    }
+
+    #[test]
+    fn token_serialization() {
+        let token = ConnectToken::with_lifetime(&KEY, 42, Duration::from_secs(30));
+        let bytes = token.to_bytes();
+
+        assert_eq!(bytes.len(), CONNECT_TOKEN_SIZE);
+        assert_eq!(ConnectToken::from_bytes(&bytes), Some(token));
+        assert_eq!(ConnectToken::from_bytes(&bytes[1..]), None);
+        assert_eq!(token.client_id(), 42);
+    }
+
+    #[test]
+    fn token_is_bound_to_key_and_contents() {
+        let time = SystemTime::now();
+        let token = ConnectToken::with_lifetime(&KEY, 42, Duration::from_secs(30));
+        assert!(token.is_valid(&KEY, time));
+        assert!(!token.is_valid(&[4; 32], time));
+
+        let mut bytes = token.to_bytes();
+        bytes[7] ^= 1;
+        assert!(!ConnectToken::from_bytes(&bytes)
+            .unwrap()
+            .is_valid(&KEY, time));
+    }
+
+    #[test]
+    fn token_expires() {
+        let token = ConnectToken::with_lifetime(&KEY, 42, Duration::from_secs(30));
+
+        assert!(token.is_valid(&KEY, token.expires()));
+        assert!(!token.is_valid(&KEY, token.expires() + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn token_is_bound_to_the_first_address() {
+        let mut verifier = ConnectTokenVerifier::default();
+        let token = ConnectToken::with_lifetime(&KEY, 42, Duration::from_secs(30)).to_bytes();
+        let time = SystemTime::now();
+
+        assert!(verifier.verify(&KEY, &token, address(), time).is_some());
+        assert!(verifier.verify(&KEY, &token, address(), time).is_some());
+        assert!(verifier
+            .verify(&KEY, &token, "127.0.0.1:12346".parse().unwrap(), time)
+            .is_none());
+
+        // once the token expired it is forgotten
+        let expired = time + Duration::from_secs(31);
+        let other = ConnectToken::new(&KEY, 43, expired + Duration::from_secs(30)).to_bytes();
+        assert!(verifier.verify(&KEY, &other, address(), expired).is_some());
+        assert_eq!(verifier.used_tokens.len(), 1);
+    }
+
+    #[test]
+    fn consumed_token_is_rejected() {
+        let mut verifier = ConnectTokenVerifier::default();
+        let token = ConnectToken::with_lifetime(&KEY, 42, Duration::from_secs(30)).to_bytes();
+        let time = SystemTime::now();
+
+        assert!(verifier.verify(&KEY, &token, address(), time).is_some());
+        verifier.consume(address());
+        assert!(verifier.verify(&KEY, &token, address(), time).is_none());
+    }
+}
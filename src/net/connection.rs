@@ -1,6 +1,7 @@
 use std::{self, fmt::Debug, net::SocketAddr, time::Instant};
 
 use crate::config::Config;
+use crate::net::DisconnectReason;
 
 /// Allows connection to send packet, send event and get global configuration.
 pub trait ConnectionMessenger<ReceiveEvent: Debug> {
@@ -27,6 +28,23 @@ pub trait Connection: Debug {
     type SendEvent: Debug + ConnectionEventAddress;
     /// Defines a connection event type.
     type ReceiveEvent: Debug + ConnectionEventAddress;
+    /// Defines the state that decides if a packet from an unknown address may create a connection.
+    /// It is shared by all connections of a `ConnectionManager`.
+    type Acceptor: Debug + Default;
+
+    /// Decides whether a packet received from an address without a connection should create a new one.
+    /// * acceptor - shared state used to make the decision, e.g. to issue and verify challenges.
+    /// * messenger - allows to send packets and events, also provides a config.
+    /// * address - defines the address the packet was received from.
+    /// * payload - the received packet.
+    /// * time - the time the packet was received at.
+    fn should_accept(
+        acceptor: &mut Self::Acceptor,
+        messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
+        address: SocketAddr,
+        payload: &[u8],
+        time: Instant,
+    ) -> bool;
 
     /// Creates new connection and initialize it by sending an connection event to the user.
     /// * messenger - allows to send packets and events, also provides a config.
@@ -41,6 +59,11 @@ pub trait Connection: Debug {
     /// Connections are considered established once they have both had both a send and a receive.
     fn is_established(&self) -> bool;
 
+    /// Returns whether the remote host proved that it receives the packets sent to its address.
+    /// Until then, only a limited number of bytes is sent to it if `Config::handshake_enabled` is
+    /// set, see `Config::amplification_factor`.
+    fn is_verified(&self) -> bool;
+
     /// Determines if the connection should be dropped due to its state.
     fn should_drop(
         &mut self,
@@ -64,6 +87,14 @@ pub trait Connection: Debug {
         time: Instant,
     );
 
+    /// Starts to close the connection gracefully and notifies the remote host with the given reason.
+    fn disconnect(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
+        reason: DisconnectReason,
+        time: Instant,
+    );
+
     /// Processes various connection-related tasks: resend dropped packets, send heartbeat packet, etc...
     /// This function gets called frequently.
     fn update(
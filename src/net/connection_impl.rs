@@ -1,13 +1,21 @@
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
-use log::error;
+use log::{debug, error};
 
-use crate::error::{ErrorKind, Result};
-use crate::packet::{DeliveryGuarantee, OutgoingPackets, Packet, PacketInfo};
+use crate::error::{ErrorKind, FragmentErrorKind, Result};
+use crate::packet::{
+    DeliveryGuarantee, OrderingGuarantee, OutgoingPacketBuilder, OutgoingPackets, Packet,
+    PacketInfo, PacketReader, PacketType,
+};
 
 use super::{
-    events::SocketEvent, Connection, ConnectionEventAddress, ConnectionMessenger, VirtualConnection,
+    connect_token::CONNECT_TOKEN_SIZE,
+    constants::DISCONNECT_PACKET_REDUNDANCY,
+    encryption::split_public_key,
+    events::{DisconnectReason, SocketEvent},
+    handshake::{request_from_bytes, Challenge, HandshakeAcceptor, MIN_CONNECTION_REQUEST_SIZE},
+    Connection, ConnectionEventAddress, ConnectionMessenger, VirtualConnection,
 };
 
 /// Required by `ConnectionManager` to properly handle connection event.
@@ -18,7 +26,10 @@ impl ConnectionEventAddress for SocketEvent {
             SocketEvent::Packet(packet) => packet.addr(),
             SocketEvent::Connect(addr) => *addr,
             SocketEvent::Timeout(addr) => *addr,
-            SocketEvent::Disconnect(addr) => *addr,
+            SocketEvent::Disconnect(addr, _) => *addr,
+            SocketEvent::QualityChanged(addr, _) => *addr,
+            SocketEvent::Acked(addr, _) => *addr,
+            SocketEvent::Lost(addr, _) => *addr,
         }
     }
 }
@@ -36,6 +47,94 @@ impl Connection for VirtualConnection {
     type SendEvent = Packet;
     /// Defines a connection event type.
     type ReceiveEvent = SocketEvent;
+    /// Issues and verifies the handshake challenges and connect tokens for unknown addresses.
+    type Acceptor = HandshakeAcceptor;
+
+    /// Accepts every packet when the handshake is disabled.
+    /// Otherwise answers connection requests with a challenge, without creating a connection,
+    /// and only accepts connection responses carrying a valid challenge. Connection requests
+    /// that are not padded, or without a valid connect token if the config requires one, are
+    /// ignored.
+    fn should_accept(
+        acceptor: &mut Self::Acceptor,
+        messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
+        address: SocketAddr,
+        payload: &[u8],
+        time: Instant,
+    ) -> bool {
+        if !messenger.config().handshake_enabled {
+            return true;
+        }
+
+        let datagram_size = payload.len();
+        let mut packet_reader = PacketReader::new(payload);
+        let header = match packet_reader.read_standard_header() {
+            Ok(header) if header.is_current_protocol() => header,
+            _ => return false,
+        };
+
+        // the public key of the key exchange is only needed once the connection is created.
+        let payload = packet_reader.read_payload();
+        let payload = if messenger.config().encryption_enabled {
+            match split_public_key(&payload) {
+                Some((payload, _public_key)) => payload,
+                None => return false,
+            }
+        } else {
+            &payload
+        };
+
+        match header.packet_type() {
+            PacketType::ConnectionRequest => {
+                // the challenge must not be larger than the request it answers.
+                if datagram_size < MIN_CONNECTION_REQUEST_SIZE {
+                    return false;
+                }
+
+                if let Some((salt, remainder)) = request_from_bytes(payload) {
+                    if let Some(private_key) = messenger.config().connect_token_key {
+                        // the connect token is followed by the padding of the request.
+                        let connect_token = remainder.get(..CONNECT_TOKEN_SIZE).unwrap_or_default();
+                        if acceptor
+                            .connect_tokens
+                            .verify(&private_key, connect_token, address, SystemTime::now())
+                            .is_none()
+                        {
+                            return false;
+                        }
+                    }
+
+                    let challenge = acceptor.challenges.issue(address, salt, time).to_bytes();
+                    let packet = OutgoingPacketBuilder::new(&challenge)
+                        .with_default_header(
+                            PacketType::ConnectionChallenge,
+                            DeliveryGuarantee::Unreliable,
+                            OrderingGuarantee::None,
+                        )
+                        .build();
+                    messenger.send_packet(&address, &packet.contents());
+                }
+                false
+            }
+            PacketType::ConnectionResponse => {
+                let accepted = Challenge::from_bytes(payload).is_some_and(|challenge| {
+                    acceptor.challenges.verify(
+                        address,
+                        &challenge,
+                        time,
+                        messenger.config().handshake_challenge_lifetime,
+                    )
+                });
+                // the connection is established with this response, so its token cannot be used
+                // to establish another one.
+                if accepted {
+                    acceptor.connect_tokens.consume(address);
+                }
+                accepted
+            }
+            _ => false,
+        }
+    }
 
     /// Creates new connection and initialize it by sending an connection event to the user.
     /// * address - defines a address that connection is associated with.
@@ -54,12 +153,22 @@ impl Connection for VirtualConnection {
         self.is_established()
     }
 
+    /// The remote host is verified once the handshake completed.
+    fn is_verified(&self) -> bool {
+        self.is_verified()
+    }
+
     /// Determines if the given `Connection` should be dropped due to its state.
     fn should_drop(
         &mut self,
         messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
         time: Instant,
     ) -> bool {
+        if self.is_closed() {
+            // the disconnect event was already sent when the connection got closed.
+            return true;
+        }
+
         let should_drop = self.packets_in_flight() > messenger.config().max_packets_in_flight
             || self.last_heard(time) >= messenger.config().idle_connection_timeout;
         if should_drop {
@@ -70,7 +179,7 @@ impl Connection for VirtualConnection {
             if self.is_established() {
                 messenger.send_event(
                     &self.remote_address,
-                    SocketEvent::Disconnect(self.remote_address),
+                    SocketEvent::Disconnect(self.remote_address, DisconnectReason::Timeout),
                 );
             }
         }
@@ -94,9 +203,50 @@ impl Connection for VirtualConnection {
                         );
                     }
 
-                    for incoming in packets {
-                        messenger.send_event(&self.remote_address, SocketEvent::Packet(incoming.0));
+                    for (packet, packet_type) in packets {
+                        if packet_type == PacketType::Disconnect {
+                            self.process_disconnect_packet(messenger, packet.payload());
+                            break;
+                        }
+
+                        if self.is_disconnecting() {
+                            // the user was already notified about the disconnect.
+                            continue;
+                        }
+
+                        if packet_type.is_handshake() {
+                            if messenger.config().handshake_enabled {
+                                self.process_handshake_packet(
+                                    messenger,
+                                    packet_type,
+                                    packet.payload(),
+                                    time,
+                                );
+                            }
+                            continue;
+                        }
+
+                        if !self.is_established() && messenger.config().handshake_enabled {
+                            if !self.accept_implicitly() {
+                                // the remote host did not complete the handshake yet.
+                                continue;
+                            }
+                            self.on_handshake_completed(messenger, time);
+                        }
+
+                        messenger.send_event(&self.remote_address, SocketEvent::Packet(packet));
                     }
+
+                    for message_id in self.take_acked_messages() {
+                        messenger.send_event(
+                            &self.remote_address,
+                            SocketEvent::Acked(self.remote_address, message_id),
+                        );
+                    }
+                }
+                // fragments are duplicated by resends and by the network, anyone can send them.
+                Err(ErrorKind::FragmentError(FragmentErrorKind::AlreadyProcessedFragment)) => {
+                    debug!("Dropped a fragment that was already received")
                 }
                 Err(err) => error!("Error occured processing incomming packet: {:?}", err),
             }
@@ -115,25 +265,41 @@ impl Connection for VirtualConnection {
         event: Self::SendEvent,
         time: Instant,
     ) {
-        let addr = self.remote_address;
-        if self.record_send() {
-            messenger.send_event(&addr, SocketEvent::Connect(addr));
+        if self.is_disconnecting() {
+            error!(
+                "Dropped packet to {}, the connection is disconnecting",
+                self.remote_address
+            );
+            return;
         }
 
-        send_packets(
-            messenger,
-            &addr,
-            self.process_outgoing(
-                PacketInfo::user_packet(
-                    event.payload(),
-                    event.delivery_guarantee(),
-                    event.order_guarantee(),
-                ),
-                None,
-                time,
-            ),
-            "user packet",
-        );
+        if messenger.config().handshake_enabled && !self.is_established() {
+            // user packets are held back until the remote host accepted us.
+            self.queue_pending_packet(event);
+            if let Some((packet_type, payload)) = self.start_handshake() {
+                self.send_control_packet(messenger, packet_type, &payload, time);
+            }
+            return;
+        }
+
+        self.queue_or_send_user_packet(messenger, event, time);
+    }
+
+    /// Notifies the user immediately, then keeps resending unacknowledged reliable packets until
+    /// they are acknowledged or `disconnect_flush_timeout` has passed, before sending the disconnect packet.
+    fn disconnect(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
+        reason: DisconnectReason,
+        time: Instant,
+    ) {
+        let deadline = time + messenger.config().disconnect_flush_timeout;
+        if self.disconnect(reason, deadline) && self.is_established() {
+            messenger.send_event(
+                &self.remote_address,
+                SocketEvent::Disconnect(self.remote_address, reason),
+            );
+        }
     }
 
     /// Processes various connection-related tasks: resend dropped packets, send heartbeat packet, etc...
@@ -142,54 +308,273 @@ impl Connection for VirtualConnection {
         &mut self,
         messenger: &mut impl ConnectionMessenger<Self::ReceiveEvent>,
         time: Instant,
+    ) {
+        if self.is_closed() {
+            return;
+        }
+
+        self.update_connection(messenger, time);
+
+        // this is the last chance to send the packets of this poll.
+        for datagram in self.take_aggregated_datagrams() {
+            messenger.send_packet(&self.remote_address, &datagram);
+        }
+    }
+}
+
+impl VirtualConnection {
+    // Resends dropped packets, notifies about the connection state and sends the packets that
+    // were held back, the disconnect packet, handshake packets, heartbeats and acknowledgments.
+    fn update_connection(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        time: Instant,
     ) {
         // resend dropped packets
-        for dropped in self.gather_dropped_packets() {
-            let packets = self.process_outgoing(
-                PacketInfo {
-                    packet_type: dropped.packet_type,
-                    payload: &dropped.payload,
-                    // because a delivery guarantee is only sent with reliable packets
-                    delivery: DeliveryGuarantee::Reliable,
-                    // this is stored with the dropped packet because they could be mixed
-                    ordering: dropped.ordering_guarantee,
-                },
-                dropped.item_identifier,
-                time,
+        for dropped in self.gather_dropped_packets(time) {
+            let packets = self.process_dropped(&dropped, time);
+            let sent = self.send_packets(messenger, packets, "dropped packets");
+            self.record_sent_datagrams(sent);
+        }
+
+        for message_id in self.gather_lost_messages(time) {
+            messenger.send_event(
+                &self.remote_address,
+                SocketEvent::Lost(self.remote_address, message_id),
             );
-            send_packets(messenger, &self.remote_address, packets, "dropped packets");
+        }
+
+        if self.is_established() {
+            if let Some(quality) = self.update_quality(time) {
+                messenger.send_event(
+                    &self.remote_address,
+                    SocketEvent::QualityChanged(self.remote_address, quality),
+                );
+            }
+            self.update_send_rate(time);
+        }
+
+        // send the queued user packets the send rate allows for
+        while let Some(packet) = self.next_paced_packet(time) {
+            self.send_user_packet(messenger, packet, time);
+        }
+
+        // send the disconnect packet once the reliable packets have been flushed
+        if let Some(reason) = self.flushed_disconnect(time) {
+            // the handshake challenge proves that the disconnect packet comes from us.
+            let mut payload = reason.to_bytes().to_vec();
+            if let Some(challenge) = self.handshake_challenge() {
+                payload.extend_from_slice(&challenge.to_bytes());
+            }
+            for _ in 0..DISCONNECT_PACKET_REDUNDANCY {
+                self.send_control_packet(messenger, PacketType::Disconnect, &payload, time);
+            }
+            self.close();
+            return;
+        }
+
+        // resend the handshake packet we are waiting an answer for
+        if messenger.config().handshake_enabled
+            && self.last_sent(time) >= messenger.config().handshake_resend_interval
+        {
+            if let Some((packet_type, payload)) = self.pending_handshake_packet() {
+                self.send_control_packet(messenger, packet_type, &payload, time);
+            }
         }
 
         // send heartbeat packets if required
         if self.is_established() {
             if let Some(heartbeat_interval) = messenger.config().heartbeat_interval {
-                let addr = self.remote_address;
                 if self.last_sent(time) >= heartbeat_interval {
-                    send_packets(
-                        messenger,
-                        &addr,
-                        self.process_outgoing(PacketInfo::heartbeat_packet(&[]), None, time),
-                        "heatbeat packet",
-                    );
+                    let packets =
+                        self.process_outgoing(PacketInfo::heartbeat_packet(&[]), None, time);
+                    self.send_packets(messenger, packets, "heatbeat packet");
                 }
             }
         }
+
+        // acknowledge received packets that no outgoing packet acknowledged in time, this is
+        // also needed when we never send anything. With the handshake enabled only verified
+        // remote hosts are acknowledged, so spoofed packets are not reflected.
+        if self.is_established() || !messenger.config().handshake_enabled {
+            if let Some(packet) = self.pending_ack_packet(time) {
+                self.send_datagram(messenger, &packet.contents());
+            }
+        }
     }
-}
 
-// Sends multiple outgoing packets.
-fn send_packets(
-    ctx: &mut impl ConnectionMessenger<SocketEvent>,
-    address: &SocketAddr,
-    packets: Result<OutgoingPackets<'_>>,
-    err_context: &str,
-) {
-    match packets {
-        Ok(packets) => {
-            for outgoing in packets {
-                ctx.send_packet(address, &outgoing.contents());
+    // Sends a user packet right away, or queues it if the send rate is limited by congestion control.
+    fn queue_or_send_user_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packet: Packet,
+        time: Instant,
+    ) {
+        if self.is_pacing() {
+            // queued packets are sent in `update` once the send rate allows it.
+            if !self.queue_paced_packet(packet) {
+                error!(
+                    "Dropped packet to {}, too many packets are waiting to be sent",
+                    self.remote_address
+                );
             }
+        } else {
+            self.send_user_packet(messenger, packet, time);
+        }
+    }
+
+    // Sends a user packet, which also counts as a send to establish the connection.
+    fn send_user_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packet: Packet,
+        time: Instant,
+    ) {
+        let addr = self.remote_address;
+        if self.record_send() {
+            messenger.send_event(&addr, SocketEvent::Connect(addr));
+        }
+
+        let packets = self.process_outgoing(
+            PacketInfo::user_packet(
+                packet.payload(),
+                packet.delivery_guarantee(),
+                packet.order_guarantee(),
+            )
+            .with_message_id(packet.message_id()),
+            None,
+            time,
+        );
+        let sent = self.send_packets(messenger, packets, "user packet");
+        self.record_sent_datagrams(sent);
+    }
+
+    // Sends a control packet, e.g. a handshake or disconnect packet.
+    fn send_control_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packet_type: PacketType,
+        payload: &[u8],
+        time: Instant,
+    ) {
+        let packets =
+            self.process_outgoing(PacketInfo::control_packet(packet_type, payload), None, time);
+        self.send_packets(messenger, packets, "control packet");
+    }
+
+    // Closes the connection on request of the remote host.
+    //
+    // Only established connections can be closed this way. When the handshake is enabled, the
+    // packet has to carry the challenge the handshake was completed with, so a host that spoofs
+    // the address of the remote host cannot close the connection.
+    fn process_disconnect_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        payload: &[u8],
+    ) {
+        if !self.is_established() || payload.len() < 2 {
+            return;
+        }
+
+        let (reason, challenge) = payload.split_at(2);
+        let reason = match DisconnectReason::from_bytes(reason) {
+            Some(reason) => reason,
+            None => return,
+        };
+        if messenger.config().handshake_enabled
+            && Challenge::from_bytes(challenge) != self.handshake_challenge()
+        {
+            return;
+        }
+
+        if !self.is_disconnecting() {
+            messenger.send_event(
+                &self.remote_address,
+                SocketEvent::Disconnect(self.remote_address, reason),
+            );
+        }
+        self.close();
+    }
+
+    // Advances the handshake and answers the remote host if needed.
+    fn process_handshake_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packet_type: PacketType,
+        payload: &[u8],
+        time: Instant,
+    ) {
+        let was_established = self.is_established();
+        if let Some((packet_type, payload)) = self.process_handshake(packet_type, payload, time) {
+            self.send_control_packet(messenger, packet_type, &payload, time);
+        }
+        if !was_established && self.is_established() {
+            self.on_handshake_completed(messenger, time);
+        }
+    }
+
+    // Notifies the user and sends the packets that were held back during the handshake.
+    fn on_handshake_completed(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        time: Instant,
+    ) {
+        messenger.send_event(
+            &self.remote_address,
+            SocketEvent::Connect(self.remote_address),
+        );
+        for packet in self.take_pending_packets() {
+            self.queue_or_send_user_packet(messenger, packet, time);
+        }
+    }
+
+    // Sends multiple outgoing packets, returns the number of packets that were sent.
+    fn send_packets(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packets: Result<OutgoingPackets<'_>>,
+        err_context: &str,
+    ) -> usize {
+        match packets {
+            Ok(packets) => {
+                let mut sent = 0;
+                for outgoing in packets {
+                    self.send_datagram(messenger, &outgoing.contents());
+                    sent += 1;
+                }
+                sent
+            }
+            Err(error) => {
+                error!("Error occured processing {}: {:?}", err_context, error);
+                0
+            }
+        }
+    }
+
+    // Sends a single packet, or holds it back until the end of `update` to aggregate it with
+    // the other packets of this poll. The packet is encrypted first if encryption is enabled.
+    // Handshake packets are never aggregated, the remote host only accepts them on their own.
+    fn send_datagram(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        packet: &[u8],
+    ) {
+        let is_handshake = PacketReader::new(packet)
+            .read_standard_header()
+            .is_ok_and(|header| header.packet_type().is_handshake());
+        let encrypted = match self.encrypt_outgoing(packet) {
+            Ok(encrypted) => encrypted,
+            Err(error) => {
+                error!("Error occured encrypting packet: {:?}", error);
+                return;
+            }
+        };
+        let packet = encrypted.as_deref().unwrap_or(packet);
+
+        if messenger.config().aggregate_packets && !is_handshake {
+            self.aggregate_packet(packet);
+        } else {
+            messenger.send_packet(&self.remote_address, packet);
         }
-        Err(error) => error!("Error occured processing {}: {:?}", err_context, error),
     }
 }
@@ -1,10 +1,19 @@
-use std::{self, collections::HashMap, fmt::Debug, io::Result, net::SocketAddr, time::Instant};
+use std::{
+    self,
+    collections::HashMap,
+    fmt::Debug,
+    io::Result,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use crossbeam_channel::{self, unbounded, Receiver, Sender};
-use log::error;
+use log::{error, warn};
 
 use crate::{
-    config::Config, net::Connection, net::ConnectionEventAddress, net::ConnectionMessenger,
+    config::Config, net::address_filter::AddressFilter, net::Connection,
+    net::ConnectionEventAddress, net::ConnectionMessenger, net::DisconnectReason,
+    net::DroppedDatagrams, net::SocketCommand,
 };
 
 // TODO: maybe we can make a breaking change and use this instead of `ConnectionEventAddress` trait?
@@ -24,6 +33,10 @@ pub trait DatagramSocket: Debug {
 
     /// Returns whether socket operates in blocking or non-blocking mode.
     fn is_blocking_mode(&self) -> bool;
+
+    /// Gets called with the current time at the start of every poll, before any packet is sent or
+    /// received. Sockets that hold packets back, e.g. to simulate latency, can release them here.
+    fn poll(&mut self, _time: Instant) {}
 }
 
 // This will be used by a `Connection`.
@@ -32,6 +45,8 @@ struct SocketEventSenderAndConfig<TSocket: DatagramSocket, ReceiveEvent: Debug>
     config: Config,
     socket: TSocket,
     event_sender: Sender<ReceiveEvent>,
+    // The number of bytes that may still be sent to the addresses that were not verified yet.
+    send_allowances: HashMap<SocketAddr, usize>,
 }
 
 impl<TSocket: DatagramSocket, ReceiveEvent: Debug>
@@ -42,8 +57,16 @@ impl<TSocket: DatagramSocket, ReceiveEvent: Debug>
             config,
             socket,
             event_sender,
+            send_allowances: Default::default(),
         }
     }
+
+    // Allows sending `amplification_factor` times the size of a received datagram to its address.
+    fn grant_allowance(&mut self, address: SocketAddr, datagram_size: usize) {
+        let allowance = self.send_allowances.entry(address).or_default();
+        *allowance = allowance
+            .saturating_add(datagram_size.saturating_mul(self.config.amplification_factor));
+    }
 }
 
 impl<TSocket: DatagramSocket, ReceiveEvent: Debug> ConnectionMessenger<ReceiveEvent>
@@ -58,6 +81,17 @@ impl<TSocket: DatagramSocket, ReceiveEvent: Debug> ConnectionMessenger<ReceiveEv
     }
 
     fn send_packet(&mut self, address: &SocketAddr, payload: &[u8]) {
+        if let Some(allowance) = self.send_allowances.get_mut(address) {
+            if payload.len() > *allowance {
+                warn!(
+                    "Dropped packet to {}, the address has not been verified yet",
+                    address
+                );
+                return;
+            }
+            *allowance -= payload.len();
+        }
+
         if let Err(err) = self.socket.send_packet(address, payload) {
             error!("Error occured sending a packet (to {}): {}", address, err)
         }
@@ -76,14 +110,26 @@ pub struct ConnectionManager<TSocket: DatagramSocket, TConnection: Connection> {
     event_receiver: Receiver<TConnection::ReceiveEvent>,
     user_event_sender: Sender<TConnection::SendEvent>,
     max_unestablished_connections: u16,
+    acceptor: TConnection::Acceptor,
+    command_receiver: Receiver<SocketCommand>,
+    command_sender: Sender<SocketCommand>,
+    address_filter: AddressFilter,
+    // The number of datagrams that were dropped because of `max_unestablished_connections`.
+    unestablished_limit_drops: u64,
 }
 
 impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket, TConnection> {
     /// Creates an instance of `ConnectionManager` by passing a socket and config.
-    pub fn new(socket: TSocket, config: Config) -> Self {
+    ///
+    /// Enables the handshake if `Config::encryption_enabled` or `Config::connect_token_key` is set,
+    /// as the handshake establishes the keys and verifies the connect tokens.
+    pub fn new(socket: TSocket, mut config: Config) -> Self {
+        config.handshake_enabled |= config.encryption_enabled || config.connect_token_key.is_some();
         let (event_sender, event_receiver) = unbounded();
         let (user_event_sender, user_event_receiver) = unbounded();
+        let (command_sender, command_receiver) = unbounded();
         let max_unestablished_connections = config.max_unestablished_connections;
+        let address_filter = AddressFilter::new(&config);
 
         ConnectionManager {
             receive_buffer: vec![0; config.receive_buffer_max_size],
@@ -93,6 +139,11 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
             user_event_sender,
             event_receiver,
             max_unestablished_connections,
+            acceptor: Default::default(),
+            command_receiver,
+            command_sender,
+            address_filter,
+            unestablished_limit_drops: 0,
         }
     }
 
@@ -103,6 +154,25 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
         let mut unestablished_connections = self.unestablished_connection_count();
 
         let messenger = &mut self.messenger;
+        messenger.socket.poll(time);
+
+        // the bans and allowed addresses apply to the datagrams received by this poll already,
+        // disconnects are executed after the packets that were sent before
+        let mut disconnects = Vec::new();
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                SocketCommand::Disconnect(address, reason) => disconnects.push((address, reason)),
+                SocketCommand::Ban(address, duration) => {
+                    self.address_filter.ban(address, duration, time)
+                }
+                SocketCommand::Unban(address) => self.address_filter.unban(address),
+                SocketCommand::Allow(address) => self.address_filter.allow(address),
+                SocketCommand::RemoveAllowed(address) => {
+                    self.address_filter.remove_allowed(address)
+                }
+            }
+        }
+        self.address_filter.update(time);
 
         // first we pull all newly arrived packets and handle them
         loop {
@@ -111,21 +181,59 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
                 .receive_packet(self.receive_buffer.as_mut())
             {
                 Ok((payload, address)) => {
-                    if let Some(conn) = self.connections.get_mut(&address) {
+                    let is_connected = self.connections.contains_key(&address);
+                    if !self.address_filter.accept(address.ip(), is_connected, time) {
+                        // the datagram is dropped before it reaches a connection.
+                    } else if let Some(conn) = self.connections.get_mut(&address) {
+                        if messenger.send_allowances.contains_key(&address) {
+                            messenger.grant_allowance(address, payload.len());
+                        }
+
                         let was_est = conn.is_established();
                         conn.process_packet(messenger, payload, time);
                         if !was_est && conn.is_established() {
                             unestablished_connections -= 1;
                         }
+                        if conn.is_verified() {
+                            messenger.send_allowances.remove(&address);
+                        }
                     } else {
-                        let mut conn = TConnection::create_connection(messenger, address, time);
-                        conn.process_packet(messenger, payload, time);
+                        // Addresses we did not send to first may be spoofed, with the handshake we
+                        // only answer them with a limited number of bytes until they complete it.
+                        if messenger.config.handshake_enabled {
+                            messenger.grant_allowance(address, payload.len());
+                        }
 
-                        // We only allow a maximum amount number of unestablished connections to bet created
-                        // from inbound packets to prevent packet flooding from allocating unbounded memory.
-                        if unestablished_connections < self.max_unestablished_connections as usize {
-                            self.connections.insert(address, conn);
-                            unestablished_connections += 1;
+                        if TConnection::should_accept(
+                            &mut self.acceptor,
+                            messenger,
+                            address,
+                            payload,
+                            time,
+                        ) {
+                            let mut conn = TConnection::create_connection(messenger, address, time);
+                            conn.process_packet(messenger, payload, time);
+
+                            // We only allow a maximum amount number of unestablished connections to bet created
+                            // from inbound packets to prevent packet flooding from allocating unbounded memory.
+                            if conn.is_established() {
+                                self.connections.insert(address, conn);
+                            } else if unestablished_connections
+                                < self.max_unestablished_connections as usize
+                            {
+                                self.connections.insert(address, conn);
+                                unestablished_connections += 1;
+                            } else {
+                                self.unestablished_limit_drops += 1;
+                            }
+                        }
+
+                        if self
+                            .connections
+                            .get(&address)
+                            .is_none_or(|conn| conn.is_verified())
+                        {
+                            messenger.send_allowances.remove(&address);
                         }
                     }
                 }
@@ -149,10 +257,12 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
                 TConnection::create_connection(messenger, event.address(), time)
             });
 
-            let was_est = conn.is_established();
             conn.process_event(messenger, event, time);
-            if !was_est && conn.is_established() {
-                unestablished_connections -= 1;
+        }
+
+        for (address, reason) in disconnects {
+            if let Some(conn) = self.connections.get_mut(&address) {
+                conn.disconnect(messenger, reason, time);
             }
         }
 
@@ -164,6 +274,58 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
         // iterate through all connections and remove those that should be dropped
         self.connections
             .retain(|_, conn| !conn.should_drop(messenger, time));
+        let connections = &self.connections;
+        messenger
+            .send_allowances
+            .retain(|address, _| connections.contains_key(address));
+    }
+
+    /// Gracefully closes the connection to the given address during the next `manual_poll`.
+    /// See [`Connection::disconnect`] for further details.
+    pub fn disconnect(&self, address: SocketAddr, reason: DisconnectReason) {
+        self.command_sender
+            .send(SocketCommand::Disconnect(address, reason))
+            .expect("Receiver must exists.");
+    }
+
+    /// Drops all datagrams received from the given IP address for the given duration, starting
+    /// with the next `manual_poll`. A connection to the address is not closed, but times out
+    /// unless the ban is lifted before. Pass `Duration::MAX` to ban the address permanently.
+    pub fn ban(&self, address: IpAddr, duration: Duration) {
+        self.command_sender
+            .send(SocketCommand::Ban(address, duration))
+            .expect("Receiver must exists.");
+    }
+
+    /// Lifts the ban of the given IP address during the next `manual_poll`.
+    pub fn unban(&self, address: IpAddr) {
+        self.command_sender
+            .send(SocketCommand::Unban(address))
+            .expect("Receiver must exists.");
+    }
+
+    /// Exempts the given IP address from `Config::address_rate_limit` and
+    /// `Config::subnet_rate_limit` during the next `manual_poll`, e.g. because it is a trusted
+    /// relay many clients connect through.
+    pub fn allow(&self, address: IpAddr) {
+        self.command_sender
+            .send(SocketCommand::Allow(address))
+            .expect("Receiver must exists.");
+    }
+
+    /// Subjects the given IP address to the rate limits again, see [`ConnectionManager::allow`].
+    pub fn remove_allowed(&self, address: IpAddr) {
+        self.command_sender
+            .send(SocketCommand::RemoveAllowed(address))
+            .expect("Receiver must exists.");
+    }
+
+    /// Returns the number of received datagrams that were dropped before they reached a connection.
+    pub fn dropped_datagrams(&self) -> DroppedDatagrams {
+        DroppedDatagrams {
+            unestablished_limit: self.unestablished_limit_drops,
+            ..self.address_filter.dropped_datagrams()
+        }
     }
 
     /// Returns a handle to the event sender which provides a thread-safe way to enqueue user events
@@ -173,6 +335,13 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
         &self.user_event_sender
     }
 
+    /// Returns a handle to the command sender which provides a thread-safe way to enqueue commands
+    /// like disconnects and bans. Disconnects are executed after the user events of the same
+    /// `manual_poll`, the other commands before its datagrams are received.
+    pub fn command_sender(&self) -> &Sender<SocketCommand> {
+        &self.command_sender
+    }
+
     /// Returns a handle to the event receiver which provides a thread-safe way to retrieve events
     /// from the connections. This should be used when the socket is busy running its polling loop in
     /// a separate thread.
@@ -198,8 +367,13 @@ impl<TSocket: DatagramSocket, TConnection: Connection> ConnectionManager<TSocket
         &mut self.messenger.socket
     }
 
+    /// Returns the connection to the given address, if there is one.
+    pub fn connection(&self, address: &SocketAddr) -> Option<&TConnection> {
+        self.connections.get(address)
+    }
+
     /// Returns a number of active connections.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "simulator"))]
     pub fn connections_count(&self) -> usize {
         self.connections.len()
     }
@@ -210,12 +384,24 @@ mod tests {
     use std::{
         collections::HashSet,
         net::{SocketAddr, SocketAddrV4},
-        time::{Duration, Instant},
+        time::{Duration, Instant, SystemTime},
     };
 
-    use crate::net::LinkConditioner;
+    use quickcheck::TestResult;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64Mcg;
+
+    use crate::net::constants::STANDARD_HEADER_SIZE;
+    use crate::net::handshake::MIN_CONNECTION_REQUEST_SIZE;
+    use crate::net::{BandwidthLimit, DatagramSocket, LinkConditioner, LossModel, NetworkQuality};
+    use crate::packet::{
+        DeliveryGuarantee, OrderingGuarantee, OutgoingPacketBuilder, PacketReader, PacketType,
+    };
     use crate::test_utils::*;
-    use crate::{Config, Packet, SocketEvent};
+    use crate::{
+        Config, CongestionControl, ConnectToken, DisconnectReason, Packet, RateLimit,
+        SocketCommand, SocketEvent,
+    };
 
     /// The socket address of where the server is located.
     const SERVER_ADDR: &str = "127.0.0.1:10001";
@@ -235,9 +421,15 @@ mod tests {
     }
 
     fn create_server_client_network() -> (FakeSocket, FakeSocket, NetworkEmulator) {
+        create_server_client_network_with(Config::default())
+    }
+
+    fn create_server_client_network_with(
+        config: Config,
+    ) -> (FakeSocket, FakeSocket, NetworkEmulator) {
         let network = NetworkEmulator::default();
-        let server = FakeSocket::bind(&network, server_address(), Config::default()).unwrap();
-        let client = FakeSocket::bind(&network, client_address(), Config::default()).unwrap();
+        let server = FakeSocket::bind(&network, server_address(), config.clone()).unwrap();
+        let client = FakeSocket::bind(&network, client_address(), config).unwrap();
         (server, client, network)
     }
 
@@ -361,6 +553,84 @@ mod tests {
 
         // the server only adds to its table after having sent explicitly
         assert_eq![2, server.connection_count()];
+        assert_eq!(server.dropped_datagrams().unestablished_limit, 1);
+    }
+
+    #[test]
+    fn rate_limits_apply_before_a_connection_is_created() {
+        let time = Instant::now();
+        let network = NetworkEmulator::default();
+        let config = Config {
+            address_rate_limit: Some(RateLimit {
+                datagrams_per_second: 1,
+                burst_size: 2,
+            }),
+            ..Default::default()
+        };
+        let mut server = FakeSocket::bind(&network, server_address(), config).unwrap();
+        // the clients share the same IP address
+        let mut clients: Vec<_> = (0..3)
+            .map(|i| FakeSocket::bind(&network, client_address_n(i), Config::default()).unwrap())
+            .collect();
+
+        for client in clients.iter_mut() {
+            client
+                .send(Packet::unreliable(server_address(), vec![1]))
+                .unwrap();
+            client.manual_poll(time);
+        }
+        server.manual_poll(time);
+        assert_eq!(server.connection_count(), 2);
+        assert_eq!(server.dropped_datagrams().address_rate_limited, 1);
+
+        // addresses with a connection are not limited, allowed addresses are not limited at all
+        server.allow(client_address().ip());
+        for client in clients.iter_mut() {
+            client
+                .send(Packet::unreliable(server_address(), vec![2]))
+                .unwrap();
+            client.manual_poll(time);
+        }
+        server.manual_poll(time);
+        assert_eq!(server.connection_count(), 3);
+        assert_eq!(server.dropped_datagrams().address_rate_limited, 1);
+    }
+
+    #[test]
+    fn datagrams_from_banned_addresses_are_dropped() {
+        let (mut server, mut client, _) = create_server_client_network();
+        let time = Instant::now();
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert!(matches!(server.recv(), Some(SocketEvent::Packet(_))));
+
+        server.ban(client_address().ip(), Duration::from_secs(10));
+        client
+            .send(Packet::unreliable(server_address(), vec![2]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), None);
+        assert_eq!(server.dropped_datagrams().banned, 1);
+
+        // the ban expires
+        let time = time + Duration::from_secs(10);
+        client
+            .send(Packet::unreliable(server_address(), vec![3]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::unreliable(
+                client_address(),
+                vec![3]
+            )))
+        );
     }
 
     #[test]
@@ -462,13 +732,15 @@ mod tests {
 
         while let Some(message) = server.recv() {
             match message {
-                SocketEvent::Connect(_) => {}
+                SocketEvent::Connect(_)
+                | SocketEvent::QualityChanged(..)
+                | SocketEvent::Acked(..) => {}
                 SocketEvent::Packet(packet) => {
                     let byte = packet.payload()[0];
                     assert![!seen.contains(&byte)];
                     seen.insert(byte);
                 }
-                SocketEvent::Timeout(_) | SocketEvent::Disconnect(_) => {
+                SocketEvent::Timeout(_) | SocketEvent::Disconnect(..) | SocketEvent::Lost(..) => {
                     panic!["This should not happen, as we've not advanced time"];
                 }
             }
@@ -502,11 +774,13 @@ mod tests {
         let mut cnt = 0;
         while let Some(message) = server.recv() {
             match message {
-                SocketEvent::Connect(_) => {}
+                SocketEvent::Connect(_)
+                | SocketEvent::QualityChanged(..)
+                | SocketEvent::Acked(..) => {}
                 SocketEvent::Packet(_) => {
                     cnt += 1;
                 }
-                SocketEvent::Timeout(_) | SocketEvent::Disconnect(_) => {
+                SocketEvent::Timeout(_) | SocketEvent::Disconnect(..) | SocketEvent::Lost(..) => {
                     panic!["This should not happen, as we've not advanced time"];
                 }
             }
@@ -515,6 +789,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::never_loop)]
     fn sequenced_packets_pathological_case() {
         let config = Config {
             max_packets_in_flight: 100,
@@ -678,7 +953,7 @@ mod tests {
         );
         assert_eq!(
             server.recv().unwrap(),
-            SocketEvent::Disconnect(client_address())
+            SocketEvent::Disconnect(client_address(), DisconnectReason::Timeout)
         );
         assert_eq!(
             client.recv().unwrap(),
@@ -686,7 +961,7 @@ mod tests {
         );
         assert_eq!(
             client.recv().unwrap(),
-            SocketEvent::Disconnect(server_address())
+            SocketEvent::Disconnect(server_address(), DisconnectReason::Timeout)
         );
     }
 
@@ -870,10 +1145,14 @@ mod tests {
                         SocketEvent::Packet(pkt) => {
                             set.insert(pkt.payload()[0]);
                         }
-                        SocketEvent::Timeout(_) | SocketEvent::Disconnect(_) => {
+                        SocketEvent::Timeout(_)
+                        | SocketEvent::Disconnect(..)
+                        | SocketEvent::Lost(..) => {
                             panic!["Unable to time out, time has not advanced"]
                         }
-                        SocketEvent::Connect(_) => {}
+                        SocketEvent::Connect(_)
+                        | SocketEvent::QualityChanged(..)
+                        | SocketEvent::Acked(..) => {}
                     }
                 }
             }
@@ -967,7 +1246,9 @@ mod tests {
             // `manual_poll` if and only if the socket is in blocking mode.
             //
             // If that functionality is changed, we will receive something unexpected here
-            match server.recv() {
+            let event = std::iter::from_fn(|| server.recv())
+                .find(|event| !matches!(event, SocketEvent::Acked(..)));
+            match event {
                 Some(SocketEvent::Packet(pkt)) => {
                     assert_eq![dummy, pkt.payload()];
                 }
@@ -978,18 +1259,1592 @@ mod tests {
         }
     }
 
-    #[quickcheck_macros::quickcheck]
-    fn do_not_panic_on_arbitrary_packets(bytes: Vec<u8>) {
-        use crate::net::DatagramSocket;
-        let network = NetworkEmulator::default();
-        let mut server = FakeSocket::bind(&network, server_address(), Config::default()).unwrap();
-        let mut client_socket = network.new_socket(client_address()).unwrap();
+    // Establishes a connection between the server and client.
+    fn connect_server_client(server: &mut FakeSocket, client: &mut FakeSocket, time: Instant) {
+        client
+            .send(Packet::unreliable(server_address(), vec![0]))
+            .unwrap();
+        client.manual_poll(time);
+        server
+            .send(Packet::unreliable(client_address(), vec![0]))
+            .unwrap();
+        server.manual_poll(time);
+        client.manual_poll(time);
 
-        client_socket
-            .send_packet(&server_address(), &bytes)
+        while server.recv().is_some() {}
+        while client.recv().is_some() {}
+    }
+
+    #[test]
+    fn disconnect_notifies_both_sides() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        client.disconnect(server_address(), DisconnectReason::Requested);
+        client.manual_poll(time);
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Disconnect(
+                server_address(),
+                DisconnectReason::Requested
+            ))
+        );
+        assert_eq!(client.recv(), None);
+        assert_eq!(client.connection_count(), 0);
+
+        // the redundant disconnect packets only notify the server once
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Disconnect(
+                client_address(),
+                DisconnectReason::Requested
+            ))
+        );
+        assert_eq!(server.recv(), None);
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn disconnect_through_command_sender() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        client
+            .get_command_sender()
+            .send(SocketCommand::Disconnect(
+                server_address(),
+                DisconnectReason::Custom(3),
+            ))
+            .unwrap();
+        client.manual_poll(time);
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Disconnect(
+                server_address(),
+                DisconnectReason::Custom(3)
+            ))
+        );
+
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Disconnect(
+                client_address(),
+                DisconnectReason::Custom(3)
+            ))
+        );
+    }
+
+    #[test]
+    fn disconnect_of_unestablished_connection_is_ignored() {
+        let (mut server, _client, network) = create_server_client_network();
+        let time = Instant::now();
+
+        network.spoof_packet(
+            client_address(),
+            server_address(),
+            control_packet(
+                PacketType::Disconnect,
+                &DisconnectReason::Requested.to_bytes(),
+            )
+            .to_vec(),
+        );
+        server.manual_poll(time);
+
+        assert_eq!(server.recv(), None);
+        assert_eq!(server.connection_count(), 1);
+    }
+
+    #[test]
+    fn disconnect_flushes_reliable_packets() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        let flushed = client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.disconnect(server_address(), DisconnectReason::Custom(7));
+        client.manual_poll(time);
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Disconnect(
+                server_address(),
+                DisconnectReason::Custom(7)
+            ))
+        );
+
+        // the reliable packet is delivered, but the disconnect is held back until it is acked
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![1]
+            )))
+        );
+        assert_eq!(server.recv(), None);
+
+        // packets sent after the disconnect are not delivered anymore
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![2]))
+            .unwrap();
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![3]))
+            .unwrap();
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Acked(server_address(), flushed))
+        );
+        assert_eq!(client.recv(), None);
+        assert_eq!(client.connection_count(), 0);
+
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Disconnect(
+                client_address(),
+                DisconnectReason::Custom(7)
+            ))
+        );
+        assert_eq!(server.recv(), None);
+    }
+
+    #[test]
+    fn disconnect_flush_has_deadline() {
+        let config = Config::default();
+        let (mut server, mut client) = create_server_client(config.clone());
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.disconnect(server_address(), DisconnectReason::Requested);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        while server.recv().is_some() {}
+
+        // the server never acknowledges the packet, so it is resent until the deadline
+        client.manual_poll(time + config.disconnect_flush_timeout - Duration::from_millis(1));
+        assert_eq!(client.connection_count(), 1);
+        server.manual_poll(time + config.disconnect_flush_timeout - Duration::from_millis(1));
+        while server.recv().is_some() {}
+
+        client.manual_poll(time + config.disconnect_flush_timeout);
+        assert_eq!(client.connection_count(), 0);
+
+        server.manual_poll(time + config.disconnect_flush_timeout);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Disconnect(
+                client_address(),
+                DisconnectReason::Requested
+            ))
+        );
+    }
+
+    fn handshake_config() -> Config {
+        Config {
+            handshake_enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn control_packet(packet_type: PacketType, payload: &[u8]) -> Box<[u8]> {
+        OutgoingPacketBuilder::new(payload)
+            .with_default_header(
+                packet_type,
+                DeliveryGuarantee::Unreliable,
+                OrderingGuarantee::None,
+            )
+            .build()
+            .contents()
+    }
+
+    // Builds a connection request that is padded like the requests sent by a client.
+    fn connection_request(salt: u64) -> Box<[u8]> {
+        let mut payload = salt.to_be_bytes().to_vec();
+        payload.resize(
+            MIN_CONNECTION_REQUEST_SIZE - STANDARD_HEADER_SIZE as usize,
+            0,
+        );
+        control_packet(PacketType::ConnectionRequest, &payload)
+    }
+
+    // Receives a single packet from an emulated socket and returns its type and payload.
+    fn receive_control_packet(socket: &mut EmulatedSocket) -> Option<(PacketType, Box<[u8]>)> {
+        let mut buffer = [0; 1500];
+        let (packet, _) = socket.receive_packet(&mut buffer).ok()?;
+        let mut reader = PacketReader::new(packet);
+        let header = reader.read_standard_header().ok()?;
+        Some((header.packet_type(), reader.read_payload()))
+    }
+
+    #[test]
+    fn lost_packet_is_resent_after_retransmission_timeout() {
+        let config = Config::default();
+        let (mut server, mut client, network) = create_server_client_network_with(config.clone());
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        // a single reliable packet is lost, no other traffic follows
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
             .unwrap();
+        client.manual_poll(time);
+        network.clear_packets(server_address());
+
+        let timeout = config.initial_retransmission_timeout;
+        client.manual_poll(time + timeout - Duration::from_millis(1));
+        server.manual_poll(time + timeout - Duration::from_millis(1));
+        assert_eq!(server.recv(), None);
 
+        client.manual_poll(time + timeout);
+        server.manual_poll(time + timeout);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![1]
+            )))
+        );
+    }
+
+    #[test]
+    fn only_lost_fragments_are_resent() {
+        let config = Config::default();
+        let (mut server, mut client, network) = create_server_client_network_with(config.clone());
         let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        let acked_events = |client: &mut FakeSocket| {
+            std::iter::from_fn(|| client.recv())
+                .filter(|event| matches!(event, SocketEvent::Acked(..)))
+                .collect::<Vec<_>>()
+        };
+
+        // one of the four fragments of the message is lost
+        let message = client
+            .send(Packet::reliable_unordered(server_address(), vec![7; 4000]))
+            .unwrap();
+        client.manual_poll(time);
+        assert_eq!(network.packet_count(server_address()), 4);
+        network.drop_packet(server_address(), 2).unwrap();
+        server.manual_poll(time);
+        assert_eq!(server.recv(), None);
+
+        // the other fragments are acknowledged, but the message is not yet
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![0]))
+            .unwrap();
         server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(acked_events(&mut client), vec![]);
+
+        let timeout = config.initial_retransmission_timeout;
+        client.manual_poll(time + timeout);
+        assert_eq!(network.packet_count(server_address()), 1);
+        server.manual_poll(time + timeout);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![7; 4000]
+            )))
+        );
+
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![0]))
+            .unwrap();
+        server.manual_poll(time + timeout);
+        client.manual_poll(time + timeout);
+        assert_eq!(
+            acked_events(&mut client),
+            vec![SocketEvent::Acked(server_address(), message)]
+        );
+    }
+
+    #[test]
+    fn connection_stats_measure_round_trip_time() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+        let rtt = Duration::from_millis(60);
+
+        assert_eq!(client.connection_stats(server_address()), None);
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time + rtt / 2);
+
+        // the reply acknowledges the packet of the client
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![2]))
+            .unwrap();
+        server.manual_poll(time + rtt / 2);
+        client.manual_poll(time + rtt);
+
+        let stats = client.connection_stats(server_address()).unwrap();
+        assert_eq!(stats.rtt, Some(rtt));
+        assert_eq!(stats.jitter, rtt / 2);
+        assert_eq!(stats.packet_loss, 0.);
+        assert_eq!(stats.packets_in_flight, 0);
+
+        // the server did not receive an acknowledgment yet
+        let stats = server.connection_stats(client_address()).unwrap();
+        assert_eq!(stats.rtt, None);
+        assert_eq!(stats.packets_in_flight, 1);
+    }
+
+    #[test]
+    fn quality_changed_event_on_high_rtt() {
+        let config = Config::default();
+        let (mut server, mut client) = create_server_client(config.clone());
+        let mut time = Instant::now();
+
+        // exchange a reliable packet with each other, which takes `rtt` to get acknowledged.
+        let mut round_trip = |rtt: Duration, time: &mut Instant| {
+            client
+                .send(Packet::reliable_unordered(server_address(), vec![1]))
+                .unwrap();
+            client.manual_poll(*time);
+            server.manual_poll(*time + rtt / 2);
+            server
+                .send(Packet::reliable_unordered(client_address(), vec![2]))
+                .unwrap();
+            server.manual_poll(*time + rtt / 2);
+            *time += rtt;
+            client.manual_poll(*time);
+            while server.recv().is_some() {}
+
+            let mut quality_changes = Vec::new();
+            while let Some(event) = client.recv() {
+                if let SocketEvent::QualityChanged(addr, quality) = event {
+                    assert_eq!(addr, server_address());
+                    quality_changes.push(quality);
+                }
+            }
+            quality_changes
+        };
+
+        // the quality only degrades after the round trip time stayed high for a while.
+        assert_eq!(round_trip(Duration::from_millis(300), &mut time), vec![]);
+        let degraded = time + config.network_quality_degradation_time;
+        let quality_changes = (0..10)
+            .map(|_| round_trip(Duration::from_millis(300), &mut time))
+            .find(|quality_changes| !quality_changes.is_empty());
+        assert_eq!(quality_changes, Some(vec![NetworkQuality::Bad]));
+        assert!(time >= degraded);
+
+        // the quality only recovers after the round trip time stayed low for a while.
+        let recovered = time + config.network_quality_recovery_time;
+        let quality_changes = (0..100)
+            .map(|_| round_trip(Duration::from_millis(20), &mut time))
+            .find(|quality_changes| !quality_changes.is_empty());
+        assert_eq!(quality_changes, Some(vec![NetworkQuality::Good]));
+        assert!(time >= recovered);
+    }
+
+    #[test]
+    fn congestion_control_paces_user_packets() {
+        let (mut server, mut client) = create_server_client(Config {
+            congestion_control: CongestionControl::GoodBad,
+            max_send_rate: 100,
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        for i in 0..20 {
+            client
+                .send(Packet::unreliable(server_address(), vec![i]))
+                .unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut receive = |client: &mut FakeSocket, time: Instant| {
+            client.manual_poll(time);
+            server.manual_poll(time);
+            while let Some(event) = server.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    received.push(packet.payload()[0]);
+                }
+            }
+            received.len()
+        };
+
+        // a burst of 50ms worth of packets is sent right away, then one packet every 10ms.
+        assert_eq!(receive(&mut client, time), 5);
+        assert_eq!(receive(&mut client, time + Duration::from_millis(5)), 5);
+        assert_eq!(receive(&mut client, time + Duration::from_millis(100)), 10);
+        assert_eq!(receive(&mut client, time + Duration::from_millis(110)), 11);
+        assert_eq!(receive(&mut client, time + Duration::from_millis(200)), 16);
+        assert_eq!(receive(&mut client, time + Duration::from_millis(250)), 20);
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn congestion_control_queue_is_bounded() {
+        let (mut server, mut client) = create_server_client(Config {
+            congestion_control: CongestionControl::GoodBad,
+            min_send_rate: 0,
+            max_send_rate: 0,
+            max_paced_packets: 10,
+            idle_connection_timeout: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        for i in 0..100 {
+            client
+                .send(Packet::unreliable(server_address(), vec![i]))
+                .unwrap();
+        }
+
+        // a send rate of zero still sends a packet every second, only the queued packets arrive.
+        let mut received = Vec::new();
+        for i in 0..20 {
+            let time = time + Duration::from_secs(i);
+            client.manual_poll(time);
+            server.manual_poll(time);
+            while let Some(event) = server.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    received.push(packet.payload()[0]);
+                }
+            }
+        }
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn acked_and_lost_events_for_sent_messages() {
+        let (mut server, mut client, network) = create_server_client_network_with(Config {
+            ack_unreliable_packets: true,
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        let delivery_events = |client: &mut FakeSocket| {
+            std::iter::from_fn(|| client.recv())
+                .filter(|event| matches!(event, SocketEvent::Acked(..) | SocketEvent::Lost(..)))
+                .collect::<Vec<_>>()
+        };
+
+        let reliable = client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        let unreliable = client
+            .send(Packet::unreliable(server_address(), vec![2]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+
+        // the acknowledgment is carried by the answer of the server.
+        server
+            .send(Packet::unreliable(client_address(), vec![3]))
+            .unwrap();
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(
+            delivery_events(&mut client),
+            vec![
+                SocketEvent::Acked(server_address(), reliable),
+                SocketEvent::Acked(server_address(), unreliable),
+            ]
+        );
+
+        // an unreliable packet that never arrives is reported lost after the retransmission timeout.
+        let lost = client
+            .send(Packet::unreliable(server_address(), vec![4]))
+            .unwrap();
+        client.manual_poll(time);
+        network.clear_packets(server_address());
+        let timeout = client
+            .connection_stats(server_address())
+            .unwrap()
+            .retransmission_timeout;
+        client.manual_poll(time + timeout);
+        assert_eq!(
+            delivery_events(&mut client),
+            vec![SocketEvent::Lost(server_address(), lost)]
+        );
+    }
+
+    #[test]
+    fn unreliable_packets_and_heartbeats_carry_acknowledgments() {
+        let config = Config {
+            ack_unreliable_packets: true,
+            heartbeat_interval: Some(Duration::from_millis(40)),
+            ..Default::default()
+        };
+        let (mut server, mut client) = create_server_client(config);
+        let time = Instant::now();
+        let rtt = Duration::from_millis(40);
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+
+        // the unreliable reply acknowledges the packet of the client
+        let reliable = server
+            .send(Packet::reliable_unordered(client_address(), vec![2]))
+            .unwrap();
+        server.manual_poll(time + rtt / 2);
+        client.manual_poll(time + rtt / 2);
+        assert_eq!(
+            client.connection_stats(server_address()).unwrap().rtt,
+            Some(rtt / 2)
+        );
+
+        // the client only sends a heartbeat, which acknowledges the reliable packet of the server
+        client.manual_poll(time + rtt);
+        server.manual_poll(time + rtt);
+        assert!(std::iter::from_fn(|| server.recv())
+            .any(|event| event == SocketEvent::Acked(client_address(), reliable)));
+
+        let stats = server.connection_stats(client_address()).unwrap();
+        assert_eq!(stats.rtt, Some(rtt / 2));
+        assert_eq!(stats.packets_in_flight, 0);
+    }
+
+    #[test]
+    fn packet_sender_assigns_message_ids() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+
+        let sent = client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        let sent_with_sender = client
+            .get_packet_sender()
+            .send(Packet::reliable_unordered(server_address(), vec![2]))
+            .unwrap();
+        assert_ne!(sent, sent_with_sender);
+        client.manual_poll(time);
+        server.manual_poll(time);
+
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![3]))
+            .unwrap();
+        server.manual_poll(time);
+        client.manual_poll(time);
+
+        let acked: Vec<_> = std::iter::from_fn(|| client.recv())
+            .filter_map(|event| match event {
+                SocketEvent::Acked(_, message_id) => Some(message_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(acked.len(), 2);
+        assert!(acked.contains(&sent) && acked.contains(&sent_with_sender));
+    }
+
+    #[test]
+    fn lost_unreliable_packets_count_towards_packet_loss() {
+        let (mut server, mut client, network) = create_server_client_network_with(Config {
+            ack_unreliable_packets: true,
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        let message_id = client
+            .get_packet_sender()
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        network.clear_packets(server_address());
+
+        let timeout = client
+            .connection_stats(server_address())
+            .unwrap()
+            .retransmission_timeout;
+        client.manual_poll(time + timeout);
+        server.manual_poll(time + timeout);
+
+        assert!(std::iter::from_fn(|| client.recv())
+            .any(|event| event == SocketEvent::Lost(server_address(), message_id)));
+        assert!(
+            client
+                .connection_stats(server_address())
+                .unwrap()
+                .packet_loss
+                > 0.
+        );
+    }
+
+    #[test]
+    fn ack_packet_is_sent_without_outgoing_traffic() {
+        let config = Config::default();
+        let (mut server, mut client) = create_server_client(config.clone());
+        let time = Instant::now();
+        let ack_delay = config.ack_delay.unwrap();
+
+        let reliable = client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert!(server.recv().is_some());
+
+        // the server waits for an outgoing packet to carry the acknowledgment
+        server.manual_poll(time + ack_delay - Duration::from_millis(1));
+        client.manual_poll(time + ack_delay - Duration::from_millis(1));
+        assert_eq!(
+            client
+                .connection_stats(server_address())
+                .unwrap()
+                .packets_in_flight,
+            1
+        );
+
+        server.manual_poll(time + ack_delay);
+        client.manual_poll(time + ack_delay);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Acked(server_address(), reliable))
+        );
+        let stats = client.connection_stats(server_address()).unwrap();
+        assert_eq!(stats.packets_in_flight, 0);
+        assert_eq!(stats.rtt, Some(ack_delay));
+
+        // nothing is left to acknowledge
+        server.manual_poll(time + ack_delay * 2);
+        client.manual_poll(time + ack_delay * 2);
+        assert_eq!(client.recv(), None);
+    }
+
+    #[test]
+    fn ack_packets_can_be_disabled() {
+        let (mut server, mut client) = create_server_client(Config {
+            ack_delay: None,
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+
+        server.manual_poll(time + Duration::from_millis(100));
+        client.manual_poll(time + Duration::from_millis(100));
+        assert_eq!(
+            client
+                .connection_stats(server_address())
+                .unwrap()
+                .packets_in_flight,
+            1
+        );
+    }
+
+    #[test]
+    fn packets_of_a_poll_are_aggregated() {
+        let (mut server, mut client, network) = create_server_client_network_with(Config {
+            aggregate_packets: true,
+            ..Default::default()
+        });
+        let time = Instant::now();
+
+        client
+            .send(Packet::unreliable(server_address(), vec![0]))
+            .unwrap();
+        client
+            .send(Packet::reliable_ordered(server_address(), vec![1], None))
+            .unwrap();
+        client
+            .send(Packet::unreliable_sequenced(
+                server_address(),
+                vec![2],
+                None,
+            ))
+            .unwrap();
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![3; 2000]))
+            .unwrap();
+        client.manual_poll(time);
+
+        // the fragments of the large packet fill up the first datagram
+        assert_eq!(network.packet_count(server_address()), 2);
+
+        server.manual_poll(time);
+        let payloads: Vec<_> = std::iter::from_fn(|| server.recv())
+            .filter_map(|event| match event {
+                SocketEvent::Packet(packet) => Some(packet.payload().to_vec()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(payloads, vec![vec![0], vec![1], vec![2], vec![3; 2000]]);
+    }
+
+    #[test]
+    fn link_conditioner_delays_packets() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let time = Instant::now();
+        let latency = Duration::from_millis(50);
+
+        client.set_link_conditioner(Some({
+            let mut lc = LinkConditioner::new();
+            lc.set_latency(latency);
+            lc
+        }));
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), None);
+
+        // the packet is released by the poll of the client once the latency has passed
+        client.manual_poll(time + latency - Duration::from_millis(1));
+        server.manual_poll(time + latency - Duration::from_millis(1));
+        assert_eq!(server.recv(), None);
+
+        client.manual_poll(time + latency);
+        server.manual_poll(time + latency);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::unreliable(
+                client_address(),
+                vec![1]
+            )))
+        );
+    }
+
+    #[test]
+    fn reliable_packets_survive_loss_burst_longer_than_redundant_acks() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let mut time = Instant::now();
+
+        // the server loses 40 packets in a row, which acknowledge more packets than the
+        // acknowledgment bitfield of the packets after the burst covers, followed by a short burst.
+        server.set_link_conditioner(Some({
+            let mut lc = LinkConditioner::new();
+            lc.set_loss_model(LossModel::Pattern(vec![5..45, 60..70]));
+            lc
+        }));
+
+        let mut received = Vec::new();
+        for i in 0..300 {
+            if i < 100 {
+                client
+                    .send(Packet::reliable_ordered(
+                        server_address(),
+                        vec![i as u8],
+                        None,
+                    ))
+                    .unwrap();
+            }
+            client.manual_poll(time);
+            server.manual_poll(time);
+            while let Some(event) = server.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    received.push(packet.payload()[0]);
+                }
+            }
+            time += Duration::from_millis(10);
+        }
+
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+        assert_eq!(
+            client
+                .connection_stats(server_address())
+                .unwrap()
+                .packets_in_flight,
+            0
+        );
+    }
+
+    #[test]
+    fn reliable_packets_get_through_congested_uplink() {
+        let (mut server, mut client) = create_server_client(Config::default());
+        let mut time = Instant::now();
+
+        // 256 kbit/s, the client sends more than the queue of the router holds at once
+        client.set_link_conditioner(Some({
+            let mut lc = LinkConditioner::new();
+            lc.set_bandwidth_limit(Some(BandwidthLimit {
+                bits_per_second: 256_000,
+                burst_size: 1_500,
+                queue_size: 8_000,
+            }));
+            lc
+        }));
+
+        for i in 0..30 {
+            client
+                .send(Packet::reliable_unordered(
+                    server_address(),
+                    vec![i as u8; 1_000],
+                ))
+                .unwrap();
+        }
+
+        let mut received = Vec::new();
+        for i in 0..300 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+            while let Some(event) = server.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    received.push(packet.payload()[0]);
+                }
+            }
+            if i == 0 {
+                // only the burst of the token bucket leaves right away
+                assert_eq!(received.len(), 1);
+            }
+            time += Duration::from_millis(10);
+        }
+
+        received.sort_unstable();
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn handshake_establishes_connection() {
+        let (mut server, mut client) = create_server_client(handshake_config());
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(
+                server_address(),
+                b"Hello world!".to_vec(),
+            ))
+            .unwrap();
+
+        // connection request, the server answers with a challenge without tracking the client
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.connection_count(), 0);
+        assert_eq!(server.recv(), None);
+        assert_eq!(client.recv(), None);
+
+        // challenge response, the server accepts the client
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.connection_count(), 1);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        // connection accepted, the client sends the packet it held back
+        client.manual_poll(time);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                b"Hello world!".to_vec()
+            )))
+        );
+    }
+
+    #[test]
+    fn handshake_authenticates_disconnect() {
+        let network = NetworkEmulator::default();
+        let mut server = FakeSocket::bind(&network, server_address(), handshake_config()).unwrap();
+        let mut client = FakeSocket::bind(&network, client_address(), handshake_config()).unwrap();
+        let time = Instant::now();
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        for _ in 0..3 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+        }
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+        while server.recv().is_some() {}
+
+        // a disconnect packet without the handshake challenge is ignored
+        network.spoof_packet(
+            client_address(),
+            server_address(),
+            control_packet(
+                PacketType::Disconnect,
+                &DisconnectReason::Requested.to_bytes(),
+            )
+            .to_vec(),
+        );
+        server.manual_poll(time);
+        assert_eq!(server.recv(), None);
+        assert_eq!(server.connection_count(), 1);
+
+        client.disconnect(server_address(), DisconnectReason::Requested);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Disconnect(
+                client_address(),
+                DisconnectReason::Requested
+            ))
+        );
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn handshake_packets_are_not_aggregated() {
+        let config = Config {
+            aggregate_packets: true,
+            ..handshake_config()
+        };
+        let (mut server, mut client, _) = create_server_client_network_with(config);
+        let time = Instant::now();
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![2]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        client.manual_poll(time);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+    }
+
+    #[test]
+    fn handshake_does_not_acknowledge_unverified_packets() {
+        let network = NetworkEmulator::default();
+        let mut client = FakeSocket::bind(&network, client_address(), handshake_config()).unwrap();
+        let mut spoofer = network.new_socket(server_address()).unwrap();
+        let time = Instant::now();
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+
+        // a reliable packet from the remote host before it answered the connection request
+        let packet = OutgoingPacketBuilder::new(&[1, 2, 3])
+            .with_default_header(
+                PacketType::Packet,
+                DeliveryGuarantee::Reliable,
+                OrderingGuarantee::None,
+            )
+            .with_acknowledgment_header(0, 0, 0)
+            .build();
+        spoofer
+            .send_packet(&client_address(), &packet.contents())
+            .unwrap();
+        client.manual_poll(time);
+        client.manual_poll(time + handshake_config().ack_delay.unwrap());
+
+        assert_eq!(client.recv(), None);
+        while let Some((packet_type, _)) = receive_control_packet(&mut spoofer) {
+            assert_eq!(packet_type, PacketType::ConnectionRequest);
+        }
+    }
+
+    #[test]
+    fn handshake_ignores_unverified_packets() {
+        let network = NetworkEmulator::default();
+        let mut server = FakeSocket::bind(&network, server_address(), handshake_config()).unwrap();
+        let mut spoofer = network.new_socket(client_address()).unwrap();
+        let time = Instant::now();
+
+        spoofer
+            .send_packet(
+                &server_address(),
+                &control_packet(PacketType::Packet, &[1, 2, 3]),
+            )
+            .unwrap();
+        spoofer
+            .send_packet(
+                &server_address(),
+                &control_packet(PacketType::ConnectionResponse, &[0; 32]),
+            )
+            .unwrap();
+        spoofer
+            .send_packet(
+                &server_address(),
+                &control_packet(PacketType::ConnectionRequest, &[0; 8]),
+            )
+            .unwrap();
+        spoofer
+            .send_packet(&server_address(), &connection_request(0))
+            .unwrap();
+        server.manual_poll(time);
+
+        assert_eq!(server.connection_count(), 0);
+        assert_eq!(server.recv(), None);
+
+        // only the padded connection request is answered
+        let (packet_type, _) = receive_control_packet(&mut spoofer).unwrap();
+        assert_eq!(packet_type, PacketType::ConnectionChallenge);
+        assert!(receive_control_packet(&mut spoofer).is_none());
+    }
+
+    #[test]
+    fn connection_requests_are_padded() {
+        for config in [handshake_config(), encryption_config()] {
+            let (_, mut client, network) = create_server_client_network_with(config);
+
+            client
+                .send(Packet::reliable_unordered(server_address(), vec![1]))
+                .unwrap();
+            client.manual_poll(Instant::now());
+            let request = network.drop_packet(server_address(), 0).unwrap();
+            assert_eq!(request.len(), MIN_CONNECTION_REQUEST_SIZE);
+        }
+    }
+
+    #[test]
+    fn unverified_addresses_are_not_sent_more_than_they_sent() {
+        // the padded request is larger than the challenge, which is only sent if it fits
+        for (amplification_factor, is_answered) in [(0, false), (1, true)] {
+            let network = NetworkEmulator::default();
+            let config = Config {
+                amplification_factor,
+                ..handshake_config()
+            };
+            let mut server = FakeSocket::bind(&network, server_address(), config).unwrap();
+            let mut spoofer = network.new_socket(client_address()).unwrap();
+
+            spoofer
+                .send_packet(&server_address(), &connection_request(0))
+                .unwrap();
+            server.manual_poll(Instant::now());
+            assert_eq!(receive_control_packet(&mut spoofer).is_some(), is_answered);
+        }
+    }
+
+    #[test]
+    fn address_is_verified_once_the_handshake_completes() {
+        let config = Config {
+            amplification_factor: 1,
+            ..handshake_config()
+        };
+        let (mut server, mut client, _) = create_server_client_network_with(config);
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        for _ in 0..3 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+        }
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        // the answer is much larger than anything the client sent
+        server
+            .send(Packet::unreliable(client_address(), vec![2; 1000]))
+            .unwrap();
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert!(std::iter::from_fn(|| client.recv()).any(
+            |event| matches!(event, SocketEvent::Packet(packet) if packet.payload() == [2; 1000])
+        ));
+    }
+
+    #[test]
+    fn answers_are_not_limited_without_handshake() {
+        let (mut server, mut client, _) = create_server_client_network();
+        let mut time = Instant::now();
+
+        let mut delivered = 0;
+        for _ in 0..20 {
+            client
+                .send(Packet::unreliable(server_address(), vec![1; 10]))
+                .unwrap();
+            client.manual_poll(time);
+            server.manual_poll(time);
+            while let Some(event) = server.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    server
+                        .send(Packet::unreliable(packet.addr(), vec![2; 200]))
+                        .unwrap();
+                }
+            }
+            server.manual_poll(time);
+            client.manual_poll(time);
+            delivered += std::iter::from_fn(|| client.recv())
+                .filter(|event| matches!(event, SocketEvent::Packet(_)))
+                .count();
+            time += Duration::from_millis(10);
+        }
+        assert_eq!(delivered, 20);
+    }
+
+    #[test]
+    fn handshake_challenge_expires() {
+        let network = NetworkEmulator::default();
+        let mut server = FakeSocket::bind(&network, server_address(), handshake_config()).unwrap();
+        let mut client = network.new_socket(client_address()).unwrap();
+        let time = Instant::now();
+
+        client
+            .send_packet(&server_address(), &connection_request(0))
+            .unwrap();
+        server.manual_poll(time);
+        let (_, challenge) = receive_control_packet(&mut client).unwrap();
+
+        // a response after the challenge lifetime is ignored
+        client
+            .send_packet(
+                &server_address(),
+                &control_packet(PacketType::ConnectionResponse, &challenge),
+            )
+            .unwrap();
+        let expired = time + handshake_config().handshake_challenge_lifetime;
+        server.manual_poll(expired + Duration::from_millis(1));
+        assert_eq!(server.connection_count(), 0);
+        assert!(receive_control_packet(&mut client).is_none());
+
+        // a response in time is accepted
+        client
+            .send_packet(
+                &server_address(),
+                &control_packet(PacketType::ConnectionResponse, &challenge),
+            )
+            .unwrap();
+        server.manual_poll(expired);
+        assert_eq!(server.connection_count(), 1);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+        let (packet_type, _) = receive_control_packet(&mut client).unwrap();
+        assert_eq!(packet_type, PacketType::ConnectionAccepted);
+    }
+
+    #[test]
+    fn handshake_recovers_from_lost_packets() {
+        let config = handshake_config();
+        let (mut server, mut client, network) = create_server_client_network_with(config.clone());
+        let mut time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+
+        // lose the connection request
+        client.manual_poll(time);
+        network.clear_packets(server_address());
+
+        // the request is resent after the resend interval
+        time += config.handshake_resend_interval;
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        // lose the connection accepted
+        network.clear_packets(client_address());
+
+        // the response is resent and answered again, without a second connect event
+        time += config.handshake_resend_interval;
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![1]
+            )))
+        );
+        assert_eq!(server.recv(), None);
+    }
+
+    #[test]
+    fn handshake_completes_implicitly_on_data() {
+        let (mut server, mut client, network) =
+            create_server_client_network_with(handshake_config());
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        // lose the connection accepted, but the server already sends data
+        network.clear_packets(client_address());
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![2]))
+            .unwrap();
+        server.manual_poll(time);
+
+        client.manual_poll(time);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                server_address(),
+                vec![2]
+            )))
+        );
+    }
+
+    fn encryption_config() -> Config {
+        Config {
+            encryption_enabled: true,
+            encryption_key: Some([7; 32]),
+            ..handshake_config()
+        }
+    }
+
+    #[test]
+    fn encrypted_packets_cannot_be_read_or_forged() {
+        let (mut server, mut client, network) =
+            create_server_client_network_with(encryption_config());
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![0]))
+            .unwrap();
+        for _ in 0..3 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+        }
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![0]
+            )))
+        );
+
+        client
+            .send(Packet::reliable_unordered(
+                server_address(),
+                b"secret message".to_vec(),
+            ))
+            .unwrap();
+        client.manual_poll(time);
+        let datagram = network.drop_packet(server_address(), 0).unwrap();
+        assert!(!datagram.windows(6).any(|window| window == b"secret"));
+
+        // altered and unencrypted packets are dropped
+        let mut tampered = datagram.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        network.spoof_packet(client_address(), server_address(), tampered);
+        network.spoof_packet(
+            client_address(),
+            server_address(),
+            control_packet(PacketType::Packet, b"forged").to_vec(),
+        );
+        server.manual_poll(time);
+        assert_eq!(server.recv(), None);
+
+        network.spoof_packet(client_address(), server_address(), datagram);
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                b"secret message".to_vec()
+            )))
+        );
+    }
+
+    #[test]
+    fn replayed_packets_are_dropped() {
+        let (mut server, mut client, network) =
+            create_server_client_network_with(encryption_config());
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![0]))
+            .unwrap();
+        for _ in 0..3 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+        }
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+        assert!(matches!(server.recv(), Some(SocketEvent::Packet(_))));
+
+        client
+            .send(Packet::unreliable(server_address(), vec![1, 2, 3]))
+            .unwrap();
+        client.manual_poll(time);
+        let datagram = network.drop_packet(server_address(), 0).unwrap();
+
+        // an unreliable packet would be delivered every time it is received
+        for _ in 0..3 {
+            network.spoof_packet(client_address(), server_address(), datagram.clone());
+        }
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::unreliable(
+                client_address(),
+                vec![1, 2, 3]
+            )))
+        );
+        assert_eq!(server.recv(), None);
+        assert_eq!(
+            server
+                .connection_stats(client_address())
+                .unwrap()
+                .replayed_packets,
+            2
+        );
+    }
+
+    #[test]
+    fn encryption_enables_the_handshake() {
+        let config = Config {
+            handshake_enabled: false,
+            ..encryption_config()
+        };
+        let (mut server, mut client, network) = create_server_client_network_with(config);
+        let time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(
+                server_address(),
+                b"secret message".to_vec(),
+            ))
+            .unwrap();
+        client.manual_poll(time);
+        let request = network.drop_packet(server_address(), 0).unwrap();
+        assert_eq!(
+            PacketReader::new(&request)
+                .read_standard_header()
+                .unwrap()
+                .packet_type(),
+            PacketType::ConnectionRequest
+        );
+        network.spoof_packet(client_address(), server_address(), request);
+
+        for _ in 0..3 {
+            server.manual_poll(time);
+            client.manual_poll(time);
+        }
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                b"secret message".to_vec()
+            )))
+        );
+    }
+
+    #[test]
+    fn encryption_keys_match_on_simultaneous_open() {
+        let (mut server, mut client, network) =
+            create_server_client_network_with(encryption_config());
+        let time = Instant::now();
+
+        // both hosts send their connection request before receiving the other one
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        let request = network.drop_packet(server_address(), 0).unwrap();
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![2]))
+            .unwrap();
+        server.manual_poll(time);
+        network.spoof_packet(client_address(), server_address(), request);
+
+        for _ in 0..4 {
+            client.manual_poll(time);
+            server.manual_poll(time);
+        }
+
+        let received = |socket: &mut FakeSocket| {
+            std::iter::from_fn(|| socket.recv())
+                .filter_map(|event| match event {
+                    SocketEvent::Packet(packet) => Some(packet.payload().to_vec()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(received(&mut server), vec![vec![1]]);
+        assert_eq!(received(&mut client), vec![vec![2]]);
+    }
+
+    #[test]
+    fn encryption_keys_are_established_when_connection_accepted_is_lost() {
+        let config = Config {
+            aggregate_packets: true,
+            ..encryption_config()
+        };
+        let (mut server, mut client, network) = create_server_client_network_with(config.clone());
+        let mut time = Instant::now();
+
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        server.manual_poll(time);
+        assert_eq!(server.recv(), Some(SocketEvent::Connect(client_address())));
+
+        // the server sends data along with the connection accepted, which is lost
+        network.clear_packets(client_address());
+        server
+            .send(Packet::reliable_unordered(client_address(), vec![2]))
+            .unwrap();
+        server.manual_poll(time);
+
+        // the data cannot be decrypted until the resent response is accepted again
+        client.manual_poll(time);
+        assert_eq!(client.recv(), None);
+
+        time += config.handshake_resend_interval;
+        client.manual_poll(time);
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(client.recv(), Some(SocketEvent::Connect(server_address())));
+
+        // the server resends its packet once it was not acknowledged in time
+        time += config.initial_retransmission_timeout;
+        server.manual_poll(time);
+        client.manual_poll(time);
+        assert_eq!(
+            client.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                server_address(),
+                vec![2]
+            )))
+        );
+
+        server.manual_poll(time);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address(),
+                vec![1]
+            )))
+        );
+    }
+
+    #[test]
+    fn connect_tokens_are_verified_before_a_connection_is_created() {
+        const KEY: [u8; 32] = [5; 32];
+        let token = ConnectToken::with_lifetime(&KEY, 1, Duration::from_secs(30));
+        let expired = ConnectToken::new(&KEY, 2, SystemTime::now() - Duration::from_secs(1));
+        let forged = ConnectToken::with_lifetime(&[6; 32], 3, Duration::from_secs(30));
+        let config = |connect_token: Option<ConnectToken>| Config {
+            connect_token_key: Some(KEY),
+            connect_token: connect_token.map(|token| token.to_bytes()),
+            ..handshake_config()
+        };
+
+        let network = NetworkEmulator::default();
+        // the connect token key enables the handshake on its own
+        let server_config = Config {
+            handshake_enabled: false,
+            ..config(None)
+        };
+        let mut server = FakeSocket::bind(&network, server_address(), server_config).unwrap();
+        // the second client reuses the token of the first one
+        let mut clients: Vec<FakeSocket> =
+            [Some(token), Some(token), Some(expired), Some(forged), None]
+                .iter()
+                .enumerate()
+                .map(|(n, &token)| {
+                    FakeSocket::bind(&network, client_address_n(n as u16), config(token)).unwrap()
+                })
+                .collect();
+
+        let time = Instant::now();
+        for client in clients.iter_mut() {
+            client
+                .send(Packet::reliable_unordered(server_address(), vec![1]))
+                .unwrap();
+        }
+        for _ in 0..3 {
+            for client in clients.iter_mut() {
+                client.manual_poll(time);
+            }
+            server.manual_poll(time);
+        }
+
+        assert_eq!(server.connection_count(), 1);
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Connect(client_address_n(0)))
+        );
+        assert_eq!(
+            server.recv(),
+            Some(SocketEvent::Packet(Packet::reliable_unordered(
+                client_address_n(0),
+                vec![1]
+            )))
+        );
+        assert_eq!(server.recv(), None);
+        assert_eq!(
+            clients[0].recv(),
+            Some(SocketEvent::Connect(server_address()))
+        );
+        for client in clients.iter_mut().skip(1) {
+            assert_eq!(client.recv(), None);
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn do_not_panic_on_arbitrary_packets(bytes: Vec<u8>) {
+        let network = NetworkEmulator::default();
+        let mut server = FakeSocket::bind(&network, server_address(), Config::default()).unwrap();
+        let mut client_socket = network.new_socket(client_address()).unwrap();
+
+        client_socket
+            .send_packet(&server_address(), &bytes)
+            .unwrap();
+
+        let time = Instant::now();
+        server.manual_poll(time);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn fragments_are_reassembled_in_any_order(message: Vec<u8>, seed: u64) -> TestResult {
+        let config = Config {
+            fragment_size: 8,
+            ..Default::default()
+        };
+        if message.len() <= usize::from(config.fragment_size)
+            || message.len() > usize::from(config.fragment_size) * usize::from(config.max_fragments)
+        {
+            return TestResult::discard();
+        }
+        let (mut server, mut client, network) = create_server_client_network_with(config);
+        let time = Instant::now();
+        connect_server_client(&mut server, &mut client, time);
+
+        client
+            .send(Packet::reliable_unordered(
+                server_address(),
+                message.clone(),
+            ))
+            .unwrap();
+        client.manual_poll(time);
+        network.shuffle_packets(server_address(), &mut Pcg64Mcg::seed_from_u64(seed));
+        server.manual_poll(time);
+
+        let payloads: Vec<_> = std::iter::from_fn(|| server.recv())
+            .filter_map(|event| match event {
+                SocketEvent::Packet(packet) => Some(packet.payload().to_vec()),
+                _ => None,
+            })
+            .collect();
+        TestResult::from_bool(payloads == vec![message])
     }
 }
@@ -2,14 +2,30 @@
 pub const FRAGMENT_HEADER_SIZE: u8 = 4;
 /// The size of the acknowledgment header.
 pub const ACKED_PACKET_HEADER: u8 = 8;
+/// The size of the header of an ack-only packet.
+pub const ACK_HEADER_SIZE: u8 = 6;
+/// The size of the length that precedes every packet in an aggregate datagram.
+pub const AGGREGATE_LENGTH_SIZE: u8 = 2;
 /// The size of the arranging header.
 pub const ARRANGING_PACKET_HEADER: u8 = 3;
 /// The size of the standard header.
 pub const STANDARD_HEADER_SIZE: u8 = 5;
+/// The number of packets before the acknowledged one that can be acknowledged by the `ack_field`
+/// of a header.
+pub const REDUNDANT_PACKET_ACKS_SIZE: u16 = 32;
 /// The ordering stream that will be used to order on if none was specified.
 pub const DEFAULT_ORDERING_STREAM: u8 = 255;
 /// The sequencing stream that will be used to sequence packets on if none was specified.
 pub const DEFAULT_SEQUENCING_STREAM: u8 = 255;
+/// The number of times a disconnect packet is sent, to make it likely that one of them arrives.
+pub const DISCONNECT_PACKET_REDUNDANCY: u8 = 3;
+/// The number of the most recent encrypted packets that are remembered to drop replayed packets.
+/// Packets that arrive later than this many newer packets are dropped as well.
+pub const REPLAY_WINDOW_SIZE: u16 = 256;
+/// The maximal number of addresses and subnets whose rate limits are tracked at once.
+/// While that many are tracked, datagrams from other addresses are dropped unless the bucket of a
+/// tracked address filled up again and can be forgotten.
+pub const MAX_RATE_LIMITED_ADDRESSES: usize = 65536;
 /// Default maximal number of fragments to size.
 pub const MAX_FRAGMENTS_DEFAULT: u16 = 16;
 /// Default maximal size of each fragment.
@@ -25,4 +41,4 @@ pub const DEFAULT_MTU: u16 = 1452;
 /// This is the current protocol version.
 ///
 /// Incremental monolithic protocol number.
-pub const PROTOCOL_VERSION: u16 = 2;
+pub const PROTOCOL_VERSION: u16 = 3;
@@ -0,0 +1,316 @@
+//! Module with the authenticated encryption of the packets of a connection.
+//!
+//! When `Config::encryption_enabled` is set, both hosts append an ephemeral X25519 public key to
+//! their handshake packets: the client to its `ConnectionRequest` and `ConnectionResponse`, the
+//! server to its `ConnectionAccepted`. The keys of a connection are derived from the shared secret
+//! of the key exchange, and from `Config::encryption_key` if the application supplied one. Every
+//! direction of a connection has its own key.
+//!
+//! All packets except the handshake packets are encrypted once the keys are established.
+//! An encrypted packet has the following layout:
+//!
+//! ```text
+//! | standard header | packet number | encrypted headers and payload | tag |
+//! | 5 bytes         | 8 bytes       | ...                           | 16  |
+//! ```
+//!
+//! The standard header has its encrypted flag set and is authenticated along with the packet
+//! number, which is incremented for every packet and used as the nonce.
+
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    error::{EncryptionErrorKind, Result},
+    net::constants::STANDARD_HEADER_SIZE,
+    packet::header::{HeaderReader, HeaderWriter, StandardHeader},
+};
+
+/// The size of the public key that is appended to the handshake packets.
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// The size of the packet number of an encrypted packet.
+pub const PACKET_NUMBER_SIZE: usize = 8;
+/// The size of the authentication tag of an encrypted packet.
+pub const TAG_SIZE: usize = 16;
+/// The number of bytes an encrypted packet is larger than the packet it encrypts.
+pub const ENCRYPTION_OVERHEAD: usize = PACKET_NUMBER_SIZE + TAG_SIZE;
+
+// Distinguishes the keys derived by this protocol from keys derived from the same secret elsewhere.
+const KEY_DERIVATION_INFO: &[u8] = b"laminar packet keys";
+
+/// Splits the public key off the end of a handshake payload.
+/// Returns `None` if the payload is too short to carry one.
+pub fn split_public_key(payload: &[u8]) -> Option<(&[u8], [u8; PUBLIC_KEY_SIZE])> {
+    let split = payload.len().checked_sub(PUBLIC_KEY_SIZE)?;
+    let mut public_key = [0; PUBLIC_KEY_SIZE];
+    public_key.copy_from_slice(&payload[split..]);
+    Some((&payload[..split], public_key))
+}
+
+/// The ephemeral key pair a host uses for the key exchange of a single connection.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    /// Generates a new random key pair.
+    pub fn new() -> KeyExchange {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        KeyExchange {
+            public_key: PublicKey::from(&secret),
+            secret,
+        }
+    }
+
+    /// Returns the public key that is sent to the remote host.
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public_key.to_bytes()
+    }
+
+    /// Derives the keys of the connection from the public key of the remote host and the
+    /// pre-shared key, if any. `is_client` tells which role this host takes, the remote host has to
+    /// take the other one.
+    ///
+    /// Returns `None` if the public key of the remote host is one that results in a shared secret
+    /// which is known in advance.
+    pub fn derive_cipher(
+        &self,
+        remote_public_key: [u8; PUBLIC_KEY_SIZE],
+        pre_shared_key: Option<&[u8; 32]>,
+        is_client: bool,
+    ) -> Option<PacketCipher> {
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(remote_public_key));
+        if !shared_secret.was_contributory() {
+            return None;
+        }
+
+        let (client_public_key, server_public_key) = if is_client {
+            (self.public_key(), remote_public_key)
+        } else {
+            (remote_public_key, self.public_key())
+        };
+        let mut info = KEY_DERIVATION_INFO.to_vec();
+        info.extend_from_slice(&client_public_key);
+        info.extend_from_slice(&server_public_key);
+
+        let mut keys = [0; 64];
+        Hkdf::<Sha256>::new(pre_shared_key.map(|key| &key[..]), shared_secret.as_bytes())
+            .expand(&info, &mut keys)
+            .expect("64 bytes is a valid length for HKDF-SHA256");
+        let (client_key, server_key) = keys.split_at(32);
+        let (send_key, receive_key) = if is_client {
+            (client_key, server_key)
+        } else {
+            (server_key, client_key)
+        };
+
+        Some(PacketCipher {
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            receive_cipher: ChaCha20Poly1305::new(Key::from_slice(receive_key)),
+            packet_number: 0,
+        })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts the packets sent on a connection and decrypts the packets received on it.
+pub struct PacketCipher {
+    send_cipher: ChaCha20Poly1305,
+    receive_cipher: ChaCha20Poly1305,
+    // The packet number of the next packet that is sent, it is never reused for the same key.
+    packet_number: u64,
+}
+
+impl PacketCipher {
+    /// Encrypts an encoded packet, everything after its standard header is encrypted.
+    pub fn encrypt(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        let header = StandardHeader::read(&mut Cursor::new(packet))?;
+        let packet_number = self.packet_number;
+        self.packet_number = packet_number
+            .checked_add(1)
+            .ok_or(EncryptionErrorKind::PacketNumberExhausted)?;
+
+        let mut encrypted = Vec::with_capacity(packet.len() + ENCRYPTION_OVERHEAD);
+        header.with_encryption().parse(&mut encrypted)?;
+        encrypted.write_u64::<BigEndian>(packet_number)?;
+
+        let ciphertext = self
+            .send_cipher
+            .encrypt(
+                &nonce(packet_number),
+                Payload {
+                    msg: &packet[STANDARD_HEADER_SIZE as usize..],
+                    aad: &encrypted,
+                },
+            )
+            .map_err(|_| EncryptionErrorKind::AuthenticationFailed)?;
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Verifies and decrypts an encrypted packet, returns the packet number and the packet as it
+    /// was before it got encrypted.
+    pub fn decrypt(&self, packet: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let authenticated_size = STANDARD_HEADER_SIZE as usize + PACKET_NUMBER_SIZE;
+        if packet.len() < authenticated_size + TAG_SIZE {
+            return Err(EncryptionErrorKind::AuthenticationFailed.into());
+        }
+
+        let mut rdr = Cursor::new(packet);
+        let header = StandardHeader::read(&mut rdr)?;
+        let packet_number = rdr.read_u64::<BigEndian>()?;
+        let plaintext = self
+            .receive_cipher
+            .decrypt(
+                &nonce(packet_number),
+                Payload {
+                    msg: &packet[authenticated_size..],
+                    aad: &packet[..authenticated_size],
+                },
+            )
+            .map_err(|_| EncryptionErrorKind::AuthenticationFailed)?;
+
+        let mut decrypted = Vec::with_capacity(STANDARD_HEADER_SIZE as usize + plaintext.len());
+        header.without_encryption().parse(&mut decrypted)?;
+        decrypted.extend_from_slice(&plaintext);
+        Ok((packet_number, decrypted))
+    }
+}
+
+// Every direction has its own key, so the packet number alone makes the nonce unique.
+fn nonce(packet_number: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&packet_number.to_be_bytes());
+    Nonce::clone_from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{EncryptionErrorKind, ErrorKind};
+    use crate::packet::{
+        DeliveryGuarantee, OrderingGuarantee, OutgoingPacketBuilder, PacketReader, PacketType,
+    };
+
+    use super::{split_public_key, KeyExchange, PacketCipher, ENCRYPTION_OVERHEAD};
+
+    fn ciphers(client_key: Option<&[u8; 32]>, server_key: Option<&[u8; 32]>) -> [PacketCipher; 2] {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        [
+            client
+                .derive_cipher(server.public_key(), client_key, true)
+                .unwrap(),
+            server
+                .derive_cipher(client.public_key(), server_key, false)
+                .unwrap(),
+        ]
+    }
+
+    fn packet(payload: &[u8]) -> Vec<u8> {
+        OutgoingPacketBuilder::new(payload)
+            .with_default_header(
+                PacketType::Packet,
+                DeliveryGuarantee::Reliable,
+                OrderingGuarantee::None,
+            )
+            .with_acknowledgment_header(7, 6, 0b101)
+            .build()
+            .contents()
+            .to_vec()
+    }
+
+    #[test]
+    fn packets_are_decrypted_by_the_remote_host() {
+        let [mut client, mut server] = ciphers(None, None);
+        let packet = packet(b"secret");
+
+        let encrypted = client.encrypt(&packet).unwrap();
+        assert_eq!(encrypted.len(), packet.len() + ENCRYPTION_OVERHEAD);
+        assert!(!encrypted.windows(6).any(|window| window == b"secret"));
+        assert!(PacketReader::new(&encrypted)
+            .read_standard_header()
+            .unwrap()
+            .is_encrypted());
+
+        assert_eq!(server.decrypt(&encrypted).unwrap(), (0, packet.clone()));
+        let encrypted = server.encrypt(&packet).unwrap();
+        assert_eq!(client.decrypt(&encrypted).unwrap(), (0, packet));
+    }
+
+    #[test]
+    fn every_packet_gets_a_new_packet_number() {
+        let [mut client, server] = ciphers(None, None);
+        let packet = packet(b"payload");
+
+        let first = client.encrypt(&packet).unwrap();
+        let second = client.encrypt(&packet).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(server.decrypt(&second).unwrap().0, 1);
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let [mut client, server] = ciphers(None, None);
+        let encrypted = client.encrypt(&packet(b"payload")).unwrap();
+
+        // the standard header, packet number, ack header, payload and tag are all authenticated.
+        for index in [2, 8, 15, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[index] ^= 1;
+            assert!(matches!(
+                server.decrypt(&tampered),
+                Err(ErrorKind::EncryptionError(
+                    EncryptionErrorKind::AuthenticationFailed
+                )) | Err(ErrorKind::DecodingError(_))
+            ));
+        }
+
+        // a packet must not be accepted in the direction it was sent in.
+        assert!(client.decrypt(&encrypted).is_err());
+        assert!(server.decrypt(&encrypted[..20]).is_err());
+    }
+
+    #[test]
+    fn pre_shared_keys_have_to_match() {
+        let [mut client, server] = ciphers(Some(&[1; 32]), Some(&[1; 32]));
+        assert!(server
+            .decrypt(&client.encrypt(&packet(&[])).unwrap())
+            .is_ok());
+
+        let [mut client, server] = ciphers(Some(&[1; 32]), Some(&[2; 32]));
+        assert!(server
+            .decrypt(&client.encrypt(&packet(&[])).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn low_order_public_keys_are_rejected() {
+        assert!(KeyExchange::new()
+            .derive_cipher([0; 32], None, false)
+            .is_none());
+    }
+
+    #[test]
+    fn public_key_is_split_off() {
+        let mut payload = vec![1, 2, 3];
+        payload.extend_from_slice(&[9; 32]);
+        assert_eq!(split_public_key(&payload), Some((&[1, 2, 3][..], [9; 32])));
+        assert_eq!(split_public_key(&[0; 31]), None);
+    }
+}
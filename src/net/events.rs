@@ -1,6 +1,8 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-use crate::packet::Packet;
+use crate::net::NetworkQuality;
+use crate::packet::{MessageId, Packet};
 
 /// Events that can occur in `laminar` and that will be pushed through the `event_receiver` returned by `Socket::bind`.
 #[derive(Debug, PartialEq)]
@@ -18,6 +20,85 @@ pub enum SocketEvent {
     /// The client has been idling for longer than the `idle_connection_timeout` time.
     /// You can control the timeout in the config.
     Timeout(SocketAddr),
-    /// The established connection to a client has timed out.
-    Disconnect(SocketAddr),
+    /// The established connection to a client was closed, either because it has timed out or
+    /// because one of the endpoints disconnected explicitly with `Socket::disconnect`.
+    Disconnect(SocketAddr, DisconnectReason),
+    /// The network quality of an established connection changed, based on the round trip time and
+    /// packet loss thresholds in the config.
+    QualityChanged(SocketAddr, NetworkQuality),
+    /// The packet sent with the given id has been acknowledged by the remote host.
+    Acked(SocketAddr, MessageId),
+    /// The unreliable packet sent with the given id is considered lost, because it was not
+    /// acknowledged in time. Only emitted when `Config::ack_unreliable_packets` is enabled,
+    /// reliable packets are resent until they are acknowledged.
+    Lost(SocketAddr, MessageId),
+}
+
+/// Commands that are sent to a socket through the sender returned by `Socket::get_command_sender`,
+/// they are executed in the order they were sent during the next poll.
+#[derive(Debug, PartialEq)]
+pub enum SocketCommand {
+    /// Gracefully closes the connection to the given address, see `Socket::disconnect_with_reason`.
+    Disconnect(SocketAddr, DisconnectReason),
+    /// Drops all datagrams from the given IP address for the given duration, see `Socket::ban`.
+    Ban(IpAddr, Duration),
+    /// Lifts the ban of the given IP address, see `Socket::unban`.
+    Unban(IpAddr),
+    /// Exempts the given IP address from the rate limits, see `Socket::allow`.
+    Allow(IpAddr),
+    /// Subjects the given IP address to the rate limits again, see `Socket::remove_allowed`.
+    RemoveAllowed(IpAddr),
+}
+
+/// The reason why a connection was closed, it is carried in the disconnect packet to the remote host.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection has timed out.
+    Timeout,
+    /// The connection was closed on request, without a specific reason.
+    Requested,
+    /// The connection was closed with an application specific reason code.
+    Custom(u8),
+}
+
+impl DisconnectReason {
+    /// Returns the bytes that represent this reason in a disconnect packet.
+    pub(crate) fn to_bytes(self) -> [u8; 2] {
+        match self {
+            DisconnectReason::Timeout => [0, 0],
+            DisconnectReason::Requested => [1, 0],
+            DisconnectReason::Custom(code) => [2, code],
+        }
+    }
+
+    /// Reads the reason from the payload of a disconnect packet.
+    pub(crate) fn from_bytes(payload: &[u8]) -> Option<DisconnectReason> {
+        match payload {
+            [0, 0] => Some(DisconnectReason::Timeout),
+            [1, 0] => Some(DisconnectReason::Requested),
+            [2, code] => Some(DisconnectReason::Custom(*code)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DisconnectReason;
+
+    #[test]
+    fn assure_parsing_disconnect_reason() {
+        for reason in &[
+            DisconnectReason::Timeout,
+            DisconnectReason::Requested,
+            DisconnectReason::Custom(42),
+        ] {
+            assert_eq!(
+                DisconnectReason::from_bytes(&reason.to_bytes()),
+                Some(*reason)
+            );
+        }
+        assert_eq!(DisconnectReason::from_bytes(&[3, 0]), None);
+        assert_eq!(DisconnectReason::from_bytes(&[1]), None);
+    }
 }
@@ -0,0 +1,318 @@
+//! Module with the logic for the challenge-response handshake.
+//!
+//! The handshake consists of four unreliable control packets:
+//!
+//! 1. The client sends a `ConnectionRequest` carrying a random salt, followed by a connect token
+//!    if the server requires one. The request is padded to `MIN_CONNECTION_REQUEST_SIZE` bytes,
+//!    so a host that spoofs its source address cannot make the server send more bytes to that
+//!    address than it sent itself.
+//! 2. The server answers with a `ConnectionChallenge` carrying the salt and a cookie.
+//!    The server does not allocate anything for the client at this point, the cookie is
+//!    signed with a secret key and carries the time it was issued at.
+//! 3. The client echoes the cookie back in a `ConnectionResponse`.
+//!    Only a client that is able to receive packets on its source address can do this.
+//! 4. The server verifies the cookie, creates the connection and confirms with a `ConnectionAccepted`.
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::connect_token::ConnectTokenVerifier;
+
+/// The size of the payload of a `ConnectionRequest` and `ConnectionAccepted` packet.
+pub const HANDSHAKE_SALT_SIZE: usize = 8;
+/// The size of the payload of a `ConnectionChallenge` and `ConnectionResponse` packet.
+pub const HANDSHAKE_CHALLENGE_SIZE: usize = 16 + CHALLENGE_MAC_SIZE;
+/// The size a `ConnectionRequest` packet is padded to, requests that are smaller are ignored.
+pub const MIN_CONNECTION_REQUEST_SIZE: usize = 128;
+
+// The size of the cookie of a challenge, a truncated HMAC-SHA256.
+const CHALLENGE_MAC_SIZE: usize = 16;
+
+/// The state of the handshake of a single connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// No handshake packet has been sent or received yet.
+    Idle,
+    /// We have sent a connection request and are waiting for a challenge.
+    Requesting {
+        /// Salt which the remote host has to echo back.
+        salt: u64,
+    },
+    /// We have answered a challenge and are waiting for the remote host to accept us.
+    Responding {
+        /// The challenge we answered, it is resent until we are accepted.
+        challenge: Challenge,
+    },
+    /// The handshake is completed.
+    Connected {
+        /// The challenge the handshake was completed with, only the two endpoints know it.
+        /// Disconnect packets carry it to prove that they come from the remote host.
+        challenge: Challenge,
+    },
+}
+
+/// A challenge issued by a host to prove that the remote host owns its source address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    salt: u64,
+    timestamp: u64,
+    mac: [u8; CHALLENGE_MAC_SIZE],
+}
+
+impl Challenge {
+    /// Returns the salt of the connection request this challenge belongs to.
+    pub fn salt(&self) -> u64 {
+        self.salt
+    }
+
+    /// Writes this challenge to a payload buffer.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HANDSHAKE_CHALLENGE_SIZE);
+        // writing into a `Vec` cannot fail.
+        buffer.write_u64::<BigEndian>(self.salt).unwrap();
+        buffer.write_u64::<BigEndian>(self.timestamp).unwrap();
+        buffer.extend_from_slice(&self.mac);
+        buffer
+    }
+
+    /// Reads a challenge from a payload, returns `None` if the payload has the wrong size.
+    pub fn from_bytes(payload: &[u8]) -> Option<Challenge> {
+        if payload.len() != HANDSHAKE_CHALLENGE_SIZE {
+            return None;
+        }
+
+        let mut rdr = Cursor::new(payload);
+        let mut challenge = Challenge {
+            salt: rdr.read_u64::<BigEndian>().ok()?,
+            timestamp: rdr.read_u64::<BigEndian>().ok()?,
+            mac: [0; CHALLENGE_MAC_SIZE],
+        };
+        challenge
+            .mac
+            .copy_from_slice(&payload[rdr.position() as usize..]);
+        Some(challenge)
+    }
+}
+
+/// Writes a salt to a payload buffer.
+pub fn salt_to_bytes(salt: u64) -> Vec<u8> {
+    salt.to_be_bytes().to_vec()
+}
+
+/// Reads a salt from a payload, returns `None` if the payload has the wrong size.
+pub fn salt_from_bytes(payload: &[u8]) -> Option<u64> {
+    if payload.len() != HANDSHAKE_SALT_SIZE {
+        return None;
+    }
+    Cursor::new(payload).read_u64::<BigEndian>().ok()
+}
+
+/// Reads the payload of a `ConnectionRequest`, returns its salt and the bytes that follow it,
+/// which start with the connect token if the request carries one and end with the padding.
+pub fn request_from_bytes(payload: &[u8]) -> Option<(u64, &[u8])> {
+    if payload.len() < HANDSHAKE_SALT_SIZE {
+        return None;
+    }
+    let (salt, remainder) = payload.split_at(HANDSHAKE_SALT_SIZE);
+    Some((salt_from_bytes(salt)?, remainder))
+}
+
+/// Decides which unknown addresses may take part in the handshake, without creating a connection
+/// for them.
+#[derive(Debug, Default)]
+pub struct HandshakeAcceptor {
+    /// Issues and verifies the challenges.
+    pub challenges: ChallengeSigner,
+    /// Verifies the connect tokens of the connection requests, if the config requires them.
+    pub connect_tokens: ConnectTokenVerifier,
+}
+
+/// Issues and verifies challenges without keeping any state per remote host.
+///
+/// The cookie of a challenge is an HMAC-SHA256 over the remote address, the salt and the time the
+/// challenge was issued at. The key is randomly generated for every signer and never leaves it.
+#[derive(Debug)]
+pub struct ChallengeSigner {
+    key: [u8; 32],
+    // The instant all cookie timestamps are relative to, initialized on first use.
+    epoch: Option<Instant>,
+}
+
+impl Default for ChallengeSigner {
+    fn default() -> Self {
+        ChallengeSigner {
+            key: rand::random(),
+            epoch: None,
+        }
+    }
+}
+
+impl ChallengeSigner {
+    /// Issues a new challenge for the given address and salt.
+    pub fn issue(&mut self, address: SocketAddr, salt: u64, time: Instant) -> Challenge {
+        let timestamp = self.timestamp(time);
+        Challenge {
+            salt,
+            timestamp,
+            mac: self.cookie(address, salt, timestamp),
+        }
+    }
+
+    /// Verifies that the challenge was issued by this signer for the given address and has not
+    /// expired yet.
+    pub fn verify(
+        &mut self,
+        address: SocketAddr,
+        challenge: &Challenge,
+        time: Instant,
+        lifetime: Duration,
+    ) -> bool {
+        let now = self.timestamp(time);
+        challenge.timestamp <= now
+            && now - challenge.timestamp <= lifetime.as_millis() as u64
+            && self
+                .mac(address, challenge.salt, challenge.timestamp)
+                .verify_truncated_left(&challenge.mac)
+                .is_ok()
+    }
+
+    fn timestamp(&mut self, time: Instant) -> u64 {
+        let epoch = *self.epoch.get_or_insert(time);
+        time.saturating_duration_since(epoch).as_millis() as u64
+    }
+
+    fn cookie(&self, address: SocketAddr, salt: u64, timestamp: u64) -> [u8; CHALLENGE_MAC_SIZE] {
+        let mut cookie = [0; CHALLENGE_MAC_SIZE];
+        cookie.copy_from_slice(
+            &self.mac(address, salt, timestamp).finalize().into_bytes()[..CHALLENGE_MAC_SIZE],
+        );
+        cookie
+    }
+
+    fn mac(&self, address: SocketAddr, salt: u64, timestamp: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        match address {
+            SocketAddr::V4(address) => mac.update(&address.ip().octets()),
+            SocketAddr::V6(address) => mac.update(&address.ip().octets()),
+        }
+        mac.update(&address.port().to_be_bytes());
+        mac.update(&salt.to_be_bytes());
+        mac.update(&timestamp.to_be_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        request_from_bytes, salt_from_bytes, salt_to_bytes, Challenge, ChallengeSigner,
+        HANDSHAKE_CHALLENGE_SIZE,
+    };
+
+    const LIFETIME: Duration = Duration::from_secs(5);
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:12345".parse().unwrap()
+    }
+
+    #[test]
+    fn issued_challenge_is_valid() {
+        let mut signer = ChallengeSigner::default();
+        let time = Instant::now();
+
+        let challenge = signer.issue(address(), 42, time);
+
+        assert_eq!(challenge.salt(), 42);
+        assert!(signer.verify(address(), &challenge, time, LIFETIME));
+    }
+
+    #[test]
+    fn challenge_is_bound_to_address() {
+        let mut signer = ChallengeSigner::default();
+        let time = Instant::now();
+
+        let challenge = signer.issue(address(), 42, time);
+
+        assert!(!signer.verify(
+            "127.0.0.1:12346".parse().unwrap(),
+            &challenge,
+            time,
+            LIFETIME
+        ));
+    }
+
+    #[test]
+    fn challenge_is_bound_to_signer() {
+        let mut signer = ChallengeSigner::default();
+        let mut other = ChallengeSigner::default();
+        let time = Instant::now();
+
+        let challenge = signer.issue(address(), 42, time);
+        other.issue(address(), 42, time);
+
+        assert!(!other.verify(address(), &challenge, time, LIFETIME));
+    }
+
+    #[test]
+    fn tampered_challenge_is_invalid() {
+        let mut signer = ChallengeSigner::default();
+        let time = Instant::now();
+
+        let mut bytes = signer.issue(address(), 42, time).to_bytes();
+        bytes[7] ^= 1;
+        let challenge = Challenge::from_bytes(&bytes).unwrap();
+
+        assert!(!signer.verify(address(), &challenge, time, LIFETIME));
+
+        let mut bytes = signer.issue(address(), 42, time).to_bytes();
+        bytes[HANDSHAKE_CHALLENGE_SIZE - 1] ^= 1;
+        let challenge = Challenge::from_bytes(&bytes).unwrap();
+
+        assert!(!signer.verify(address(), &challenge, time, LIFETIME));
+    }
+
+    #[test]
+    fn challenge_expires() {
+        let mut signer = ChallengeSigner::default();
+        let time = Instant::now();
+
+        let challenge = signer.issue(address(), 42, time);
+
+        assert!(signer.verify(address(), &challenge, time + LIFETIME, LIFETIME));
+        assert!(!signer.verify(
+            address(),
+            &challenge,
+            time + LIFETIME + Duration::from_millis(1),
+            LIFETIME
+        ));
+    }
+
+    #[test]
+    fn challenge_serialization() {
+        let mut signer = ChallengeSigner::default();
+        let challenge = signer.issue(address(), 42, Instant::now());
+
+        assert_eq!(
+            Challenge::from_bytes(&challenge.to_bytes()),
+            Some(challenge)
+        );
+        assert_eq!(Challenge::from_bytes(&[0; 23]), None);
+        assert_eq!(salt_from_bytes(&salt_to_bytes(42)), Some(42));
+        assert_eq!(salt_from_bytes(&[0; 7]), None);
+        assert_eq!(request_from_bytes(&salt_to_bytes(42)), Some((42, &[][..])));
+        assert_eq!(
+            request_from_bytes(&[0, 0, 0, 0, 0, 0, 0, 1, 9]),
+            Some((1, &[9][..]))
+        );
+        assert_eq!(request_from_bytes(&[0; 7]), None);
+    }
+}
@@ -3,21 +3,94 @@
 //! networks. This is not in heavy use yet, hence the allowing dead code. These will be removed as our testing
 //! becomes more sophisticated.
 
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 use rand::Rng;
 use rand_pcg::Pcg64Mcg as Random;
 
-/// Network simulator. Used to simulate network conditions as dropped packets and packet delays.
+/// Decides which packets are dropped by a [`LinkConditioner`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LossModel {
+    /// Every packet is dropped with the same probability, between 0 and 1.
+    Uniform(f64),
+    /// Two-state model of a link that alternates between a good and a bad state, losses on such a
+    /// link come in bursts like they do on Wi-Fi and mobile links. Before each packet, the link
+    /// changes its state with the transition probability of its current state, then drops the
+    /// packet with the loss probability of the new state.
+    GilbertElliott {
+        /// Probability that the link turns bad.
+        good_to_bad: f64,
+        /// Probability that the link recovers.
+        bad_to_good: f64,
+        /// Probability that a packet is dropped while the link is good.
+        good_loss: f64,
+        /// Probability that a packet is dropped while the link is bad.
+        bad_loss: f64,
+    },
+    /// Drops the packets whose index is in one of the ranges, the first packet passed to the
+    /// conditioner has index 0. E.g. `Pattern(vec![10..20])` drops the 11th up to the 20th packet.
+    Pattern(Vec<Range<u64>>),
+}
+
+/// Bandwidth of a [`LinkConditioner`], modelled as a token bucket in front of a router queue.
+///
+/// The bucket fills with `bits_per_second` and holds up to `burst_size` bytes, a packet leaves
+/// once the bucket holds enough tokens for its size. Packets that have to wait for tokens are held
+/// in a queue of `queue_size` bytes, packets that do not fit in the queue anymore are dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandwidthLimit {
+    /// Rate at which the link sends data, a rate of 0 drops every packet.
+    pub bits_per_second: u32,
+    /// Number of bytes that can be sent at once after the link was idle.
+    pub burst_size: usize,
+    /// Number of bytes the queue holds before it drops packets.
+    pub queue_size: usize,
+}
+
+/// Network simulator. Used to simulate network conditions as dropped, delayed, reordered and
+/// duplicated packets.
 /// For use in [FakeSocket::set_link_conditioner](crate::test_utils::FakeSocket::set_link_conditioner).
+///
+/// Packets passed to [`send`](#method.send) are held in a queue ordered by the time they arrive,
+/// and are returned by [`next_released`](#method.next_released) once that time has come.
 #[derive(Clone, Debug)]
 pub struct LinkConditioner {
-    // Value between 0 and 1, representing the % change a packet will be dropped on sending
-    packet_loss: f64,
+    // Decides which packets will be dropped on sending
+    loss_model: LossModel,
+    // Whether the link is in the bad state of the Gilbert-Elliott model
+    in_bad_state: bool,
+    // Number of packets that were passed to the conditioner
+    packets_sent: u64,
     // Duration of the delay imposed between packets
     latency: Duration,
+    // Maximal random deviation from the latency, in both directions
+    jitter: Duration,
+    // Value between 0 and 1, representing the % chance a packet skips the latency and overtakes others
+    reorder_rate: f64,
+    // Value between 0 and 1, representing the % chance a packet arrives twice
+    duplicate_rate: f64,
+    // Bandwidth of the link, which is unlimited if not set
+    bandwidth_limit: Option<BandwidthLimit>,
+    // Number of bytes in the token bucket at the time of `last_departure`
+    tokens: f64,
+    // Time the last packet left, or will leave, the queue
+    last_departure: Option<Instant>,
+    // Times the packets in the queue leave it, together with their sizes
+    queued_packets: VecDeque<(Instant, usize)>,
     // Random number generator
     random: Random,
+    // Packets that were sent but did not arrive yet, ordered by the time they arrive
+    delayed_packets: VecDeque<DelayedPacket>,
+}
+
+#[derive(Clone, Debug)]
+struct DelayedPacket {
+    arrival: Instant,
+    address: SocketAddr,
+    payload: Box<[u8]>,
 }
 
 impl LinkConditioner {
@@ -25,16 +98,40 @@ impl LinkConditioner {
     #[allow(dead_code)]
     pub fn new() -> LinkConditioner {
         LinkConditioner {
-            packet_loss: 0.0,
+            loss_model: LossModel::Uniform(0.0),
+            in_bad_state: false,
+            packets_sent: 0,
             latency: Duration::default(),
+            jitter: Duration::default(),
+            reorder_rate: 0.0,
+            duplicate_rate: 0.0,
+            bandwidth_limit: None,
+            tokens: 0.0,
+            last_departure: None,
+            queued_packets: VecDeque::new(),
             random: Random::new(0),
+            delayed_packets: VecDeque::new(),
         }
     }
 
+    /// Seeds the random number generator, conditioners with the same seed and settings drop, delay
+    /// and duplicate the same packets.
+    #[allow(dead_code)]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random = Random::new(u128::from(seed));
+    }
+
     /// Sets the packet loss rate of Link Conditioner
     #[allow(dead_code)]
     pub fn set_packet_loss(&mut self, rate: f64) {
-        self.packet_loss = rate;
+        self.set_loss_model(LossModel::Uniform(rate));
+    }
+
+    /// Sets the model that decides which packets are dropped, see [`LossModel`].
+    #[allow(dead_code)]
+    pub fn set_loss_model(&mut self, loss_model: LossModel) {
+        self.loss_model = loss_model;
+        self.in_bad_state = false;
     }
 
     /// Sets the latency the link conditioner should apply to each packet
@@ -43,9 +140,180 @@ impl LinkConditioner {
         self.latency = latency
     }
 
+    /// Sets the maximal random deviation from the latency, every packet is delayed by a latency
+    /// between `latency - jitter` and `latency + jitter`. Jitter can reorder packets as well.
+    #[allow(dead_code)]
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        self.jitter = jitter
+    }
+
+    /// Sets the rate of packets that arrive without latency, so that they overtake the packets
+    /// that were sent before them.
+    #[allow(dead_code)]
+    pub fn set_reorder_rate(&mut self, rate: f64) {
+        self.reorder_rate = rate;
+    }
+
+    /// Sets the rate of packets that arrive twice, each copy is delayed independently.
+    #[allow(dead_code)]
+    pub fn set_duplicate_rate(&mut self, rate: f64) {
+        self.duplicate_rate = rate;
+    }
+
+    /// Limits the bandwidth of the link, see [`BandwidthLimit`]. `None` removes the limit.
+    #[allow(dead_code)]
+    pub fn set_bandwidth_limit(&mut self, bandwidth_limit: Option<BandwidthLimit>) {
+        self.bandwidth_limit = bandwidth_limit;
+        self.last_departure = None;
+        self.queued_packets.clear();
+    }
+
     /// Function that checks to see if a packet should be dropped or not
     pub fn should_send(&mut self) -> bool {
-        self.random.gen_range(0.0..1.0) >= self.packet_loss
+        let index = self.packets_sent;
+        self.packets_sent += 1;
+
+        match self.loss_model {
+            LossModel::Uniform(rate) => self.random.gen_range(0.0..1.0) >= rate,
+            LossModel::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                if self.in_bad_state {
+                    self.in_bad_state = !self.happens(bad_to_good);
+                } else {
+                    self.in_bad_state = self.happens(good_to_bad);
+                }
+
+                let loss = if self.in_bad_state {
+                    bad_loss
+                } else {
+                    good_loss
+                };
+                !self.happens(loss)
+            }
+            LossModel::Pattern(ref ranges) => !ranges.iter().any(|range| range.contains(&index)),
+        }
+    }
+
+    /// Passes a packet, sent at the given time, through the simulated network. The packet is
+    /// either dropped, or queued until it arrives, possibly twice.
+    pub fn send(&mut self, address: SocketAddr, payload: &[u8], time: Instant) {
+        let departure = match self.departure(payload.len(), time) {
+            Some(departure) => departure,
+            None => return,
+        };
+        if !self.should_send() {
+            return;
+        }
+
+        let copies = if self.happens(self.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let arrival = departure + self.delay();
+            // packets that arrive at the same time keep the order they were sent in.
+            let index = self
+                .delayed_packets
+                .partition_point(|packet| packet.arrival <= arrival);
+            self.delayed_packets.insert(
+                index,
+                DelayedPacket {
+                    arrival,
+                    address,
+                    payload: Box::from(payload),
+                },
+            );
+        }
+    }
+
+    /// Returns the next packet that has arrived at the given time, together with its destination.
+    pub fn next_released(&mut self, time: Instant) -> Option<(SocketAddr, Box<[u8]>)> {
+        if self.delayed_packets.front()?.arrival > time {
+            return None;
+        }
+        self.delayed_packets
+            .pop_front()
+            .map(|packet| (packet.address, packet.payload))
+    }
+
+    // Returns the time a packet of the given size leaves the router queue, or `None` if the queue is
+    // full and the packet is dropped.
+    fn departure(&mut self, size: usize, time: Instant) -> Option<Instant> {
+        let limit = match self.bandwidth_limit {
+            Some(ref limit) if limit.bits_per_second == 0 => return None,
+            Some(ref limit) => limit,
+            None => return Some(time),
+        };
+
+        while let Some(&(departure, _)) = self.queued_packets.front() {
+            if departure > time {
+                break;
+            }
+            self.queued_packets.pop_front();
+        }
+
+        let bytes_per_second = f64::from(limit.bits_per_second) / 8.0;
+        let burst_size = limit.burst_size as f64;
+        // the queue is first in, first out, so a packet cannot leave before the previous one did.
+        let start = match self.last_departure {
+            Some(last_departure) => {
+                let start = last_departure.max(time);
+                let refill = (start - last_departure).as_secs_f64() * bytes_per_second;
+                self.tokens = (self.tokens + refill).min(burst_size);
+                start
+            }
+            None => {
+                self.tokens = burst_size;
+                time
+            }
+        };
+        self.last_departure = Some(start);
+
+        let departure = if self.tokens >= size as f64 {
+            self.tokens -= size as f64;
+            start
+        } else {
+            let queued: usize = self.queued_packets.iter().map(|&(_, size)| size).sum();
+            if queued + size > limit.queue_size {
+                return None;
+            }
+            let wait = (size as f64 - self.tokens) / bytes_per_second;
+            self.tokens = 0.0;
+            start + Duration::from_secs_f64(wait)
+        };
+
+        if departure > time {
+            self.queued_packets.push_back((departure, size));
+        }
+        self.last_departure = Some(departure);
+        Some(departure)
+    }
+
+    // Returns the delay of a single packet, the random number generator is only used for the
+    // conditions that are enabled, so that results stay reproducible.
+    fn delay(&mut self) -> Duration {
+        if self.happens(self.reorder_rate) {
+            return Duration::default();
+        }
+        if self.jitter == Duration::default() {
+            return self.latency;
+        }
+
+        let deviation = self.jitter.mul_f64(self.random.gen_range(0.0..=1.0));
+        if self.random.gen_bool(0.5) {
+            self.latency + deviation
+        } else {
+            self.latency.saturating_sub(deviation)
+        }
+    }
+
+    fn happens(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.random.gen_range(0.0..1.0) < rate
     }
 }
 
@@ -54,3 +322,210 @@ impl Default for LinkConditioner {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use super::{BandwidthLimit, LinkConditioner, LossModel};
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:10001".parse().unwrap()
+    }
+
+    fn released(conditioner: &mut LinkConditioner, time: Instant) -> Vec<u8> {
+        std::iter::from_fn(|| conditioner.next_released(time))
+            .map(|(_, payload)| payload[0])
+            .collect()
+    }
+
+    #[test]
+    fn packets_arrive_after_latency() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_latency(Duration::from_millis(50));
+        let time = Instant::now();
+
+        conditioner.send(address(), &[1], time);
+        conditioner.send(address(), &[2], time + Duration::from_millis(10));
+
+        assert!(released(&mut conditioner, time + Duration::from_millis(49)).is_empty());
+        assert_eq!(
+            conditioner.next_released(time + Duration::from_millis(50)),
+            Some((address(), Box::from(&[1][..])))
+        );
+        assert!(released(&mut conditioner, time + Duration::from_millis(59)).is_empty());
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_millis(60)),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_latency(Duration::from_millis(50));
+        conditioner.set_jitter(Duration::from_millis(20));
+        let time = Instant::now();
+
+        for i in 0..100 {
+            conditioner.send(address(), &[i], time);
+        }
+
+        assert!(released(&mut conditioner, time + Duration::from_millis(29)).is_empty());
+        let early = released(&mut conditioner, time + Duration::from_millis(50));
+        assert!(!early.is_empty() && early.len() < 100);
+        assert_eq!(
+            early.len() + released(&mut conditioner, time + Duration::from_millis(70)).len(),
+            100
+        );
+    }
+
+    #[test]
+    fn reordered_packets_overtake_delayed_packets() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_latency(Duration::from_millis(50));
+        conditioner.set_reorder_rate(0.5);
+        let time = Instant::now();
+
+        for i in 0..100 {
+            conditioner.send(address(), &[i], time);
+        }
+
+        let overtaking = released(&mut conditioner, time);
+        let delayed = released(&mut conditioner, time + Duration::from_millis(50));
+        assert!(!overtaking.is_empty() && !delayed.is_empty());
+        assert_eq!(overtaking.len() + delayed.len(), 100);
+        // packets with the same delay keep their order
+        assert!(overtaking.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(delayed.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn duplicated_and_dropped_packets() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_duplicate_rate(1.0);
+        let time = Instant::now();
+
+        conditioner.send(address(), &[1], time);
+        assert_eq!(released(&mut conditioner, time), vec![1, 1]);
+
+        conditioner.set_packet_loss(1.0);
+        conditioner.send(address(), &[2], time);
+        assert!(released(&mut conditioner, time).is_empty());
+    }
+
+    #[test]
+    fn pattern_drops_the_given_packets() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_loss_model(LossModel::Pattern(vec![2..4, 6..7]));
+
+        let sent: Vec<bool> = (0..8).map(|_| conditioner.should_send()).collect();
+        assert_eq!(
+            sent,
+            vec![true, true, false, false, true, true, false, true]
+        );
+    }
+
+    #[test]
+    fn gilbert_elliott_losses_come_in_bursts() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_loss_model(LossModel::GilbertElliott {
+            good_to_bad: 0.05,
+            bad_to_good: 0.2,
+            good_loss: 0.0,
+            bad_loss: 1.0,
+        });
+
+        let sent: Vec<bool> = (0..10_000).map(|_| conditioner.should_send()).collect();
+        let lost = sent.iter().filter(|sent| !**sent).count();
+        let bursts = sent.windows(2).filter(|pair| pair[0] && !pair[1]).count();
+
+        // the link is bad a fifth of the time, for five packets on average
+        assert!(lost > 1_000 && lost < 3_000, "lost {} packets", lost);
+        assert!(
+            lost / bursts >= 3,
+            "{} packets lost in {} bursts",
+            lost,
+            bursts
+        );
+    }
+
+    #[test]
+    fn bandwidth_limit_paces_packets() {
+        let mut conditioner = LinkConditioner::new();
+        // 8 bytes per millisecond
+        conditioner.set_bandwidth_limit(Some(BandwidthLimit {
+            bits_per_second: 64_000,
+            burst_size: 80,
+            queue_size: 1_000,
+        }));
+        let time = Instant::now();
+
+        for i in 0..4 {
+            conditioner.send(address(), &[i; 80], time);
+        }
+
+        // the burst leaves at once, the others follow when the bucket has been refilled
+        assert_eq!(released(&mut conditioner, time), vec![0]);
+        assert!(released(&mut conditioner, time + Duration::from_millis(9)).is_empty());
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_millis(10)),
+            vec![1]
+        );
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_millis(20)),
+            vec![2]
+        );
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_millis(30)),
+            vec![3]
+        );
+
+        // an idle link refills the bucket
+        conditioner.send(address(), &[4; 80], time + Duration::from_millis(100));
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_millis(100)),
+            vec![4]
+        );
+    }
+
+    #[test]
+    fn full_queue_drops_packets() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_bandwidth_limit(Some(BandwidthLimit {
+            bits_per_second: 64_000,
+            burst_size: 80,
+            queue_size: 160,
+        }));
+        let time = Instant::now();
+
+        for i in 0..5 {
+            conditioner.send(address(), &[i; 80], time);
+        }
+        // the queue has room again once a packet left it
+        conditioner.send(address(), &[5; 80], time + Duration::from_millis(10));
+
+        assert_eq!(
+            released(&mut conditioner, time + Duration::from_secs(1)),
+            vec![0, 1, 2, 5]
+        );
+    }
+
+    #[test]
+    fn zero_bandwidth_drops_packets() {
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_bandwidth_limit(Some(BandwidthLimit {
+            bits_per_second: 0,
+            burst_size: 80,
+            queue_size: 160,
+        }));
+        let time = Instant::now();
+
+        for i in 0..3 {
+            conditioner.send(address(), &[i; 80], time);
+        }
+
+        assert!(released(&mut conditioner, time + Duration::from_secs(1)).is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+//! Reading and writing of datagrams in the pcap capture file format, which can be opened with
+//! tools like Wireshark and tcpdump.
+//!
+//! Every datagram is stored as a raw IP packet with an IPv4 or IPv6 header and a UDP header, so
+//! that the addresses it was sent from and to are part of the capture.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+const MAGIC_MICROSECONDS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOSECONDS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPSHOT_LENGTH: u32 = 65_535;
+// Link types of captures that contain raw IP packets.
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+const TIME_TO_LIVE: u8 = 64;
+
+/// A datagram of a capture, together with the time it was sent or received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedDatagram {
+    /// Time since the Unix epoch.
+    pub timestamp: Duration,
+    /// Address the datagram was sent from.
+    pub source: SocketAddr,
+    /// Address the datagram was sent to.
+    pub destination: SocketAddr,
+    /// Contents of the datagram.
+    pub payload: Vec<u8>,
+}
+
+/// Writes datagrams to a pcap capture.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the header of the capture and returns a `PcapWriter` that appends datagrams to it.
+    pub fn new(mut writer: W) -> io::Result<PcapWriter<W>> {
+        writer.write_u32::<LittleEndian>(MAGIC_MICROSECONDS)?;
+        writer.write_u16::<LittleEndian>(VERSION_MAJOR)?;
+        writer.write_u16::<LittleEndian>(VERSION_MINOR)?;
+        // time zone offset and accuracy of the timestamps
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(SNAPSHOT_LENGTH)?;
+        writer.write_u32::<LittleEndian>(LINKTYPE_RAW)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Appends a datagram to the capture.
+    pub fn write_datagram(&mut self, datagram: &CapturedDatagram) -> io::Result<()> {
+        let packet = ip_packet(datagram.source, datagram.destination, &datagram.payload)?;
+
+        self.writer
+            .write_u32::<LittleEndian>(datagram.timestamp.as_secs() as u32)?;
+        self.writer
+            .write_u32::<LittleEndian>(datagram.timestamp.subsec_micros())?;
+        self.writer.write_u32::<LittleEndian>(packet.len() as u32)?;
+        self.writer.write_u32::<LittleEndian>(packet.len() as u32)?;
+        self.writer.write_all(&packet)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> std::fmt::Debug for PcapWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PcapWriter").finish()
+    }
+}
+
+/// Reads the UDP datagrams of a pcap capture, other packets of the capture are skipped.
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanoseconds: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the header of the capture, fails if it is not a pcap capture of raw IP packets.
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+
+        let (big_endian, nanoseconds) = match LittleEndian::read_u32(&header) {
+            MAGIC_MICROSECONDS => (false, false),
+            MAGIC_NANOSECONDS => (false, true),
+            magic if magic == MAGIC_MICROSECONDS.swap_bytes() => (true, false),
+            magic if magic == MAGIC_NANOSECONDS.swap_bytes() => (true, true),
+            _ => return Err(invalid_data("not a pcap capture")),
+        };
+        let link_type = if big_endian {
+            BigEndian::read_u32(&header[20..])
+        } else {
+            LittleEndian::read_u32(&header[20..])
+        };
+        if ![LINKTYPE_RAW, LINKTYPE_IPV4, LINKTYPE_IPV6].contains(&link_type) {
+            return Err(invalid_data("capture does not contain raw IP packets"));
+        }
+
+        Ok(PcapReader {
+            reader,
+            big_endian,
+            nanoseconds,
+        })
+    }
+
+    /// Reads the next UDP datagram, returns `None` at the end of the capture.
+    pub fn read_datagram(&mut self) -> io::Result<Option<CapturedDatagram>> {
+        loop {
+            let mut header = [0; 16];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            let field = |index: usize| {
+                let bytes = &header[index * 4..];
+                if self.big_endian {
+                    BigEndian::read_u32(bytes)
+                } else {
+                    LittleEndian::read_u32(bytes)
+                }
+            };
+            let seconds = u64::from(field(0));
+            let timestamp = if self.nanoseconds {
+                Duration::new(seconds, field(1))
+            } else {
+                Duration::new(seconds, 0) + Duration::from_micros(u64::from(field(1)))
+            };
+
+            let mut packet = vec![0; field(2) as usize];
+            self.reader.read_exact(&mut packet)?;
+            // truncated packets are skipped, their payload is incomplete.
+            if field(2) != field(3) {
+                continue;
+            }
+
+            if let Some((source, destination, payload)) = parse_udp_packet(&packet) {
+                return Ok(Some(CapturedDatagram {
+                    timestamp,
+                    source,
+                    destination,
+                    payload: payload.to_vec(),
+                }));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<CapturedDatagram>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_datagram().transpose()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Wraps the payload into a UDP header and an IP header, an IPv4 address is mapped to IPv6 if the
+// other address is an IPv6 address.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_length = UDP_HEADER_SIZE + payload.len();
+    let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_length);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            packet.write_u8(0x45)?;
+            packet.write_u8(0)?;
+            packet.write_u16::<BigEndian>((IPV4_HEADER_SIZE + udp_length) as u16)?;
+            // identification, flags and fragment offset
+            packet.write_u32::<BigEndian>(0)?;
+            packet.write_u8(TIME_TO_LIVE)?;
+            packet.write_u8(UDP_PROTOCOL)?;
+            packet.write_u16::<BigEndian>(0)?;
+            packet.extend_from_slice(&source_ip.octets());
+            packet.extend_from_slice(&destination_ip.octets());
+
+            let checksum = ipv4_checksum(&packet);
+            BigEndian::write_u16(&mut packet[10..12], checksum);
+        }
+        (source_ip, destination_ip) => {
+            packet.write_u32::<BigEndian>(0x6000_0000)?;
+            packet.write_u16::<BigEndian>(udp_length as u16)?;
+            packet.write_u8(UDP_PROTOCOL)?;
+            packet.write_u8(TIME_TO_LIVE)?;
+            packet.extend_from_slice(&to_ipv6(source_ip).octets());
+            packet.extend_from_slice(&to_ipv6(destination_ip).octets());
+        }
+    }
+
+    packet.write_u16::<BigEndian>(source.port())?;
+    packet.write_u16::<BigEndian>(destination.port())?;
+    packet.write_u16::<BigEndian>(udp_length as u16)?;
+    // the checksum is optional, and there is nothing to verify in a capture
+    packet.write_u16::<BigEndian>(0)?;
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(BigEndian::read_u16(word)))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Returns the addresses and payload of a UDP packet, or `None` if it is not one.
+fn parse_udp_packet(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (source_ip, destination_ip, udp): (IpAddr, IpAddr, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let header_size = usize::from(packet[0] & 0x0f) * 4;
+            if packet.len() < header_size.max(IPV4_HEADER_SIZE) || packet[9] != UDP_PROTOCOL {
+                return None;
+            }
+            let source: [u8; 4] = packet[12..16].try_into().ok()?;
+            let destination: [u8; 4] = packet[16..20].try_into().ok()?;
+            (source.into(), destination.into(), &packet[header_size..])
+        }
+        6 => {
+            if packet.len() < IPV6_HEADER_SIZE || packet[6] != UDP_PROTOCOL {
+                return None;
+            }
+            let source: [u8; 16] = packet[8..24].try_into().ok()?;
+            let destination: [u8; 16] = packet[24..40].try_into().ok()?;
+            (
+                unmap(source.into()),
+                unmap(destination.into()),
+                &packet[IPV6_HEADER_SIZE..],
+            )
+        }
+        _ => return None,
+    };
+
+    if udp.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+    let length = usize::from(BigEndian::read_u16(&udp[4..6]));
+    if length < UDP_HEADER_SIZE || length > udp.len() {
+        return None;
+    }
+
+    Some((
+        SocketAddr::new(source_ip, BigEndian::read_u16(&udp[0..2])),
+        SocketAddr::new(destination_ip, BigEndian::read_u16(&udp[2..4])),
+        &udp[UDP_HEADER_SIZE..length],
+    ))
+}
+
+fn unmap(ip: Ipv6Addr) -> IpAddr {
+    match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ipv4_checksum, CapturedDatagram, PcapReader, PcapWriter};
+
+    fn datagram(source: &str, destination: &str, payload: &[u8]) -> CapturedDatagram {
+        CapturedDatagram {
+            timestamp: Duration::new(1_600_000_000, 123_456_000),
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn datagrams_are_read_back() {
+        let datagrams = vec![
+            datagram("127.0.0.1:12345", "10.0.0.1:80", &[1, 2, 3]),
+            datagram("[::1]:12345", "[fe80::1]:80", &[]),
+            datagram("127.0.0.1:12345", "[fe80::1]:80", &[4; 1000]),
+        ];
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for datagram in &datagrams {
+            writer.write_datagram(datagram).unwrap();
+        }
+        let capture = writer.into_inner();
+
+        let read: Vec<CapturedDatagram> = PcapReader::new(capture.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, datagrams);
+    }
+
+    #[test]
+    fn ipv4_header_has_valid_checksum() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_datagram(&datagram("192.168.0.1:1", "192.168.0.2:2", &[0; 4]))
+            .unwrap();
+        let capture = writer.into_inner();
+
+        // the global header, followed by the record header
+        let ip_header = &capture[24 + 16..24 + 16 + 20];
+        assert_eq!(ip_header[0], 0x45);
+        assert_eq!(ipv4_checksum(ip_header), 0);
+    }
+
+    #[test]
+    fn other_captures_are_rejected() {
+        assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+        assert!(PcapReader::new(&[0xd4, 0xc3, 0xb2, 0xa1][..]).is_err());
+    }
+}
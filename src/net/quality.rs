@@ -1,10 +1,9 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::config::Config;
-use crate::sequence_buffer::CongestionData;
 
 /// Represents the quality of a network.
-#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum NetworkQuality {
     /// Connection is generally good, minimal packet loss or latency.
     Good,
@@ -12,67 +11,150 @@ pub enum NetworkQuality {
     Bad,
 }
 
+/// Statistics about the network conditions of a single connection.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConnectionStats {
+    /// The smoothed round trip time, `None` until the first acknowledgment has been received.
+    pub rtt: Option<Duration>,
+    /// The smoothed variation of the round trip time.
+    pub jitter: Duration,
+    /// The smoothed ratio of sent packets that got lost, from `0.0` to `1.0`.
+    pub packet_loss: f32,
+    /// The number of reliable packets that have not been acknowledged yet.
+    pub packets_in_flight: u16,
+    /// How long we currently wait for an acknowledgment before a reliable packet is resent.
+    pub retransmission_timeout: Duration,
+    /// The number of packets per second we currently send at most, `None` if congestion control
+    /// is disabled.
+    pub send_rate: Option<f32>,
+    /// The number of received encrypted packets that were dropped because they were received
+    /// before, or arrived too late to tell.
+    pub replayed_packets: u64,
+}
+
 /// This type helps with calculating the round trip time from any packet.
 /// It is able to smooth out the network jitter if there is any.
+///
+/// The estimation follows RFC 6298, with `Config::rtt_smoothing_factor` as the weight of a new
+/// round trip time measurement.
 pub struct RttMeasurer {
-    config: Config,
-    rtt: f32,
+    smoothing_factor: f64,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
 }
 
 impl RttMeasurer {
     /// Creates and returns a new RttMeasurer.
     pub fn new(config: &Config) -> RttMeasurer {
         RttMeasurer {
-            config: config.clone(),
-            rtt: 0.,
+            smoothing_factor: f64::from(config.rtt_smoothing_factor),
+            smoothed_rtt: None,
+            rtt_variance: Duration::default(),
         }
     }
 
-    /// Calculates the round trip time (rtt) from the given acknowledgment.
-    /// Whereafter it updates the rtt from the given connection.
-    pub fn calculate_rrt(&mut self, congestion_data: Option<&mut CongestionData>) {
-        self.rtt = self.get_smoothed_rtt(congestion_data);
+    /// Updates the smoothed round trip time (rtt) and its variation with a new measurement.
+    pub fn add_sample(&mut self, rtt: Duration) {
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+            Some(smoothed_rtt) => {
+                let deviation = smoothed_rtt.abs_diff(rtt);
+                self.rtt_variance = self.rtt_variance * 3 / 4 + deviation / 4;
+                self.smoothed_rtt = Some(
+                    smoothed_rtt.mul_f64(1. - self.smoothing_factor)
+                        + rtt.mul_f64(self.smoothing_factor),
+                );
+            }
+        }
     }
 
-    #[cfg(test)]
-    pub fn get_rtt(&self) -> f32 {
-        self.rtt
+    /// Returns the smoothed round trip time, if it has been measured yet.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
     }
 
-    /// This will get the smoothed round trip time (rtt) from the time we last heard from a packet.
-    fn get_smoothed_rtt(&self, congestion_avoidance_entry: Option<&mut CongestionData>) -> f32 {
-        match congestion_avoidance_entry {
-            Some(avoidance_data) => {
-                let elapsed_time = avoidance_data.sending_time.elapsed();
+    /// Returns the smoothed variation of the round trip time, also known as jitter.
+    pub fn rtt_variance(&self) -> Duration {
+        self.rtt_variance
+    }
+}
 
-                let rtt_time = self.as_milliseconds(elapsed_time);
+/// Decides on the quality of a network based on its statistics.
+///
+/// The quality turns bad once the round trip time or packet loss have exceeded
+/// `Config::rtt_max_value` or `Config::packet_loss_max_value` for
+/// `Config::network_quality_degradation_time`. It only turns good again once both have stayed
+/// below `Config::network_quality_hysteresis` times those values for
+/// `Config::network_quality_recovery_time`.
+pub struct QualityMonitor {
+    quality: NetworkQuality,
+    // Since when the statistics are bad enough to degrade from a good quality.
+    degrading_since: Option<Instant>,
+    // Since when the statistics are good enough to recover from a bad quality.
+    recovering_since: Option<Instant>,
+    rtt_max_value: Duration,
+    packet_loss_max_value: f32,
+    hysteresis: f32,
+    recovery_time: Duration,
+    degradation_time: Duration,
+}
 
-                self.smooth_out_rtt(rtt_time)
-            }
-            None => 0.0,
+impl QualityMonitor {
+    /// Creates and returns a new QualityMonitor, which starts off with a good network quality.
+    pub fn new(config: &Config) -> QualityMonitor {
+        QualityMonitor {
+            quality: NetworkQuality::Good,
+            degrading_since: None,
+            recovering_since: None,
+            rtt_max_value: Duration::from_millis(u64::from(config.rtt_max_value)),
+            packet_loss_max_value: config.packet_loss_max_value,
+            hysteresis: config.network_quality_hysteresis,
+            recovery_time: config.network_quality_recovery_time,
+            degradation_time: config.network_quality_degradation_time,
         }
     }
 
-    /// Converts a duration to milliseconds.
-    ///
-    /// `as_milliseconds` is not supported yet supported in rust stable.
-    /// See this stackoverflow post for more info: https://stackoverflow.com/questions/36816072/how-do-i-get-a-duration-as-a-number-of-milliseconds-in-rust
-    fn as_milliseconds(&self, duration: Duration) -> u64 {
-        let nanos = u64::from(duration.subsec_nanos());
-        (1000 * 1000 * 1000 * duration.as_secs() + nanos) / (1000 * 1000)
+    /// Returns the current network quality.
+    pub fn quality(&self) -> NetworkQuality {
+        self.quality
     }
 
-    /// Smooths out round trip time (rtt) value by the specified smoothing factor.
-    ///
-    /// First we subtract the max allowed rtt.
-    /// This way we can see by how many we are off from the max allowed rtt.
-    /// Then we multiply with or smoothing factor.
-    ///
-    /// We do this so that if one packet has an bad rtt it will not directly bring down the or network quality estimation.
-    /// The default is 10% smoothing so if in total or packet is 50 milliseconds later than max allowed rtt we will increase or rtt estimation with 5.
-    fn smooth_out_rtt(&self, rtt: u64) -> f32 {
-        let exceeded_rrt_time = rtt as i64 - i64::from(self.config.rtt_max_value);
-        exceeded_rrt_time as f32 * self.config.rtt_smoothing_factor
+    /// Updates the network quality with the latest statistics, returns the new quality if it changed.
+    pub fn update(&mut self, stats: &ConnectionStats, time: Instant) -> Option<NetworkQuality> {
+        let rtt = stats.rtt.unwrap_or_default();
+
+        match self.quality {
+            NetworkQuality::Good => {
+                if rtt > self.rtt_max_value || stats.packet_loss > self.packet_loss_max_value {
+                    let degrading_since = *self.degrading_since.get_or_insert(time);
+                    if time.saturating_duration_since(degrading_since) >= self.degradation_time {
+                        self.quality = NetworkQuality::Bad;
+                        self.degrading_since = None;
+                        return Some(self.quality);
+                    }
+                } else {
+                    self.degrading_since = None;
+                }
+            }
+            NetworkQuality::Bad => {
+                if rtt <= self.rtt_max_value.mul_f32(self.hysteresis)
+                    && stats.packet_loss <= self.packet_loss_max_value * self.hysteresis
+                {
+                    let recovering_since = *self.recovering_since.get_or_insert(time);
+                    if time.saturating_duration_since(recovering_since) >= self.recovery_time {
+                        self.quality = NetworkQuality::Good;
+                        self.recovering_since = None;
+                        return Some(self.quality);
+                    }
+                } else {
+                    self.recovering_since = None;
+                }
+            }
+        }
+        None
     }
 }
 
@@ -84,11 +166,23 @@ mod test {
     use crate::config::Config;
     use crate::net::VirtualConnection;
 
-    use super::RttMeasurer;
+    use super::{ConnectionStats, NetworkQuality, QualityMonitor, RttMeasurer};
 
     static TEST_HOST_IP: &str = "127.0.0.1";
     static TEST_PORT: &str = "20000";
 
+    fn stats(rtt: u64, packet_loss: f32) -> ConnectionStats {
+        ConnectionStats {
+            rtt: Some(Duration::from_millis(rtt)),
+            jitter: Duration::default(),
+            packet_loss,
+            packets_in_flight: 0,
+            retransmission_timeout: Duration::default(),
+            send_rate: None,
+            replayed_packets: 0,
+        }
+    }
+
     #[test]
     fn test_create_connection() {
         let mut addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT)
@@ -100,14 +194,26 @@ mod test {
 
     #[test]
     fn convert_duration_to_milliseconds_test() {
-        let network_quality = RttMeasurer::new(&Config::default());
-        let milliseconds1 = network_quality.as_milliseconds(Duration::from_secs(1));
-        let milliseconds2 = network_quality.as_milliseconds(Duration::from_millis(1500));
-        let milliseconds3 = network_quality.as_milliseconds(Duration::from_millis(1671));
+        for millis in &[1000, 1500, 1671] {
+            let mut network_quality = RttMeasurer::new(&Config::default());
+            network_quality.add_sample(Duration::from_millis(*millis));
+
+            assert_eq!(
+                network_quality.smoothed_rtt().unwrap().as_millis(),
+                u128::from(*millis)
+            );
+        }
+    }
+
+    #[test]
+    fn first_sample_initializes_rtt() {
+        let mut measurer = RttMeasurer::new(&Config::default());
+        assert_eq!(measurer.smoothed_rtt(), None);
+
+        measurer.add_sample(Duration::from_millis(100));
 
-        assert_eq!(milliseconds1, 1000);
-        assert_eq!(milliseconds2, 1500);
-        assert_eq!(milliseconds3, 1671);
+        assert_eq!(measurer.smoothed_rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(measurer.rtt_variance(), Duration::from_millis(50));
     }
 
     #[test]
@@ -115,14 +221,117 @@ mod test {
         // for test purpose make sure we set smoothing factor to 10%.
         let config = Config {
             rtt_smoothing_factor: 0.10,
-            rtt_max_value: 250,
             ..Default::default()
         };
+        let mut measurer = RttMeasurer::new(&config);
+
+        measurer.add_sample(Duration::from_millis(100));
+        measurer.add_sample(Duration::from_millis(200));
+
+        // a single spike only moves the estimation by 10% of the difference.
+        let rtt = measurer.smoothed_rtt().unwrap();
+        assert!(rtt.abs_diff(Duration::from_millis(110)) < Duration::from_micros(1));
+        assert_eq!(measurer.rtt_variance(), Duration::from_micros(62_500));
+    }
+
+    #[test]
+    fn variance_decreases_on_stable_rtt() {
+        let mut measurer = RttMeasurer::new(&Config::default());
+
+        for _ in 0..100 {
+            measurer.add_sample(Duration::from_millis(50));
+        }
+
+        assert!(measurer.rtt_variance() < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn quality_turns_bad_on_high_rtt_or_loss() {
+        let config = Config::default();
+        let degradation_time = config.network_quality_degradation_time;
+        let time = Instant::now();
+
+        let mut monitor = QualityMonitor::new(&config);
+        assert_eq!(monitor.update(&stats(250, 0.), time), None);
+        assert_eq!(monitor.update(&stats(251, 0.), time), None);
+        assert_eq!(
+            monitor.update(&stats(300, 0.), time + degradation_time),
+            Some(NetworkQuality::Bad)
+        );
+        assert_eq!(
+            monitor.update(&stats(300, 0.), time + degradation_time * 2),
+            None
+        );
+
+        let mut monitor = QualityMonitor::new(&config);
+        assert_eq!(monitor.update(&stats(10, 0.5), time), None);
+        assert_eq!(
+            monitor.update(&stats(10, 0.5), time + degradation_time),
+            Some(NetworkQuality::Bad)
+        );
+        assert_eq!(monitor.quality(), NetworkQuality::Bad);
+    }
+
+    #[test]
+    fn quality_ignores_isolated_loss() {
+        let config = Config::default();
+        let time = Instant::now();
+        let mut monitor = QualityMonitor::new(&config);
+
+        // a single lost packet out of 60 packets per second, the following packets are delivered
+        // and lower the smoothed packet loss by the smoothing factor of 0.1 each.
+        let mut packet_loss = 0.1;
+        for i in 0..60 {
+            let packet_time = time + Duration::from_millis(16 * i);
+            assert_eq!(monitor.update(&stats(50, packet_loss), packet_time), None);
+            packet_loss *= 0.9;
+        }
+        assert_eq!(monitor.quality(), NetworkQuality::Good);
+
+        // a spike that lasts shorter than the degradation time restarts it.
+        let time = time + Duration::from_secs(1);
+        assert_eq!(monitor.update(&stats(300, 0.), time), None);
+        assert_eq!(monitor.update(&stats(50, 0.), time), None);
+        assert_eq!(
+            monitor.update(
+                &stats(300, 0.),
+                time + config.network_quality_degradation_time
+            ),
+            None
+        );
+        assert_eq!(monitor.quality(), NetworkQuality::Good);
+    }
+
+    #[test]
+    fn quality_recovers_with_hysteresis() {
+        let config = Config::default();
+        let recovery_time = config.network_quality_recovery_time;
+        let start = Instant::now();
+        let time = start + config.network_quality_degradation_time;
+        let mut monitor = QualityMonitor::new(&config);
+        monitor.update(&stats(300, 0.), start);
+        monitor.update(&stats(300, 0.), time);
 
-        let network_quality = RttMeasurer::new(&config);
-        let smoothed_rtt = network_quality.smooth_out_rtt(300);
+        // just below the threshold is not good enough to recover.
+        assert_eq!(monitor.update(&stats(240, 0.), time + recovery_time), None);
 
-        // 300ms has exceeded 50ms over the max allowed rtt. So we check if or smoothing factor is now 10% from 50.
-        assert!((smoothed_rtt - 5.0f32).abs() < f32::EPSILON);
+        // a single spike restarts the recovery.
+        assert_eq!(monitor.update(&stats(100, 0.), time), None);
+        assert_eq!(
+            monitor.update(&stats(220, 0.), time + recovery_time / 2),
+            None
+        );
+        assert_eq!(monitor.update(&stats(100, 0.), time + recovery_time), None);
+        assert_eq!(
+            monitor.update(
+                &stats(100, 0.),
+                time + recovery_time * 2 - Duration::from_millis(1)
+            ),
+            None
+        );
+        assert_eq!(
+            monitor.update(&stats(100, 0.), time + recovery_time * 2),
+            Some(NetworkQuality::Good)
+        );
     }
 }
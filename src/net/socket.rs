@@ -1,19 +1,26 @@
 use std::{
     self,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    io::Write,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     thread::{sleep, yield_now},
     time::{Duration, Instant},
 };
 
-use crossbeam_channel::{self, Receiver, Sender, TryRecvError};
+use crossbeam_channel::{self, Receiver, SendError, Sender, TryRecvError};
+use log::error;
 
 use crate::{
     config::Config,
     error::Result,
     net::{
-        events::SocketEvent, ConnectionManager, DatagramSocket, LinkConditioner, VirtualConnection,
+        events::SocketEvent, CaptureSocket, ConnectionManager, ConnectionStats, DatagramSocket,
+        DisconnectReason, DroppedDatagrams, LinkConditioner, SocketCommand, VirtualConnection,
     },
-    packet::Packet,
+    packet::{MessageId, Packet},
 };
 
 // Wraps `LinkConditioner` and `UdpSocket` together. LinkConditioner is enabled when building with a "tester" feature.
@@ -22,6 +29,8 @@ struct SocketWithConditioner {
     is_blocking_mode: bool,
     socket: UdpSocket,
     link_conditioner: Option<LinkConditioner>,
+    // The time of the current poll, packets that pass the link conditioner are sent at this time.
+    time: Option<Instant>,
 }
 
 impl SocketWithConditioner {
@@ -31,6 +40,7 @@ impl SocketWithConditioner {
             is_blocking_mode,
             socket,
             link_conditioner: None,
+            time: None,
         })
     }
 
@@ -38,17 +48,32 @@ impl SocketWithConditioner {
     pub fn set_link_conditioner(&mut self, link_conditioner: Option<LinkConditioner>) {
         self.link_conditioner = link_conditioner;
     }
+
+    // Sends the packets that were held back by the link conditioner and arrived at the given time.
+    fn send_released_packets(&mut self, time: Instant) {
+        if let Some(ref mut link) = &mut self.link_conditioner {
+            while let Some((addr, payload)) = link.next_released(time) {
+                if let Err(err) = self.socket.send_to(&payload, addr) {
+                    error!(
+                        "Error occured sending a delayed packet (to {}): {}",
+                        addr, err
+                    );
+                }
+            }
+        }
+    }
 }
 
 /// Provides a `DatagramSocket` implementation for `SocketWithConditioner`
 impl DatagramSocket for SocketWithConditioner {
-    // Determinate whether packet will be sent or not based on `LinkConditioner` if enabled.
+    // Passes the packet through the `LinkConditioner` if enabled, which drops or delays it.
     fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> std::io::Result<usize> {
         if cfg!(feature = "tester") {
             if let Some(ref mut link) = &mut self.link_conditioner {
-                if !link.should_send() {
-                    return Ok(0);
-                }
+                let time = self.time.unwrap_or_else(Instant::now);
+                link.send(*addr, payload, time);
+                self.send_released_packets(time);
+                return Ok(payload.len());
             }
         }
         self.socket.send_to(payload, addr)
@@ -73,12 +98,51 @@ impl DatagramSocket for SocketWithConditioner {
     fn is_blocking_mode(&self) -> bool {
         self.is_blocking_mode
     }
+
+    /// Sends the packets that were held back by the `LinkConditioner` and arrived by now.
+    fn poll(&mut self, time: Instant) {
+        self.time = Some(time);
+        self.send_released_packets(time);
+    }
+}
+
+/// A thread-safe handle to enqueue packets to a socket, returned by `Socket::get_packet_sender`.
+///
+/// Every packet gets the next `MessageId` of the socket, which `SocketEvent::Acked` and
+/// `SocketEvent::Lost` refer to once the delivery of the packet is known.
+#[derive(Clone, Debug)]
+pub struct PacketSender {
+    sender: Sender<Packet>,
+    next_message_id: Arc<AtomicU64>,
+}
+
+impl PacketSender {
+    /// Creates a packet sender that enqueues the packets to the given channel.
+    pub(crate) fn new(sender: Sender<Packet>) -> Self {
+        PacketSender {
+            sender,
+            next_message_id: Default::default(),
+        }
+    }
+
+    /// Enqueues a single packet, returns the id of the packet. Fails with the packet if the
+    /// socket was dropped.
+    pub fn send(&self, packet: Packet) -> std::result::Result<MessageId, SendError<Packet>> {
+        let message_id = MessageId(self.next_message_id.fetch_add(1, Ordering::Relaxed));
+        self.sender
+            .send(packet.with_message_id(message_id))
+            .map(|()| message_id)
+    }
 }
 
 /// A reliable UDP socket implementation with configurable reliability and ordering guarantees.
 #[derive(Debug)]
 pub struct Socket {
-    handler: ConnectionManager<SocketWithConditioner, VirtualConnection>,
+    handler: ConnectionManager<
+        CaptureSocket<SocketWithConditioner, Box<dyn Write + Send>>,
+        VirtualConnection,
+    >,
+    packet_sender: PacketSender,
 }
 
 impl Socket {
@@ -113,19 +177,31 @@ impl Socket {
     }
 
     fn bind_internal(socket: UdpSocket, config: Config) -> Result<Self> {
+        let handler = ConnectionManager::new(
+            CaptureSocket::new(SocketWithConditioner::new(socket, config.blocking_mode)?),
+            config,
+        );
+        let packet_sender = PacketSender::new(handler.event_sender().clone());
         Ok(Socket {
-            handler: ConnectionManager::new(
-                SocketWithConditioner::new(socket, config.blocking_mode)?,
-                config,
-            ),
+            handler,
+            packet_sender,
         })
     }
 
     /// Returns a handle to the packet sender which provides a thread-safe way to enqueue packets
     /// to be processed. This should be used when the socket is busy running its polling loop in a
     /// separate thread.
-    pub fn get_packet_sender(&self) -> Sender<Packet> {
-        self.handler.event_sender().clone()
+    ///
+    /// The packets share the message ids with the packets sent with `Socket::send`.
+    pub fn get_packet_sender(&self) -> PacketSender {
+        self.packet_sender.clone()
+    }
+
+    /// Returns a handle to the command sender which provides a thread-safe way to enqueue commands,
+    /// like disconnects. This should be used when the socket is busy running its polling loop in a
+    /// separate thread.
+    pub fn get_command_sender(&self) -> Sender<SocketCommand> {
+        self.handler.command_sender().clone()
     }
 
     /// Returns a handle to the event receiver which provides a thread-safe way to retrieve events
@@ -135,13 +211,13 @@ impl Socket {
         self.handler.event_receiver().clone()
     }
 
-    /// Sends a single packet
-    pub fn send(&mut self, packet: Packet) -> Result<()> {
-        self.handler
-            .event_sender()
+    /// Sends a single packet, returns the id that `SocketEvent::Acked` and `SocketEvent::Lost`
+    /// refer to once the delivery of the packet is known.
+    pub fn send(&mut self, packet: Packet) -> Result<MessageId> {
+        Ok(self
+            .packet_sender
             .send(packet)
-            .expect("Receiver must exists.");
-        Ok(())
+            .expect("Receiver must exists."))
     }
 
     /// Receives a single packet
@@ -153,6 +229,69 @@ impl Socket {
         }
     }
 
+    /// Gracefully closes the connection to the given address.
+    ///
+    /// `SocketEvent::Disconnect` is emitted on the next poll. Unacknowledged reliable packets are
+    /// still resent until they are acknowledged or `Config::disconnect_flush_timeout` has passed,
+    /// after which a disconnect packet is sent to the remote host, which emits
+    /// `SocketEvent::Disconnect` as well.
+    ///
+    /// Disconnect packets are only authenticated with `Config::handshake_enabled`. Without the
+    /// handshake, a host that spoofs the address of the remote host can close the connection too.
+    ///
+    /// Use [`SocketCommand::Disconnect`] with the sender returned by `get_command_sender` when the
+    /// socket is busy running its polling loop in a separate thread.
+    pub fn disconnect(&self, addr: SocketAddr) {
+        self.disconnect_with_reason(addr, DisconnectReason::Requested);
+    }
+
+    /// Gracefully closes the connection to the given address, the remote host receives the given reason.
+    /// See [`Socket::disconnect`] for further details.
+    pub fn disconnect_with_reason(&self, addr: SocketAddr, reason: DisconnectReason) {
+        self.handler.disconnect(addr, reason);
+    }
+
+    /// Returns the network statistics of the connection to the given address, like the smoothed
+    /// round trip time, jitter and packet loss. Returns `None` if there is no such connection.
+    pub fn connection_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.handler.connection(&addr).map(VirtualConnection::stats)
+    }
+
+    /// Drops all datagrams received from the given IP address for the given duration, starting
+    /// with the next poll. A connection to the address is not closed, but times out unless the
+    /// ban is lifted with [`Socket::unban`] before. Pass `Duration::MAX` to ban the address
+    /// permanently.
+    ///
+    /// Use [`SocketCommand::Ban`] with the sender returned by `get_command_sender` when the
+    /// socket is busy running its polling loop in a separate thread, likewise for the other
+    /// address filter methods.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.handler.ban(ip, duration);
+    }
+
+    /// Lifts the ban of the given IP address.
+    pub fn unban(&self, ip: IpAddr) {
+        self.handler.unban(ip);
+    }
+
+    /// Exempts the given IP address from `Config::address_rate_limit` and
+    /// `Config::subnet_rate_limit`.
+    pub fn allow(&self, ip: IpAddr) {
+        self.handler.allow(ip);
+    }
+
+    /// Subjects the given IP address to the rate limits again.
+    pub fn remove_allowed(&self, ip: IpAddr) {
+        self.handler.remove_allowed(ip);
+    }
+
+    /// Returns the number of received datagrams that were dropped before they reached a
+    /// connection, because their address was banned or exceeded a rate limit, or because too
+    /// many connections were unestablished.
+    pub fn dropped_datagrams(&self) -> DroppedDatagrams {
+        self.handler.dropped_datagrams()
+    }
+
     /// Runs the polling loop with the default '1ms' sleep duration. This should run in a spawned thread
     /// since calls to `self.manual_poll` are blocking.
     pub fn start_polling(&mut self) {
@@ -182,10 +321,23 @@ impl Socket {
         Ok(self.handler.socket().local_addr()?)
     }
 
+    /// Starts to record every datagram this socket sends and receives to a pcap capture written
+    /// to the given writer, a previous capture is stopped. See [`CaptureSocket`] for further details.
+    pub fn start_capture<W: Write + Send + 'static>(&mut self, writer: W) -> Result<()> {
+        self.handler.socket_mut().start_capture(Box::new(writer))
+    }
+
+    /// Stops the capture and flushes its writer.
+    pub fn stop_capture(&mut self) -> Result<()> {
+        self.handler.socket_mut().stop_capture()?;
+        Ok(())
+    }
+
     /// Sets the link conditioner for this socket. See [LinkConditioner] for further details.
     #[cfg(feature = "tester")]
     pub fn set_link_conditioner(&mut self, link_conditioner: Option<LinkConditioner>) {
         self.handler
+            .socket_mut()
             .socket_mut()
             .set_link_conditioner(link_conditioner);
     }
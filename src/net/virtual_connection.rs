@@ -1,21 +1,40 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error};
 
 use crate::{
     config::Config,
-    error::{ErrorKind, PacketErrorKind, Result},
+    error::{
+        DecodingErrorKind, EncryptionErrorKind, ErrorKind, FragmentErrorKind, PacketErrorKind,
+        Result,
+    },
     infrastructure::{
         arranging::{Arranging, ArrangingSystem, OrderingSystem, SequencingSystem},
-        AcknowledgmentHandler, Fragmentation, SentPacket,
+        AcknowledgmentHandler, Aggregator, CongestionHandler, Fragmentation, ReplayWindow,
+        SentPacket,
     },
-    net::constants::{
-        ACKED_PACKET_HEADER, DEFAULT_ORDERING_STREAM, DEFAULT_SEQUENCING_STREAM,
-        STANDARD_HEADER_SIZE,
+    net::{
+        connect_token::{ConnectToken, CONNECT_TOKEN_SIZE},
+        constants::{
+            ACKED_PACKET_HEADER, DEFAULT_ORDERING_STREAM, DEFAULT_SEQUENCING_STREAM,
+            REPLAY_WINDOW_SIZE, STANDARD_HEADER_SIZE,
+        },
+        encryption::{split_public_key, KeyExchange, PacketCipher, PUBLIC_KEY_SIZE},
+        events::DisconnectReason,
+        handshake::{
+            request_from_bytes, salt_from_bytes, salt_to_bytes, Challenge, ChallengeSigner,
+            HandshakeState, MIN_CONNECTION_REQUEST_SIZE,
+        },
+        ConnectionStats, NetworkQuality, QualityMonitor,
     },
     packet::{
-        DeliveryGuarantee, IncomingPackets, OrderingGuarantee, OutgoingPacketBuilder,
-        OutgoingPackets, Packet, PacketInfo, PacketReader, PacketType, SequenceNumber,
+        header::{AckedPacketHeader, FragmentHeader},
+        DeliveryGuarantee, IncomingPackets, MessageId, OrderingGuarantee, OutgoingPacket,
+        OutgoingPacketBuilder, OutgoingPackets, Packet, PacketInfo, PacketReader, PacketType,
+        SequenceNumber,
     },
 };
 
@@ -32,12 +51,33 @@ pub struct VirtualConnection {
     ever_sent: bool,
     ever_recv: bool,
 
+    handshake: HandshakeState,
+    handshake_signer: ChallengeSigner,
+    // Our key pair of the key exchange, and the keys derived from it once the handshake completed.
+    key_exchange: Option<KeyExchange>,
+    cipher: Option<PacketCipher>,
+    replay_window: ReplayWindow,
+    pending_packets: Vec<Packet>,
+    // User packets waiting for the send rate to allow them to be sent.
+    paced_packets: VecDeque<Packet>,
+    // Since when received packets wait for an outgoing packet to carry their acknowledgment.
+    ack_pending_since: Option<Instant>,
+
+    // The reason and flush deadline of a disconnect that is in progress.
+    disconnecting: Option<(DisconnectReason, Instant)>,
+    closed: bool,
+
     ordering_system: OrderingSystem<(Box<[u8]>, PacketType)>,
     sequencing_system: SequencingSystem<Box<[u8]>>,
+    // Sequence number of the next fragmented packet, which its fragments are reassembled by.
+    fragment_sequence_number: SequenceNumber,
     acknowledge_handler: AcknowledgmentHandler,
+    congestion_handler: CongestionHandler,
+    quality_monitor: QualityMonitor,
 
     config: Config,
     fragmentation: Fragmentation,
+    aggregator: Aggregator,
 }
 
 impl VirtualConnection {
@@ -49,10 +89,24 @@ impl VirtualConnection {
             remote_address: addr,
             ever_sent: false,
             ever_recv: false,
+            handshake: HandshakeState::Idle,
+            handshake_signer: ChallengeSigner::default(),
+            key_exchange: None,
+            cipher: None,
+            replay_window: ReplayWindow::new(REPLAY_WINDOW_SIZE),
+            pending_packets: Vec::new(),
+            paced_packets: VecDeque::new(),
+            ack_pending_since: None,
+            disconnecting: None,
+            closed: false,
             ordering_system: OrderingSystem::new(),
             sequencing_system: SequencingSystem::new(),
+            fragment_sequence_number: 0,
             acknowledge_handler: AcknowledgmentHandler::new(),
+            congestion_handler: CongestionHandler::new(config),
+            quality_monitor: QualityMonitor::new(config),
             fragmentation: Fragmentation::new(config),
+            aggregator: Aggregator::new(config),
             config: config.to_owned(),
         }
     }
@@ -75,9 +129,364 @@ impl VirtualConnection {
         !was_est && self.is_established()
     }
 
-    /// Returns if the connection has been established
+    /// Returns if the connection has been established.
+    ///
+    /// When the handshake is enabled, the connection is established once the handshake completed.
     pub fn is_established(&self) -> bool {
-        self.ever_sent && self.ever_recv
+        if self.config.handshake_enabled {
+            matches!(self.handshake, HandshakeState::Connected { .. })
+        } else {
+            self.ever_sent && self.ever_recv
+        }
+    }
+
+    /// Returns whether the remote host proved that it receives the packets sent to its address by
+    /// completing the handshake. Only the handshake carries a challenge that a host which spoofs
+    /// the address cannot guess.
+    pub fn is_verified(&self) -> bool {
+        self.config.handshake_enabled && self.is_established()
+    }
+
+    /// Starts the handshake if it has not been started yet.
+    /// Returns the connection request that should be sent to the remote host.
+    pub fn start_handshake(&mut self) -> Option<(PacketType, Vec<u8>)> {
+        if self.handshake != HandshakeState::Idle {
+            return None;
+        }
+
+        let salt = rand::random();
+        self.handshake = HandshakeState::Requesting { salt };
+        if self.config.encryption_enabled {
+            self.key_exchange = Some(KeyExchange::new());
+        }
+        Some((PacketType::ConnectionRequest, self.request_payload(salt)))
+    }
+
+    /// Returns the handshake packet that should be resent while we wait for the remote host.
+    pub fn pending_handshake_packet(&self) -> Option<(PacketType, Vec<u8>)> {
+        match self.handshake {
+            HandshakeState::Requesting { salt } => {
+                Some((PacketType::ConnectionRequest, self.request_payload(salt)))
+            }
+            HandshakeState::Responding { challenge } => Some((
+                PacketType::ConnectionResponse,
+                self.with_public_key(challenge.to_bytes()),
+            )),
+            HandshakeState::Idle | HandshakeState::Connected { .. } => None,
+        }
+    }
+
+    /// Returns the challenge the handshake was completed with, once it is completed.
+    pub fn handshake_challenge(&self) -> Option<Challenge> {
+        match self.handshake {
+            HandshakeState::Connected { challenge } => Some(challenge),
+            _ => None,
+        }
+    }
+
+    /// Processes a received handshake packet.
+    /// Returns the handshake packet that should be sent back to the remote host, if any.
+    ///
+    /// A connection in the `Idle` state only receives packets after the `ConnectionManager` accepted
+    /// them, which means the `ConnectionResponse` was already verified by the acceptor.
+    pub fn process_handshake(
+        &mut self,
+        packet_type: PacketType,
+        payload: &[u8],
+        time: Instant,
+    ) -> Option<(PacketType, Vec<u8>)> {
+        // when encryption is enabled, every handshake packet but the challenge carries a public key.
+        let (payload, remote_public_key) = match packet_type {
+            PacketType::ConnectionChallenge => (payload, None),
+            _ if self.config.encryption_enabled => {
+                let (payload, public_key) = split_public_key(payload)?;
+                (payload, Some(public_key))
+            }
+            _ => (payload, None),
+        };
+
+        match packet_type {
+            PacketType::ConnectionRequest => {
+                let (salt, connect_token) = request_from_bytes(payload)?;
+                // the acceptor verifies the tokens of unknown addresses, but a request also reaches
+                // a connection that we created by sending to the remote host first.
+                if let Some(ref private_key) = self.config.connect_token_key {
+                    let token = connect_token
+                        .get(..CONNECT_TOKEN_SIZE)
+                        .and_then(ConnectToken::from_bytes)?;
+                    if !token.is_valid(private_key, SystemTime::now()) {
+                        return None;
+                    }
+                }
+                let challenge = self.handshake_signer.issue(self.remote_address, salt, time);
+                Some((PacketType::ConnectionChallenge, challenge.to_bytes()))
+            }
+            PacketType::ConnectionChallenge => {
+                let challenge = Challenge::from_bytes(payload)?;
+                match self.handshake {
+                    HandshakeState::Requesting { salt } if salt == challenge.salt() => {
+                        self.handshake = HandshakeState::Responding { challenge };
+                        Some((
+                            PacketType::ConnectionResponse,
+                            self.with_public_key(challenge.to_bytes()),
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+            PacketType::ConnectionResponse => {
+                let challenge = Challenge::from_bytes(payload)?;
+                match self.handshake {
+                    HandshakeState::Idle | HandshakeState::Connected { .. } => {}
+                    HandshakeState::Requesting { .. } | HandshakeState::Responding { .. } => {
+                        if !self.handshake_signer.verify(
+                            self.remote_address,
+                            &challenge,
+                            time,
+                            self.config.handshake_challenge_lifetime,
+                        ) {
+                            return None;
+                        }
+                    }
+                }
+                if let Some(public_key) = remote_public_key {
+                    // a resent response is answered with the key we already derived our keys with.
+                    if self.cipher.is_none() {
+                        self.cipher = Some(self.derive_cipher(public_key)?);
+                    }
+                }
+                // a resent response must not replace the challenge the handshake completed with.
+                if self.handshake_challenge().is_none() {
+                    self.handshake = HandshakeState::Connected { challenge };
+                }
+                Some((
+                    PacketType::ConnectionAccepted,
+                    self.with_public_key(salt_to_bytes(challenge.salt())),
+                ))
+            }
+            PacketType::ConnectionAccepted => {
+                if let HandshakeState::Responding { challenge } = self.handshake {
+                    if salt_from_bytes(payload) == Some(challenge.salt()) {
+                        if let Some(public_key) = remote_public_key {
+                            self.cipher = Some(self.derive_cipher(public_key)?);
+                        }
+                        self.handshake = HandshakeState::Connected { challenge };
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Completes the handshake when the remote host already sends data while we are waiting for
+    /// its `ConnectionAccepted`, which happens if that packet got lost.
+    /// Returns whether the connection has become established because of this.
+    ///
+    /// This never happens with encryption enabled, as the keys can only be derived from the
+    /// `ConnectionAccepted`.
+    pub fn accept_implicitly(&mut self) -> bool {
+        if self.config.encryption_enabled {
+            return false;
+        }
+        if let HandshakeState::Responding { challenge } = self.handshake {
+            self.handshake = HandshakeState::Connected { challenge };
+            return true;
+        }
+        false
+    }
+
+    // Returns the payload of our connection request: the salt, the connect token, the padding and
+    // our public key.
+    fn request_payload(&self, salt: u64) -> Vec<u8> {
+        let mut payload = salt_to_bytes(salt);
+        if let Some(ref connect_token) = self.config.connect_token {
+            payload.extend_from_slice(connect_token);
+        }
+
+        let public_key_size = if self.key_exchange.is_some() {
+            PUBLIC_KEY_SIZE
+        } else {
+            0
+        };
+        let padded_size = MIN_CONNECTION_REQUEST_SIZE
+            .saturating_sub(STANDARD_HEADER_SIZE as usize + public_key_size);
+        if payload.len() < padded_size {
+            payload.resize(padded_size, 0);
+        }
+        self.with_public_key(payload)
+    }
+
+    // Appends our public key to a handshake payload, once we generated a key pair for the key
+    // exchange because encryption is enabled.
+    fn with_public_key(&self, mut payload: Vec<u8>) -> Vec<u8> {
+        if let Some(ref key_exchange) = self.key_exchange {
+            payload.extend_from_slice(&key_exchange.public_key());
+        }
+        payload
+    }
+
+    // Derives the keys of the connection from the public key of the remote host. Both hosts may
+    // have sent a connection request at the same time, so the host with the lower public key takes
+    // the client role instead of the one that started the handshake.
+    fn derive_cipher(&mut self, remote_public_key: [u8; 32]) -> Option<PacketCipher> {
+        let key_exchange = self.key_exchange.get_or_insert_with(KeyExchange::new);
+        let is_client = key_exchange.public_key() < remote_public_key;
+        key_exchange.derive_cipher(
+            remote_public_key,
+            self.config.encryption_key.as_ref(),
+            is_client,
+        )
+    }
+
+    /// Encrypts an encoded packet before it is sent, see `Config::encryption_enabled`.
+    /// Returns `None` if the packet is sent as it is, which handshake packets always are.
+    pub fn encrypt_outgoing(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.config.encryption_enabled
+            || PacketReader::new(packet)
+                .read_standard_header()?
+                .packet_type()
+                .is_handshake()
+        {
+            return Ok(None);
+        }
+
+        match self.cipher {
+            Some(ref mut cipher) => Ok(Some(cipher.encrypt(packet)?)),
+            None => Err(EncryptionErrorKind::MissingKeys.into()),
+        }
+    }
+
+    // Decrypts a received packet, returns its packet number along with the decrypted packet, or
+    // `None` if the packet was not encrypted. Only handshake packets and the aggregate datagrams
+    // around encrypted packets are accepted unencrypted when encryption is enabled.
+    fn decrypt_incoming(&self, packet: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        let header = PacketReader::new(packet).read_standard_header()?;
+        if header.is_encrypted() {
+            let cipher = self
+                .cipher
+                .as_ref()
+                .ok_or(EncryptionErrorKind::MissingKeys)?;
+            return Ok(Some(cipher.decrypt(packet)?));
+        }
+
+        if self.config.encryption_enabled
+            && !header.packet_type().is_handshake()
+            && !header.is_aggregate()
+        {
+            return Err(EncryptionErrorKind::UnencryptedPacket.into());
+        }
+        Ok(None)
+    }
+
+    /// Queues a packet that will be sent once the handshake has been completed.
+    pub fn queue_pending_packet(&mut self, packet: Packet) {
+        self.pending_packets.push(packet);
+    }
+
+    /// Takes the packets that were queued while the handshake was in progress.
+    pub fn take_pending_packets(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.pending_packets)
+    }
+
+    /// Starts to disconnect from the remote host, unacknowledged reliable packets will be flushed until `deadline`.
+    /// Returns false if the connection is already disconnecting or closed.
+    pub fn disconnect(&mut self, reason: DisconnectReason, deadline: Instant) -> bool {
+        if self.disconnecting.is_some() || self.closed {
+            return false;
+        }
+
+        self.pending_packets.clear();
+        self.disconnecting = Some((reason, deadline));
+        true
+    }
+
+    /// Returns if the connection is disconnecting or closed, no user packets are sent or received anymore.
+    pub fn is_disconnecting(&self) -> bool {
+        self.disconnecting.is_some() || self.closed
+    }
+
+    /// Returns the reason of the disconnect once all reliable packets have been acknowledged or
+    /// the flush deadline has passed.
+    pub fn flushed_disconnect(&self, time: Instant) -> Option<DisconnectReason> {
+        match self.disconnecting {
+            Some((reason, deadline)) if !self.closed => {
+                if (self.packets_in_flight() == 0 && self.paced_packets.is_empty())
+                    || time >= deadline
+                {
+                    Some(reason)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Marks the connection as closed, it will be dropped without further events.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Returns if the connection has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns the measured network statistics of this connection, like the round trip time and
+    /// packet loss.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            replayed_packets: self.replay_window.dropped_packets(),
+            ..self
+                .congestion_handler
+                .stats(self.acknowledge_handler.packets_in_flight())
+        }
+    }
+
+    /// Returns the current network quality of this connection.
+    pub fn quality(&self) -> NetworkQuality {
+        self.quality_monitor.quality()
+    }
+
+    /// Updates the network quality with the latest statistics, returns the new quality if it changed.
+    pub fn update_quality(&mut self, time: Instant) -> Option<NetworkQuality> {
+        let stats = self.stats();
+        self.quality_monitor.update(&stats, time)
+    }
+
+    /// Returns whether outgoing user packets are paced according to the send rate.
+    pub fn is_pacing(&self) -> bool {
+        self.congestion_handler.is_pacing()
+    }
+
+    /// Adapts the send rate to the current network conditions.
+    pub fn update_send_rate(&mut self, time: Instant) {
+        let quality = self.quality();
+        self.congestion_handler.update_send_rate(quality, time);
+    }
+
+    /// Queues a user packet until the send rate allows it to be sent.
+    /// Returns false if the packet was dropped because `Config::max_paced_packets` are queued already.
+    pub fn queue_paced_packet(&mut self, packet: Packet) -> bool {
+        if self.paced_packets.len() >= self.config.max_paced_packets {
+            return false;
+        }
+        self.paced_packets.push_back(packet);
+        true
+    }
+
+    /// Returns the next queued user packet, if the send rate allows it to be sent now.
+    pub fn next_paced_packet(&mut self, time: Instant) -> Option<Packet> {
+        if self.paced_packets.is_empty() || !self.congestion_handler.can_send(time) {
+            return None;
+        }
+        self.paced_packets.pop_front()
+    }
+
+    /// Records the number of datagrams that were sent, which count against the send rate.
+    pub fn record_sent_datagrams(&mut self, datagrams: usize) {
+        self.congestion_handler.process_sent(datagrams);
     }
 
     /// Returns the current number of not yet acknowledged packets
@@ -110,14 +519,35 @@ impl VirtualConnection {
         match packet.delivery {
             DeliveryGuarantee::Unreliable => {
                 if packet.payload.len() <= self.config.receive_buffer_max_size {
-                    if packet.packet_type == PacketType::Heartbeat {
-                        // TODO: implement congestion control.
-                        // self.congestion_handler
-                        //     .process_outgoing(self.acknowledge_handler.local_sequence_num(), time);
-                    }
+                    // handshake and disconnect packets are sent before and after the connection
+                    // exchanges acknowledgments, so they never carry them.
+                    let acknowledged = self.config.ack_unreliable_packets
+                        && matches!(
+                            packet.packet_type,
+                            PacketType::Packet | PacketType::Heartbeat
+                        );
 
-                    let mut builder = OutgoingPacketBuilder::new(packet.payload)
-                        .with_default_header(packet.packet_type, packet.delivery, packet.ordering);
+                    let mut builder = OutgoingPacketBuilder::new(packet.payload);
+                    if acknowledged {
+                        builder = builder
+                            .with_acknowledged_header(packet.packet_type, packet.ordering)
+                            .with_acknowledgment_header(
+                                self.acknowledge_handler.local_sequence_num(),
+                                self.acknowledge_handler.remote_sequence_num(),
+                                self.acknowledge_handler.ack_bitfield(),
+                            );
+                        self.congestion_handler
+                            .process_outgoing(self.acknowledge_handler.local_sequence_num(), time);
+                        self.acknowledge_handler
+                            .process_outgoing_unreliable(packet.message_id, time);
+                        self.ack_pending_since = None;
+                    } else {
+                        builder = builder.with_default_header(
+                            packet.packet_type,
+                            packet.delivery,
+                            packet.ordering,
+                        );
+                    }
 
                     if let OrderingGuarantee::Sequenced(stream_id) = packet.ordering {
                         let item_identifier = self
@@ -192,67 +622,145 @@ impl VirtualConnection {
                         if packet.packet_type != PacketType::Packet {
                             return Err(PacketErrorKind::PacketCannotBeFragmented.into());
                         }
-                        OutgoingPackets::many(
-                            Fragmentation::spit_into_fragments(packet.payload, &self.config)?
-                                .into_iter()
-                                .enumerate()
-                                .map(|(fragment_id, fragment)| {
-                                    let fragments_needed = Fragmentation::fragments_needed(
-                                        payload_length,
-                                        self.config.fragment_size,
-                                    )
-                                        as u8;
-
-                                    let mut builder = OutgoingPacketBuilder::new(fragment)
-                                        .with_default_header(
-                                            PacketType::Fragment, // change from Packet to Fragment type, it only matters when assembling/dissasembling packet header.
-                                            packet.delivery,
-                                            packet.ordering,
-                                        );
-
-                                    builder = builder.with_fragment_header(
-                                        self.acknowledge_handler.local_sequence_num(),
-                                        fragment_id as u8,
-                                        fragments_needed,
-                                    );
-
-                                    if fragment_id == 0 {
-                                        builder = builder.with_acknowledgment_header(
-                                            self.acknowledge_handler.local_sequence_num(),
-                                            self.acknowledge_handler.remote_sequence_num(),
-                                            self.acknowledge_handler.ack_bitfield(),
-                                        );
-                                    }
-
-                                    builder.build()
-                                })
-                                .collect(),
-                        )
+                        return self.process_outgoing_fragments(packet, time);
                     }
                 };
 
-                // TODO: implement congestion control.
-                // self.congestion_handler
-                //     .process_outgoing(self.acknowledge_handler.local_sequence_num(), time);
+                self.congestion_handler
+                    .process_outgoing(self.acknowledge_handler.local_sequence_num(), time);
 
                 self.acknowledge_handler.process_outgoing(
                     packet.packet_type,
                     packet.payload,
                     packet.ordering,
                     item_identifier_value,
+                    packet.message_id,
+                    time,
                 );
+                self.ack_pending_since = None;
 
                 Ok(outgoing)
             }
         }
     }
 
+    /// Pre-processes a dropped packet to be resent over the network. A dropped fragment is resent on
+    /// its own, without the other fragments of its packet.
+    pub fn process_dropped<'a>(
+        &mut self,
+        dropped: &'a SentPacket,
+        time: Instant,
+    ) -> Result<OutgoingPackets<'a>> {
+        match dropped.fragment {
+            Some(fragment) => {
+                self.last_sent = time;
+                let outgoing = self.process_outgoing_fragment(
+                    &dropped.payload,
+                    fragment,
+                    dropped.ordering_guarantee,
+                    dropped.message_id,
+                    time,
+                );
+                self.ack_pending_since = None;
+                Ok(OutgoingPackets::one(outgoing))
+            }
+            None => self.process_outgoing(
+                PacketInfo {
+                    packet_type: dropped.packet_type,
+                    payload: &dropped.payload,
+                    // because a delivery guarantee is only sent with reliable packets
+                    delivery: DeliveryGuarantee::Reliable,
+                    // this is stored with the dropped packet because they could be mixed
+                    ordering: dropped.ordering_guarantee,
+                    message_id: dropped.message_id,
+                },
+                dropped.item_identifier,
+                time,
+            ),
+        }
+    }
+
+    // Splits the packet into fragments, every fragment is acknowledged and resent on its own.
+    fn process_outgoing_fragments<'a>(
+        &mut self,
+        packet: PacketInfo<'a>,
+        time: Instant,
+    ) -> Result<OutgoingPackets<'a>> {
+        let fragments = Fragmentation::spit_into_fragments(packet.payload, &self.config)?;
+        let fragment_count = fragments.len() as u8;
+        let sequence = self.fragment_sequence_number;
+        self.fragment_sequence_number = self.fragment_sequence_number.wrapping_add(1);
+
+        let outgoing = fragments
+            .into_iter()
+            .enumerate()
+            .map(|(fragment_id, fragment)| {
+                self.process_outgoing_fragment(
+                    fragment,
+                    FragmentHeader::new(sequence, fragment_id as u8, fragment_count),
+                    packet.ordering,
+                    packet.message_id,
+                    time,
+                )
+            })
+            .collect();
+        self.ack_pending_since = None;
+
+        Ok(OutgoingPackets::many(outgoing))
+    }
+
+    // Builds a single fragment and enqueues it for acknowledgment.
+    fn process_outgoing_fragment<'a>(
+        &mut self,
+        payload: &'a [u8],
+        fragment: FragmentHeader,
+        ordering: OrderingGuarantee,
+        message_id: Option<MessageId>,
+        time: Instant,
+    ) -> OutgoingPacket<'a> {
+        let sequence = self.acknowledge_handler.local_sequence_num();
+        let outgoing = OutgoingPacketBuilder::new(payload)
+            .with_default_header(
+                PacketType::Fragment, // change from Packet to Fragment type, it only matters when assembling/dissasembling packet header.
+                DeliveryGuarantee::Reliable,
+                ordering,
+            )
+            .with_fragment_header(
+                fragment.sequence(),
+                fragment.id(),
+                fragment.fragment_count(),
+            )
+            .with_acknowledgment_header(
+                sequence,
+                self.acknowledge_handler.remote_sequence_num(),
+                self.acknowledge_handler.ack_bitfield(),
+            )
+            .build();
+
+        self.congestion_handler.process_outgoing(sequence, time);
+        self.acknowledge_handler
+            .process_outgoing_fragment(fragment, payload, ordering, message_id, time);
+
+        outgoing
+    }
+
     /// Processes the incoming data and returns a packet once the data is complete.
     pub fn process_incoming(
         &mut self,
         received_data: &[u8],
         time: Instant,
     ) -> Result<IncomingPackets> {
+        let decrypted = match self.decrypt_incoming(received_data)? {
+            Some((packet_number, decrypted)) => {
+                if !self.replay_window.accept(packet_number) {
+                    // replayed packets are dropped silently, before they affect any state.
+                    return Ok(IncomingPackets::zero());
+                }
+                Some(decrypted)
+            }
+            None => None,
+        };
+        let received_data = decrypted.as_deref().unwrap_or(received_data);
         self.last_heard = time;
 
         let mut packet_reader = PacketReader::new(received_data);
@@ -263,17 +771,68 @@ impl VirtualConnection {
             return Err(ErrorKind::ProtocolVersionMismatch);
         }
 
+        if header.is_aggregate() {
+            let mut packets = VecDeque::new();
+            for data in packet_reader.read_aggregated_packets()? {
+                // a malformed packet does not affect the other packets of the datagram.
+                match self.process_aggregated_packet(data, time) {
+                    Ok(incoming) => packets.extend(incoming),
+                    Err(ErrorKind::FragmentError(FragmentErrorKind::AlreadyProcessedFragment)) => {
+                        debug!("Dropped an aggregated fragment that was already received")
+                    }
+                    Err(err) => error!("Error occured processing aggregated packet: {:?}", err),
+                }
+            }
+            return Ok(IncomingPackets::many(packets));
+        }
+
         if header.is_heartbeat() {
             // heartbeat packets are unreliable, unordered and empty packets.
-            // we already updated our `self.last_heard` time, nothing else to be done.
+            // we already updated our `self.last_heard` time, only acknowledgments are left to process.
+            if header.is_acknowledged() {
+                let acked_header = packet_reader.read_acknowledge_header()?;
+                self.process_acknowledgment(&acked_header, time);
+            }
+            return Ok(IncomingPackets::zero());
+        }
+
+        if header.is_ack() {
+            let ack_header = packet_reader.read_ack_header()?;
+            self.congestion_handler.process_incoming(
+                ack_header.ack_seq(),
+                ack_header.ack_field(),
+                time,
+            );
+            self.acknowledge_handler
+                .process_acknowledgments(ack_header.ack_seq(), ack_header.ack_field());
             return Ok(IncomingPackets::zero());
         }
 
+        if header.packet_type().is_handshake() || header.is_disconnect() {
+            // control packets are unreliable and unordered, their payload is handled by the connection.
+            return Ok(IncomingPackets::one(
+                Packet::new(
+                    self.remote_address,
+                    packet_reader.read_payload(),
+                    DeliveryGuarantee::Unreliable,
+                    OrderingGuarantee::None,
+                ),
+                header.packet_type(),
+            ));
+        }
+
         match header.delivery_guarantee() {
             DeliveryGuarantee::Unreliable => {
+                let mut arranging_header_offset = STANDARD_HEADER_SIZE;
+                if header.is_acknowledged() {
+                    let acked_header = packet_reader.read_acknowledge_header()?;
+                    self.process_acknowledgment(&acked_header, time);
+                    arranging_header_offset += ACKED_PACKET_HEADER;
+                }
+
                 if let OrderingGuarantee::Sequenced(_id) = header.ordering_guarantee() {
                     let arranging_header =
-                        packet_reader.read_arranging_header(u16::from(STANDARD_HEADER_SIZE))?;
+                        packet_reader.read_arranging_header(u16::from(arranging_header_offset))?;
 
                     let payload = packet_reader.read_payload();
 
@@ -309,24 +868,15 @@ impl VirtualConnection {
             DeliveryGuarantee::Reliable => {
                 if header.is_fragment() {
                     if let Ok((fragment_header, acked_header)) = packet_reader.read_fragment() {
+                        // every fragment is acknowledged, whether it completes the packet or not.
+                        self.process_acknowledgment(&acked_header, time);
                         let payload = packet_reader.read_payload();
 
-                        match self.fragmentation.handle_fragment(
-                            fragment_header,
-                            &payload,
-                            acked_header,
-                        ) {
-                            Ok(Some((payload, acked_header))) => {
-                                // TODO: implement congestion control.
-                                // self.congestion_handler
-                                //     .process_incoming(acked_header.sequence());
-
-                                self.acknowledge_handler.process_incoming(
-                                    acked_header.sequence(),
-                                    acked_header.ack_seq(),
-                                    acked_header.ack_field(),
-                                );
-
+                        match self
+                            .fragmentation
+                            .handle_fragment(fragment_header, &payload)
+                        {
+                            Ok(Some(payload)) => {
                                 return Ok(IncomingPackets::one(
                                     Packet::new(
                                         self.remote_address,
@@ -343,16 +893,7 @@ impl VirtualConnection {
                     }
                 } else {
                     let acked_header = packet_reader.read_acknowledge_header()?;
-
-                    // TODO: implement congestion control.
-                    // self.congestion_handler
-                    //     .process_incoming(acked_header.sequence());
-
-                    self.acknowledge_handler.process_incoming(
-                        acked_header.sequence(),
-                        acked_header.ack_seq(),
-                        acked_header.ack_field(),
-                    );
+                    self.process_acknowledgment(&acked_header, time);
 
                     if let OrderingGuarantee::Sequenced(_) = header.ordering_guarantee() {
                         let arranging_header = packet_reader.read_arranging_header(u16::from(
@@ -432,11 +973,108 @@ impl VirtualConnection {
         Ok(IncomingPackets::zero())
     }
 
+    /// Returns the message ids of the packets that got acknowledged since the last call.
+    pub fn take_acked_messages(&mut self) -> Vec<MessageId> {
+        self.acknowledge_handler.take_acked_messages()
+    }
+
+    /// Gathers the message ids of unreliable packets that are considered lost.
+    ///
+    /// Only unreliable packets sent while `Config::ack_unreliable_packets` is enabled can be lost,
+    /// reliable packets are resent instead. Every lost packet counts towards the packet loss,
+    /// also the ones without a message id.
+    pub fn gather_lost_messages(&mut self, time: Instant) -> Vec<MessageId> {
+        let timeout = self.congestion_handler.retransmission_timeout();
+        let lost = self
+            .acknowledge_handler
+            .lost_unreliable_packets(time, timeout);
+
+        // lost unreliable packets are not resent, so there is no reason to back off.
+        self.congestion_handler
+            .process_dropped(lost.len(), false, time);
+
+        lost.into_iter()
+            .filter_map(|packet| packet.message_id)
+            .collect()
+    }
+
+    /// Queues an encoded packet to be sent with the other packets of this poll, see
+    /// `Config::aggregate_packets`.
+    pub fn aggregate_packet(&mut self, packet: &[u8]) {
+        self.aggregator.push(packet);
+    }
+
+    /// Returns the datagrams that contain the packets aggregated since the last call.
+    pub fn take_aggregated_datagrams(&mut self) -> Vec<Box<[u8]>> {
+        self.aggregator.take_datagrams()
+    }
+
+    // Processes a packet of an aggregate datagram, aggregate datagrams are never nested.
+    fn process_aggregated_packet(&mut self, data: &[u8], time: Instant) -> Result<IncomingPackets> {
+        if PacketReader::new(data)
+            .read_standard_header()?
+            .is_aggregate()
+        {
+            return Err(ErrorKind::DecodingError(DecodingErrorKind::PacketType));
+        }
+        self.process_incoming(data, time)
+    }
+
+    /// Returns an ack-only packet once received packets waited `Config::ack_delay` for an
+    /// outgoing packet to carry their acknowledgment.
+    pub fn pending_ack_packet(&mut self, time: Instant) -> Option<OutgoingPacket<'static>> {
+        let ack_delay = self.config.ack_delay?;
+        let pending_since = self.ack_pending_since?;
+        if time.saturating_duration_since(pending_since) < ack_delay {
+            return None;
+        }
+
+        self.ack_pending_since = None;
+        self.last_sent = time;
+        Some(
+            OutgoingPacketBuilder::new(&[])
+                .with_default_header(
+                    PacketType::Ack,
+                    DeliveryGuarantee::Unreliable,
+                    OrderingGuarantee::None,
+                )
+                .with_ack_header(
+                    self.acknowledge_handler.remote_sequence_num(),
+                    self.acknowledge_handler.ack_bitfield(),
+                )
+                .build(),
+        )
+    }
+
+    // Processes the acknowledgment information the remote host sent along with a packet.
+    fn process_acknowledgment(&mut self, acked_header: &AckedPacketHeader, time: Instant) {
+        self.ack_pending_since.get_or_insert(time);
+        self.congestion_handler.process_incoming(
+            acked_header.ack_seq(),
+            acked_header.ack_field(),
+            time,
+        );
+        self.acknowledge_handler.process_incoming(
+            acked_header.sequence(),
+            acked_header.ack_seq(),
+            acked_header.ack_field(),
+        );
+    }
+
     /// Gathers dropped packets from the acknowledgment handler.
     ///
     /// Note that after requesting dropped packets the dropped packets will be removed from this client.
-    pub fn gather_dropped_packets(&mut self) -> Vec<SentPacket> {
-        self.acknowledge_handler.dropped_packets()
+    pub fn gather_dropped_packets(&mut self, time: Instant) -> Vec<SentPacket> {
+        let timeout = self.congestion_handler.retransmission_timeout();
+        let dropped = self.acknowledge_handler.dropped_packets(time, timeout);
+
+        let timed_out = dropped
+            .iter()
+            .any(|packet| time.saturating_duration_since(packet.sent_time) >= timeout);
+        self.congestion_handler
+            .process_dropped(dropped.len(), timed_out, time);
+
+        dropped
     }
 }
 
@@ -458,11 +1096,13 @@ mod tests {
 
     use byteorder::{BigEndian, WriteBytesExt};
 
-    use crate::PROTOCOL_VERSION;
     use crate::config::Config;
+    use crate::net::connect_token::ConnectToken;
     use crate::net::constants;
+    use crate::net::handshake::salt_to_bytes;
     use crate::packet::header::{AckedPacketHeader, ArrangingHeader, HeaderWriter, StandardHeader};
     use crate::packet::{DeliveryGuarantee, OrderingGuarantee, Packet, PacketInfo, PacketType};
+    use crate::PROTOCOL_VERSION;
 
     use super::VirtualConnection;
 
@@ -510,17 +1150,22 @@ mod tests {
 
         let standard_header = [protocol_version, vec![1, 1, 2]].concat();
 
+        // every fragment header is followed by the acknowledgment header of the fragment
         let acked_header = vec![0, 0, 0, 4, 0, 0, 255, 255, 0, 0, 0, 0];
-        let first_fragment = vec![0, 0, 1, 4];
-        let second_fragment = vec![0, 0, 2, 4];
-        let third_fragment = vec![0, 0, 3, 4];
+        let first_fragment = vec![0, 0, 1, 4, 0, 1, 255, 255, 0, 0, 0, 0];
+        let second_fragment = vec![0, 0, 2, 4, 0, 2, 255, 255, 0, 0, 0, 0];
+        let third_fragment = vec![0, 0, 3, 4, 0, 3, 255, 255, 0, 0, 0, 0];
 
         let mut connection = create_virtual_connection();
         let packet = connection
             .process_incoming(
-                [standard_header.as_slice(), acked_header.as_slice()]
-                    .concat()
-                    .as_slice(),
+                [
+                    standard_header.as_slice(),
+                    acked_header.as_slice(),
+                    &PAYLOAD,
+                ]
+                .concat()
+                .as_slice(),
                 Instant::now(),
             )
             .unwrap()
@@ -574,7 +1219,9 @@ mod tests {
             .unwrap();
         assert_eq!(
             packets.payload(),
-            &*[PAYLOAD, PAYLOAD, PAYLOAD].concat().into_boxed_slice()
+            &*[PAYLOAD, PAYLOAD, PAYLOAD, PAYLOAD]
+                .concat()
+                .into_boxed_slice()
         );
     }
 
@@ -1021,8 +1668,9 @@ mod tests {
 
     #[test]
     fn fragmentation_send_returns_right_size() {
-        let fragment_packet_size =
-            constants::STANDARD_HEADER_SIZE + constants::FRAGMENT_HEADER_SIZE;
+        let fragment_packet_size = constants::STANDARD_HEADER_SIZE
+            + constants::FRAGMENT_HEADER_SIZE
+            + constants::ACKED_PACKET_HEADER;
 
         let mut connection = create_virtual_connection();
         let buffer = vec![1; 4000];
@@ -1040,10 +1688,10 @@ mod tests {
             .unwrap()
             .into_iter();
 
-        // the first fragment of an sequence of fragments contains also the acknowledgment header.
+        // every fragment contains the acknowledgment header.
         assert_eq!(
             packets.fold(0, |acc, p| acc + p.contents().len()),
-            4000 + (fragment_packet_size * 4 + constants::ACKED_PACKET_HEADER) as usize
+            4000 + (fragment_packet_size * 4) as usize
         );
     }
 
@@ -1085,4 +1733,37 @@ mod tests {
         }
         assert_eq![last_recv_value, 99_999];
     }
+
+    #[test]
+    fn connection_request_without_connect_token_is_ignored() {
+        const KEY: [u8; 32] = [5; 32];
+        let config = Config {
+            handshake_enabled: true,
+            connect_token_key: Some(KEY),
+            ..Config::default()
+        };
+        let mut connection = VirtualConnection::new(get_fake_addr(), &config, Instant::now());
+        let token = ConnectToken::with_lifetime(&KEY, 1, Duration::from_secs(30));
+        let forged = ConnectToken::with_lifetime(&[6; 32], 1, Duration::from_secs(30));
+
+        for request in [
+            salt_to_bytes(1),
+            [salt_to_bytes(1), forged.to_bytes()].concat(),
+        ] {
+            let answer = connection.process_handshake(
+                PacketType::ConnectionRequest,
+                &request,
+                Instant::now(),
+            );
+            assert_eq!(answer, None);
+        }
+
+        let request = [salt_to_bytes(1), token.to_bytes()].concat();
+        let answer =
+            connection.process_handshake(PacketType::ConnectionRequest, &request, Instant::now());
+        assert_eq!(
+            answer.map(|(packet_type, _)| packet_type),
+            Some(PacketType::ConnectionChallenge)
+        );
+    }
 }
@@ -3,7 +3,7 @@
 pub use self::enums::{DeliveryGuarantee, OrderingGuarantee, PacketType};
 pub use self::outgoing::{OutgoingPacket, OutgoingPacketBuilder};
 pub use self::packet_reader::PacketReader;
-pub use self::packet_structure::{Packet, PacketInfo};
+pub use self::packet_structure::{MessageId, Packet, PacketInfo};
 pub use self::process_result::{IncomingPackets, OutgoingPackets};
 
 pub mod header;
@@ -86,6 +86,33 @@ pub enum PacketType {
     Fragment = 1,
     /// Heartbeat packet
     Heartbeat = 2,
+    /// Handshake packet requesting a new connection
+    ConnectionRequest = 3,
+    /// Handshake packet challenging the remote host to prove it owns its address
+    ConnectionChallenge = 4,
+    /// Handshake packet answering a challenge
+    ConnectionResponse = 5,
+    /// Handshake packet confirming that the connection has been accepted
+    ConnectionAccepted = 6,
+    /// Packet notifying the remote host that the connection is closed
+    Disconnect = 7,
+    /// Packet that only acknowledges received packets, sent when there is nothing to piggyback on
+    Ack = 8,
+    /// Datagram that contains multiple packets
+    Aggregate = 9,
+}
+
+impl PacketType {
+    /// Returns true if this is one of the handshake packet types.
+    pub fn is_handshake(self) -> bool {
+        matches!(
+            self,
+            PacketType::ConnectionRequest
+                | PacketType::ConnectionChallenge
+                | PacketType::ConnectionResponse
+                | PacketType::ConnectionAccepted
+        )
+    }
 }
 
 impl EnumConverter for PacketType {
@@ -103,6 +130,13 @@ impl TryFrom<u8> for PacketType {
             0 => Ok(PacketType::Packet),
             1 => Ok(PacketType::Fragment),
             2 => Ok(PacketType::Heartbeat),
+            3 => Ok(PacketType::ConnectionRequest),
+            4 => Ok(PacketType::ConnectionChallenge),
+            5 => Ok(PacketType::ConnectionResponse),
+            6 => Ok(PacketType::ConnectionAccepted),
+            7 => Ok(PacketType::Disconnect),
+            8 => Ok(PacketType::Ack),
+            9 => Ok(PacketType::Aggregate),
             _ => Err(ErrorKind::DecodingError(DecodingErrorKind::PacketType)),
         }
     }
@@ -168,5 +202,31 @@ mod tests {
             PacketType::Heartbeat,
             PacketType::try_from(heartbeat.to_u8()).unwrap()
         );
+
+        for packet_type in &[
+            PacketType::ConnectionRequest,
+            PacketType::ConnectionChallenge,
+            PacketType::ConnectionResponse,
+            PacketType::ConnectionAccepted,
+        ] {
+            assert!(packet_type.is_handshake());
+            assert_eq!(
+                *packet_type,
+                PacketType::try_from(packet_type.to_u8()).unwrap()
+            );
+        }
+        assert!(!heartbeat.is_handshake());
+        assert_eq!(
+            PacketType::Disconnect,
+            PacketType::try_from(PacketType::Disconnect.to_u8()).unwrap()
+        );
+        assert_eq!(
+            PacketType::Ack,
+            PacketType::try_from(PacketType::Ack.to_u8()).unwrap()
+        );
+        assert_eq!(
+            PacketType::Aggregate,
+            PacketType::try_from(PacketType::Aggregate.to_u8()).unwrap()
+        );
     }
 }
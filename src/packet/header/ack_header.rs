@@ -0,0 +1,100 @@
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::Result;
+use crate::net::constants::ACK_HEADER_SIZE;
+
+use super::{HeaderReader, HeaderWriter};
+
+#[derive(Copy, Clone, Debug)]
+/// This header is the content of an ack-only packet, it acknowledges packets without having a
+/// sequence number of its own.
+pub struct AckHeader {
+    // This is the last acknowledged sequence number.
+    ack_seq: u16,
+    // This is an bitfield of all last 32 acknowledged packages
+    ack_field: u32,
+}
+
+impl AckHeader {
+    /// Creates a new header from the last received sequence number and the bitfield of the 32
+    /// sequence numbers before it, see [`AckedPacketHeader`](./struct.AckedPacketHeader.html).
+    pub fn new(last_seq: u16, bit_field: u32) -> AckHeader {
+        AckHeader {
+            ack_seq: last_seq,
+            ack_field: bit_field,
+        }
+    }
+
+    /// Returns bit field of all last 32 acknowledged packages.
+    pub fn ack_field(&self) -> u32 {
+        self.ack_field
+    }
+
+    /// Returns last acknowledged sequence number.
+    pub fn ack_seq(&self) -> u16 {
+        self.ack_seq
+    }
+}
+
+impl HeaderWriter for AckHeader {
+    type Output = Result<()>;
+
+    fn parse(&self, buffer: &mut Vec<u8>) -> Self::Output {
+        buffer.write_u16::<BigEndian>(self.ack_seq)?;
+        buffer.write_u32::<BigEndian>(self.ack_field)?;
+        Ok(())
+    }
+}
+
+impl HeaderReader for AckHeader {
+    type Header = Result<AckHeader>;
+
+    fn read(rdr: &mut Cursor<&[u8]>) -> Self::Header {
+        let ack_seq = rdr.read_u16::<BigEndian>()?;
+        let ack_field = rdr.read_u32::<BigEndian>()?;
+
+        Ok(AckHeader { ack_seq, ack_field })
+    }
+
+    fn size() -> u8 {
+        ACK_HEADER_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::net::constants::ACK_HEADER_SIZE;
+    use crate::packet::header::{AckHeader, HeaderReader, HeaderWriter};
+
+    #[test]
+    fn serialize() {
+        let mut buffer = Vec::new();
+        let header = AckHeader::new(2, 3);
+        assert![header.parse(&mut buffer).is_ok()];
+
+        assert_eq!(buffer[1], 2);
+        assert_eq!(buffer[5], 3);
+        assert_eq!(buffer.len() as u8, AckHeader::size());
+    }
+
+    #[test]
+    fn deserialize() {
+        let buffer = vec![0, 2, 0, 0, 0, 3];
+
+        let mut cursor = Cursor::new(buffer.as_slice());
+
+        let header = AckHeader::read(&mut cursor).unwrap();
+
+        assert_eq!(header.ack_seq(), 2);
+        assert_eq!(header.ack_field(), 3);
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(AckHeader::size(), ACK_HEADER_SIZE);
+    }
+}
@@ -31,7 +31,6 @@ impl AckedPacketHeader {
     }
 
     /// Returns the sequence number from this packet.
-    #[allow(dead_code)]
     pub fn sequence(&self) -> u16 {
         self.seq
     }
@@ -7,7 +7,7 @@ use crate::net::constants::FRAGMENT_HEADER_SIZE;
 
 use super::{HeaderReader, HeaderWriter};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 /// This header represents a fragmented packet header.
 pub struct FragmentHeader {
     sequence: u16,
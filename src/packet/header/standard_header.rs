@@ -3,13 +3,18 @@ use std::io::Cursor;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::PROTOCOL_VERSION;
 use crate::error::Result;
 use crate::net::constants::STANDARD_HEADER_SIZE;
 use crate::packet::{DeliveryGuarantee, EnumConverter, OrderingGuarantee, PacketType};
+use crate::PROTOCOL_VERSION;
 
 use super::{HeaderReader, HeaderWriter};
 
+// Set in the delivery guarantee byte when an unreliable packet carries an `AckedPacketHeader`.
+const ACKNOWLEDGED_FLAG: u8 = 0x80;
+// Set in the delivery guarantee byte when everything after this header is encrypted.
+const ENCRYPTED_FLAG: u8 = 0x40;
+
 #[derive(Copy, Clone, Debug)]
 /// This header will be included in each packet, and contains some basic information.
 pub struct StandardHeader {
@@ -17,6 +22,8 @@ pub struct StandardHeader {
     packet_type: PacketType,
     delivery_guarantee: DeliveryGuarantee,
     ordering_guarantee: OrderingGuarantee,
+    acknowledged: bool,
+    encrypted: bool,
 }
 
 impl StandardHeader {
@@ -27,15 +34,34 @@ impl StandardHeader {
         packet_type: PacketType,
     ) -> Self {
         StandardHeader {
-            protocol_version:PROTOCOL_VERSION,
+            protocol_version: PROTOCOL_VERSION,
             delivery_guarantee,
             ordering_guarantee,
             packet_type,
+            acknowledged: delivery_guarantee == DeliveryGuarantee::Reliable,
+            encrypted: false,
         }
     }
 
+    /// Marks that an `AckedPacketHeader` follows this header, which reliable packets always have.
+    pub fn with_acknowledgment(mut self) -> Self {
+        self.acknowledged = true;
+        self
+    }
+
+    /// Marks that everything after this header is encrypted.
+    pub fn with_encryption(mut self) -> Self {
+        self.encrypted = true;
+        self
+    }
+
+    /// Removes the mark that everything after this header is encrypted.
+    pub fn without_encryption(mut self) -> Self {
+        self.encrypted = false;
+        self
+    }
+
     /// Returns the protocol version
-    #[cfg(test)]
     pub fn protocol_version(&self) -> u16 {
         self.protocol_version
     }
@@ -55,11 +81,36 @@ impl StandardHeader {
         self.packet_type
     }
 
+    /// Returns true if an `AckedPacketHeader` follows this header, false otherwise
+    pub fn is_acknowledged(&self) -> bool {
+        self.acknowledged
+    }
+
+    /// Returns true if everything after this header is encrypted, false otherwise
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
     /// Returns true if the packet is a heartbeat packet, false otherwise
     pub fn is_heartbeat(&self) -> bool {
         self.packet_type == PacketType::Heartbeat
     }
 
+    /// Returns true if the packet is a disconnect packet, false otherwise
+    pub fn is_disconnect(&self) -> bool {
+        self.packet_type == PacketType::Disconnect
+    }
+
+    /// Returns true if the packet is an ack-only packet, false otherwise
+    pub fn is_ack(&self) -> bool {
+        self.packet_type == PacketType::Ack
+    }
+
+    /// Returns true if the datagram contains multiple aggregated packets, false otherwise
+    pub fn is_aggregate(&self) -> bool {
+        self.packet_type == PacketType::Aggregate
+    }
+
     /// Returns true if the packet is a fragment, false if not
     pub fn is_fragment(&self) -> bool {
         self.packet_type == PacketType::Fragment
@@ -87,7 +138,14 @@ impl HeaderWriter for StandardHeader {
     fn parse(&self, buffer: &mut Vec<u8>) -> Self::Output {
         buffer.write_u16::<BigEndian>(self.protocol_version)?;
         buffer.write_u8(self.packet_type.to_u8())?;
-        buffer.write_u8(self.delivery_guarantee.to_u8())?;
+        let mut delivery_guarantee = self.delivery_guarantee.to_u8();
+        if self.acknowledged && self.delivery_guarantee == DeliveryGuarantee::Unreliable {
+            delivery_guarantee |= ACKNOWLEDGED_FLAG;
+        }
+        if self.encrypted {
+            delivery_guarantee |= ENCRYPTED_FLAG;
+        }
+        buffer.write_u8(delivery_guarantee)?;
         buffer.write_u8(self.ordering_guarantee.to_u8())?;
         Ok(())
     }
@@ -102,11 +160,17 @@ impl HeaderReader for StandardHeader {
         let delivery_guarantee_id = rdr.read_u8()?;
         let order_guarantee_id = rdr.read_u8()?;
 
+        let delivery_guarantee = DeliveryGuarantee::try_from(
+            delivery_guarantee_id & !(ACKNOWLEDGED_FLAG | ENCRYPTED_FLAG),
+        )?;
         let header = StandardHeader {
             protocol_version,
             packet_type: PacketType::try_from(packet_id)?,
-            delivery_guarantee: DeliveryGuarantee::try_from(delivery_guarantee_id)?,
+            delivery_guarantee,
             ordering_guarantee: OrderingGuarantee::try_from(order_guarantee_id)?,
+            acknowledged: delivery_guarantee == DeliveryGuarantee::Reliable
+                || delivery_guarantee_id & ACKNOWLEDGED_FLAG != 0,
+            encrypted: delivery_guarantee_id & ENCRYPTED_FLAG != 0,
         };
 
         Ok(header)
@@ -159,6 +223,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn acknowledged_unreliable_header_roundtrip() {
+        let mut buffer = Vec::new();
+        let header = StandardHeader::new(
+            DeliveryGuarantee::Unreliable,
+            OrderingGuarantee::None,
+            PacketType::Packet,
+        );
+        assert![!header.is_acknowledged()];
+        assert![header.with_acknowledgment().parse(&mut buffer).is_ok()];
+
+        let header = StandardHeader::read(&mut Cursor::new(buffer.as_slice())).unwrap();
+
+        assert_eq!(header.delivery_guarantee(), DeliveryGuarantee::Unreliable);
+        assert![header.is_acknowledged()];
+    }
+
+    #[test]
+    fn encrypted_header_roundtrip() {
+        let mut buffer = Vec::new();
+        let header = StandardHeader::new(
+            DeliveryGuarantee::Reliable,
+            OrderingGuarantee::Ordered(None),
+            PacketType::Packet,
+        );
+        assert![!header.is_encrypted()];
+        assert![header.with_encryption().parse(&mut buffer).is_ok()];
+
+        let header = StandardHeader::read(&mut Cursor::new(buffer.as_slice())).unwrap();
+
+        assert_eq!(header.delivery_guarantee(), DeliveryGuarantee::Reliable);
+        assert![header.is_encrypted()];
+        assert![!header.without_encryption().is_encrypted()];
+    }
+
     #[test]
     fn size() {
         assert_eq!(StandardHeader::size(), STANDARD_HEADER_SIZE);
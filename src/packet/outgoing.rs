@@ -2,7 +2,8 @@ use crate::{
     net::constants::{DEFAULT_ORDERING_STREAM, DEFAULT_SEQUENCING_STREAM},
     packet::{
         header::{
-            AckedPacketHeader, ArrangingHeader, FragmentHeader, HeaderWriter, StandardHeader,
+            AckHeader, AckedPacketHeader, ArrangingHeader, FragmentHeader, HeaderWriter,
+            StandardHeader,
         },
         DeliveryGuarantee, OrderingGuarantee, PacketType,
     },
@@ -49,6 +50,26 @@ impl<'p> OutgoingPacketBuilder<'p> {
         self
     }
 
+    /// Adds the [`StandardHeader`](./headers/standard_header) of an unreliable packet to the header,
+    /// which is followed by an [`AckedPacketHeader`](./headers/acked_packet_header).
+    pub fn with_acknowledged_header(
+        mut self,
+        packet_type: PacketType,
+        ordering_guarantee: OrderingGuarantee,
+    ) -> Self {
+        let header = StandardHeader::new(
+            DeliveryGuarantee::Unreliable,
+            ordering_guarantee,
+            packet_type,
+        )
+        .with_acknowledgment();
+        header
+            .parse(&mut self.header)
+            .expect("Could not write default header to buffer");
+
+        self
+    }
+
     /// Adds the [`AckedPacketHeader`](./headers/acked_packet_header) to the header.
     pub fn with_acknowledgment_header(
         mut self,
@@ -64,6 +85,16 @@ impl<'p> OutgoingPacketBuilder<'p> {
         self
     }
 
+    /// Adds the [`AckHeader`](./headers/ack_header) of an ack-only packet to the header.
+    pub fn with_ack_header(mut self, last_seq: u16, bit_field: u32) -> Self {
+        let header = AckHeader::new(last_seq, bit_field);
+        header
+            .parse(&mut self.header)
+            .expect("Could not write ack header to buffer");
+
+        self
+    }
+
     /// Adds the [`ArrangingHeader`](./headers/arranging_header) if needed.
     ///
     /// - `arranging_id` = identifier for this packet that needs to be sequenced.
@@ -115,7 +146,7 @@ impl<'p> OutgoingPacket<'p> {
     ///
     /// # Remark
     /// - Until here we could use a reference to the outgoing data but here we need to do a hard copy.
-    /// Because the header could vary in size but should be in front of the payload provided by the user.
+    ///   Because the header could vary in size but should be in front of the payload provided by the user.
     pub fn contents(&self) -> Box<[u8]> {
         [self.header.as_slice(), self.payload]
             .concat()
@@ -173,6 +204,15 @@ mod tests {
         assert_eq!(outgoing.contents().to_vec(), expected);
     }
 
+    #[test]
+    fn assure_creation_ack_header() {
+        let outgoing = OutgoingPacketBuilder::new(&[])
+            .with_ack_header(2, 3)
+            .build();
+
+        assert_eq!(outgoing.contents().to_vec(), vec![0, 2, 0, 0, 0, 3]);
+    }
+
     #[test]
     fn assure_creation_default_header() {
         let payload = test_payload();
@@ -1,8 +1,10 @@
 use std::io::Cursor;
 
-use crate::net::constants::STANDARD_HEADER_SIZE;
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::net::constants::{AGGREGATE_LENGTH_SIZE, STANDARD_HEADER_SIZE};
 use crate::packet::header::{
-    AckedPacketHeader, ArrangingHeader, FragmentHeader, HeaderReader, StandardHeader,
+    AckHeader, AckedPacketHeader, ArrangingHeader, FragmentHeader, HeaderReader, StandardHeader,
 };
 use crate::{ErrorKind, Result};
 
@@ -70,22 +72,59 @@ impl<'s> PacketReader<'s> {
         }
     }
 
-    /// Reads the `FragmentHeader` and optionally the `AckedPacketHeader` from the underlying buffer.
+    /// Reads the `AckHeader` of an ack-only packet from the underlying buffer.
+    ///
+    /// # Remark
+    /// - Will change the position to the location of `AckHeader`
+    pub fn read_ack_header(&mut self) -> Result<AckHeader> {
+        // the ack header comes after standard header.
+        self.cursor.set_position(u64::from(STANDARD_HEADER_SIZE));
+
+        if self.can_read(AckHeader::size()) {
+            AckHeader::read(&mut self.cursor)
+        } else {
+            Err(ErrorKind::CouldNotReadHeader(String::from("ack")))
+        }
+    }
+
+    /// Reads the packets of an aggregate datagram from the underlying buffer, each of them is a
+    /// complete packet starting with its own `StandardHeader`.
+    ///
+    /// # Remark
+    /// - Will change the position to the end of the buffer
+    pub fn read_aggregated_packets(&mut self) -> Result<Vec<&'s [u8]>> {
+        // the aggregated packets come after standard header.
+        self.cursor.set_position(u64::from(STANDARD_HEADER_SIZE));
+
+        let mut packets = Vec::new();
+        while (self.cursor.position() as usize) < self.buffer.len() {
+            if !self.can_read(AGGREGATE_LENGTH_SIZE) {
+                return Err(ErrorKind::CouldNotReadHeader(String::from("aggregate")));
+            }
+            let length = self.cursor.read_u16::<BigEndian>()?;
+
+            let start = self.cursor.position() as usize;
+            let end = start + usize::from(length);
+            if end > self.buffer.len() {
+                return Err(ErrorKind::CouldNotReadHeader(String::from("aggregate")));
+            }
+            packets.push(&self.buffer[start..end]);
+            self.cursor.set_position(end as u64);
+        }
+        Ok(packets)
+    }
+
+    /// Reads the `FragmentHeader` and the `AckedPacketHeader` from the underlying buffer.
     ///
     /// # Remark
     /// - Notice that this will continue on the position of last read header;
-    /// e.g. when reading `StandardHeader` the position of the underlying `Cursor` will be at the end where it left of,
-    /// when calling this function afterward it will read the `FragmentHeader` from there on.
-    /// - Note that only the first fragment of a sequence contains acknowledgment information that's why `AckedPacketHeader` is optional.
-    pub fn read_fragment(&mut self) -> Result<(FragmentHeader, Option<AckedPacketHeader>)> {
-        if self.can_read(FragmentHeader::size()) {
+    ///   e.g. when reading `StandardHeader` the position of the underlying `Cursor` will be at the end where it left of,
+    ///   when calling this function afterward it will read the `FragmentHeader` from there on.
+    /// - Note that every fragment is acknowledged on its own, that's why every fragment contains an `AckedPacketHeader`.
+    pub fn read_fragment(&mut self) -> Result<(FragmentHeader, AckedPacketHeader)> {
+        if self.can_read(FragmentHeader::size() + AckedPacketHeader::size()) {
             let fragment_header = FragmentHeader::read(&mut self.cursor)?;
-
-            let acked_header = if fragment_header.id() == 0 {
-                Some(AckedPacketHeader::read(&mut self.cursor)?)
-            } else {
-                None
-            };
+            let acked_header = AckedPacketHeader::read(&mut self.cursor)?;
 
             Ok((fragment_header, acked_header))
         } else {
@@ -97,17 +136,19 @@ impl<'s> PacketReader<'s> {
     ///
     /// # Remark
     /// - Notice that this will continue on the position of last read header;
-    /// e.g. when reading `StandardHeader` the position of the underlying `Cursor` will be at the end where it left of,
-    /// when calling this function afterward it will read all the bytes from there on.
+    ///   e.g. when reading `StandardHeader` the position of the underlying `Cursor` will be at the end where it left of,
+    ///   when calling this function afterward it will read all the bytes from there on.
     pub fn read_payload(&self) -> Box<[u8]> {
-        self.buffer[self.cursor.position() as usize..self.buffer.len()]
-            .to_vec()
-            .into_boxed_slice()
+        let start = (self.cursor.position() as usize).min(self.buffer.len());
+        self.buffer[start..].to_vec().into_boxed_slice()
     }
 
     // Checks if a given length of bytes could be read with the buffer.
     fn can_read(&self, length: u8) -> bool {
-        (self.buffer.len() - self.cursor.position() as usize) >= length as usize
+        self.buffer
+            .len()
+            .saturating_sub(self.cursor.position() as usize)
+            >= length as usize
     }
 }
 
@@ -125,10 +166,19 @@ mod tests {
         assert!(!reader.can_read((buffer.len() + 1) as u8));
     }
 
+    #[test]
+    fn arranging_header_past_the_end_is_rejected() {
+        let buffer = vec![1, 2, 3];
+
+        let mut reader = PacketReader::new(buffer.as_slice());
+        assert!(reader.read_arranging_header(10).is_err());
+        assert!(reader.read_payload().is_empty());
+    }
+
     #[test]
     fn assure_read_standard_header() {
         // standard header
-        let reliable_ordered_payload: Vec<u8> = vec![vec![0, 1, 0, 1, 2]].concat();
+        let reliable_ordered_payload: Vec<u8> = [vec![0, 1, 0, 1, 2]].concat();
 
         let mut reader = PacketReader::new(reliable_ordered_payload.as_slice());
 
@@ -150,7 +200,7 @@ mod tests {
     fn assure_read_acknowledgment_header() {
         // standard header, acked header
         let reliable_ordered_payload: Vec<u8> =
-            vec![vec![0, 1, 0, 1, 2], vec![0, 1, 0, 2, 0, 0, 0, 3]].concat();
+            [vec![0, 1, 0, 1, 2], vec![0, 1, 0, 2, 0, 0, 0, 3]].concat();
 
         let mut reader = PacketReader::new(reliable_ordered_payload.as_slice());
 
@@ -161,10 +211,43 @@ mod tests {
         assert_eq!(acked_header.ack_field(), 3);
     }
 
+    #[test]
+    fn assure_read_ack_header() {
+        // standard header, ack header
+        let ack_payload: Vec<u8> = [vec![0, 1, 8, 0, 0], vec![0, 2, 0, 0, 0, 3]].concat();
+
+        let mut reader = PacketReader::new(ack_payload.as_slice());
+
+        assert!(reader.read_standard_header().unwrap().is_ack());
+        let ack_header = reader.read_ack_header().unwrap();
+
+        assert_eq!(ack_header.ack_seq(), 2);
+        assert_eq!(ack_header.ack_field(), 3);
+    }
+
+    #[test]
+    fn assure_read_aggregated_packets() {
+        // standard header, two packets with their length
+        let aggregate: Vec<u8> = [vec![0, 1, 9, 0, 0], vec![0, 2, 1, 2], vec![0, 1, 3]].concat();
+
+        let mut reader = PacketReader::new(aggregate.as_slice());
+        assert!(reader.read_standard_header().unwrap().is_aggregate());
+        assert_eq!(
+            reader.read_aggregated_packets().unwrap(),
+            vec![&[1, 2][..], &[3][..]]
+        );
+
+        // the length of the last packet exceeds the datagram
+        let truncated: Vec<u8> = [vec![0, 1, 9, 0, 0], vec![0, 2, 1]].concat();
+        assert!(PacketReader::new(truncated.as_slice())
+            .read_aggregated_packets()
+            .is_err());
+    }
+
     #[test]
     fn assure_read_fragment_header() {
         // standard header, acked header, arranging header
-        let reliable_ordered_payload: Vec<u8> = vec![
+        let reliable_ordered_payload: Vec<u8> = [
             vec![0, 1, 0, 1, 2],
             vec![0, 1, 0, 3],
             vec![0, 1, 0, 2, 0, 0, 0, 3],
@@ -187,19 +270,30 @@ mod tests {
             OrderingGuarantee::Ordered(None)
         );
 
-        assert_eq!(acked_header.unwrap().sequence(), 1);
-        assert_eq!(acked_header.unwrap().ack_seq(), 2);
-        assert_eq!(acked_header.unwrap().ack_field(), 3);
+        assert_eq!(acked_header.sequence(), 1);
+        assert_eq!(acked_header.ack_seq(), 2);
+        assert_eq!(acked_header.ack_field(), 3);
 
         assert_eq!(fragment_header.sequence(), 1);
         assert_eq!(fragment_header.id(), 0);
         assert_eq!(fragment_header.fragment_count(), 3);
     }
 
+    #[test]
+    fn fragment_without_acked_header_is_rejected() {
+        // standard header, fragment header of a fragment other than the first
+        let payload: Vec<u8> = [vec![0, 1, 1, 1, 0], vec![0, 1, 1, 3]].concat();
+
+        let mut reader = PacketReader::new(payload.as_slice());
+        reader.read_standard_header().unwrap();
+
+        assert!(reader.read_fragment().is_err());
+    }
+
     #[test]
     fn assure_read_unreliable_sequenced_header() {
         // standard header, arranging header
-        let reliable_ordered_payload: Vec<u8> = vec![vec![0, 1, 0, 1, 2], vec![0, 1, 2]].concat();
+        let reliable_ordered_payload: Vec<u8> = [vec![0, 1, 0, 1, 2], vec![0, 1, 2]].concat();
 
         let mut reader = PacketReader::new(reliable_ordered_payload.as_slice());
 
@@ -214,7 +308,7 @@ mod tests {
     #[test]
     fn assure_read_reliable_ordered_header() {
         // standard header, acked header, arranging header
-        let reliable_ordered_payload: Vec<u8> = vec![
+        let reliable_ordered_payload: Vec<u8> = [
             vec![0, 1, 0, 1, 2],
             vec![0, 1, 0, 2, 0, 0, 0, 3],
             vec![0, 1, 2],
@@ -253,7 +347,7 @@ mod tests {
     fn assure_read_reliable_unordered_header() {
         // standard header, acked header, arranging header
         let reliable_ordered_payload: Vec<u8> =
-            vec![vec![0, 1, 0, 1, 2], vec![0, 1, 0, 2, 0, 0, 0, 3]].concat();
+            [vec![0, 1, 0, 1, 2], vec![0, 1, 0, 2, 0, 0, 0, 3]].concat();
         let mut reader = PacketReader::new(reliable_ordered_payload.as_slice());
 
         let standard_header = reader.read_standard_header().unwrap();
@@ -278,7 +372,7 @@ mod tests {
     #[test]
     fn expect_read_error() {
         // standard header (with one corrupt byte)
-        let reliable_ordered_payload: Vec<u8> = vec![vec![0, 1, 0, 1]].concat();
+        let reliable_ordered_payload: Vec<u8> = [vec![0, 1, 0, 1]].concat();
 
         let mut reader = PacketReader::new(reliable_ordered_payload.as_slice());
 
@@ -2,6 +2,10 @@ use std::net::SocketAddr;
 
 use crate::packet::{DeliveryGuarantee, OrderingGuarantee, PacketType};
 
+/// Identifies a packet sent with `Socket::send`, it is used to notify about the delivery of the packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub(crate) u64);
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 /// This is a user friendly packet containing the payload, endpoint, and reliability guarantees.
 /// A packet could have reliability guarantees to specify how it should be delivered and processed.
@@ -24,6 +28,8 @@ pub struct Packet {
     delivery: DeliveryGuarantee,
     /// Defines on how the packet will be ordered.
     ordering: OrderingGuarantee,
+    /// Identifies the packet for delivery notifications, only set on packets that are sent.
+    message_id: Option<MessageId>,
 }
 
 impl Packet {
@@ -39,6 +45,7 @@ impl Packet {
             payload,
             delivery,
             ordering,
+            message_id: None,
         }
     }
 
@@ -59,6 +66,7 @@ impl Packet {
             payload: payload.into_boxed_slice(),
             delivery: DeliveryGuarantee::Unreliable,
             ordering: OrderingGuarantee::None,
+            message_id: None,
         }
     }
 
@@ -83,6 +91,7 @@ impl Packet {
             payload: payload.into_boxed_slice(),
             delivery: DeliveryGuarantee::Unreliable,
             ordering: OrderingGuarantee::Sequenced(stream_id),
+            message_id: None,
         }
     }
 
@@ -102,6 +111,7 @@ impl Packet {
             payload: payload.into_boxed_slice(),
             delivery: DeliveryGuarantee::Reliable,
             ordering: OrderingGuarantee::None,
+            message_id: None,
         }
     }
 
@@ -125,6 +135,7 @@ impl Packet {
             payload: payload.into_boxed_slice(),
             delivery: DeliveryGuarantee::Reliable,
             ordering: OrderingGuarantee::Ordered(stream_id),
+            message_id: None,
         }
     }
 
@@ -149,6 +160,7 @@ impl Packet {
             payload: payload.into_boxed_slice(),
             delivery: DeliveryGuarantee::Reliable,
             ordering: OrderingGuarantee::Sequenced(stream_id),
+            message_id: None,
         }
     }
 
@@ -180,6 +192,17 @@ impl Packet {
     pub fn order_guarantee(&self) -> OrderingGuarantee {
         self.ordering
     }
+
+    /// Returns the id this packet was sent with, if any.
+    pub(crate) fn message_id(&self) -> Option<MessageId> {
+        self.message_id
+    }
+
+    /// Sets the id this packet is sent with.
+    pub(crate) fn with_message_id(mut self, message_id: MessageId) -> Packet {
+        self.message_id = Some(message_id);
+        self
+    }
 }
 
 /// This packet type has similar properties to `Packet` except that it doesn't own anything, and additionally has `PacketType`.
@@ -193,6 +216,8 @@ pub struct PacketInfo<'a> {
     pub(crate) delivery: DeliveryGuarantee,
     /// Defines how the packet will be ordered.
     pub(crate) ordering: OrderingGuarantee,
+    /// Identifies the packet for delivery notifications.
+    pub(crate) message_id: Option<MessageId>,
 }
 
 impl<'a> PacketInfo<'a> {
@@ -207,6 +232,7 @@ impl<'a> PacketInfo<'a> {
             payload,
             delivery,
             ordering,
+            message_id: None,
         }
     }
 
@@ -217,6 +243,25 @@ impl<'a> PacketInfo<'a> {
             payload,
             delivery: DeliveryGuarantee::Unreliable,
             ordering: OrderingGuarantee::None,
+            message_id: None,
+        }
+    }
+
+    /// Sets the id of the message this packet belongs to, delivery notifications are sent for it.
+    pub fn with_message_id(mut self, message_id: Option<MessageId>) -> Self {
+        self.message_id = message_id;
+        self
+    }
+
+    /// Creates a control packet of the given type, e.g. a handshake or disconnect packet, that is
+    /// expected to be sent over the network.
+    pub fn control_packet(packet_type: PacketType, payload: &'a [u8]) -> Self {
+        PacketInfo {
+            packet_type,
+            payload,
+            delivery: DeliveryGuarantee::Unreliable,
+            ordering: OrderingGuarantee::None,
+            message_id: None,
         }
     }
 }
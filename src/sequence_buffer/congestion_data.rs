@@ -1,27 +1,20 @@
 use std::time::Instant;
 
-use crate::packet::SequenceNumber;
-
 #[derive(Clone)]
-/// This contains the information required to reassemble fragments.
+/// This contains the information required to measure the round trip time of a packet.
 pub struct CongestionData {
-    pub sequence: SequenceNumber,
     pub sending_time: Instant,
 }
 
 impl CongestionData {
-    pub fn new(sequence: SequenceNumber, sending_time: Instant) -> Self {
-        CongestionData {
-            sequence,
-            sending_time,
-        }
+    pub fn new(sending_time: Instant) -> Self {
+        CongestionData { sending_time }
     }
 }
 
 impl Default for CongestionData {
     fn default() -> Self {
         CongestionData {
-            sequence: 0,
             sending_time: Instant::now(),
         }
     }
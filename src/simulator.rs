@@ -0,0 +1,455 @@
+//! Deterministic network simulator to write multiplayer integration tests on top of laminar.
+//!
+//! A [`Simulator`] runs any number of endpoints on a virtual clock. Every step advances the clock
+//! by a fixed tick and polls the endpoints in the order they were added, packets travel between
+//! them through the [`LinkConditioner`] configured for their direction. The conditioners are
+//! seeded from the seed of the simulator, so a simulation with the same seed, setup and sequence of
+//! calls always drops, delays and delivers the same packets.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use laminar::{simulator::Simulator, Config, LinkConditioner, Packet};
+//!
+//! let server = "127.0.0.1:12345".parse().unwrap();
+//! let client = "127.0.0.1:12346".parse().unwrap();
+//!
+//! let mut simulator = Simulator::new(42, Duration::from_millis(10));
+//! simulator.add_endpoint(server, Config::default()).unwrap();
+//! simulator.add_endpoint(client, Config::default()).unwrap();
+//!
+//! let mut conditioner = LinkConditioner::new();
+//! conditioner.set_latency(Duration::from_millis(40));
+//! conditioner.set_packet_loss(0.1);
+//! simulator.set_link(client, server, Some(conditioner));
+//!
+//! simulator
+//!     .endpoint(client)
+//!     .unwrap()
+//!     .send(Packet::reliable_unordered(server, vec![1, 2, 3]))
+//!     .unwrap();
+//! simulator.run_for(Duration::from_secs(1));
+//!
+//! while let Some(event) = simulator.endpoint(server).unwrap().recv() {
+//!     println!("{:?}", event);
+//! }
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use rand_pcg::Pcg64Mcg as Random;
+
+use crate::{
+    error::{ErrorKind, Result},
+    net::{
+        ConnectionManager, ConnectionStats, DatagramSocket, DisconnectReason, LinkConditioner,
+        VirtualConnection,
+    },
+    packet::{MessageId, Packet},
+    Config, SocketEvent,
+};
+
+/// Runs endpoints on a shared virtual clock, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Simulator {
+    network: Rc<RefCell<Network>>,
+    endpoints: Vec<Endpoint>,
+    time: Instant,
+    tick: Duration,
+    // Seeds the link conditioners, in the order they are set
+    random: Random,
+}
+
+impl Simulator {
+    /// Creates a simulator without endpoints, every step advances the clock by `tick`.
+    pub fn new(seed: u64, tick: Duration) -> Simulator {
+        Simulator {
+            network: Default::default(),
+            endpoints: Vec::new(),
+            time: Instant::now(),
+            tick,
+            random: Random::new(u128::from(seed)),
+        }
+    }
+
+    /// Adds an endpoint that is bound to the given address.
+    /// If another endpoint already was bound to this address, an error will be returned instead.
+    pub fn add_endpoint(&mut self, address: SocketAddr, config: Config) -> Result<()> {
+        match self.network.borrow_mut().inboxes.entry(address) {
+            Entry::Occupied(_) => {
+                return Err(ErrorKind::IOError(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "Cannot bind to address",
+                )))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Default::default());
+            }
+        }
+
+        let socket = SimulatedSocket {
+            network: self.network.clone(),
+            address,
+            time: self.time,
+        };
+        self.endpoints.push(Endpoint {
+            address,
+            handler: ConnectionManager::new(socket, config),
+            next_message_id: 0,
+        });
+        Ok(())
+    }
+
+    /// Returns the endpoint that is bound to the given address.
+    pub fn endpoint(&mut self, address: SocketAddr) -> Option<&mut Endpoint> {
+        self.endpoints
+            .iter_mut()
+            .find(|endpoint| endpoint.address == address)
+    }
+
+    /// Sets the conditions of the packets sent from one address to another, the other direction is
+    /// not affected. The conditioner is seeded by the simulator. Without a conditioner, packets
+    /// arrive instantly.
+    pub fn set_link(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        conditioner: Option<LinkConditioner>,
+    ) {
+        let links = &mut self.network.borrow_mut().links;
+        match conditioner {
+            Some(mut conditioner) => {
+                conditioner.set_seed(self.random.gen());
+                links.insert((from, to), conditioner);
+            }
+            None => {
+                links.remove(&(from, to));
+            }
+        }
+    }
+
+    /// Returns the current time of the virtual clock.
+    pub fn now(&self) -> Instant {
+        self.time
+    }
+
+    /// Advances the clock by one tick and polls every endpoint, in the order they were added.
+    pub fn step(&mut self) {
+        self.time += self.tick;
+        for endpoint in self.endpoints.iter_mut() {
+            endpoint.handler.manual_poll(self.time);
+        }
+    }
+
+    /// Steps until the clock has advanced by at least the given duration.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.time + duration;
+        while self.time < end {
+            self.step();
+        }
+    }
+
+    /// Steps until the condition holds or the clock has advanced by the given duration, returns
+    /// whether the condition holds.
+    pub fn run_until<F>(&mut self, timeout: Duration, mut condition: F) -> bool
+    where
+        F: FnMut(&mut Simulator) -> bool,
+    {
+        let end = self.time + timeout;
+        while !condition(self) {
+            if self.time >= end {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+}
+
+/// Endpoint of a [`Simulator`], with an interface similar to the real `Socket`.
+#[derive(Debug)]
+pub struct Endpoint {
+    address: SocketAddr,
+    handler: ConnectionManager<SimulatedSocket, VirtualConnection>,
+    next_message_id: u64,
+}
+
+impl Endpoint {
+    /// Returns the address this endpoint is bound to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Sends a packet in the next step, returns the id that delivery notifications refer to.
+    pub fn send(&mut self, packet: Packet) -> Result<MessageId> {
+        let message_id = MessageId(self.next_message_id);
+        self.next_message_id += 1;
+
+        // we can savely unwrap, because receiver will always exist
+        self.handler
+            .event_sender()
+            .send(packet.with_message_id(message_id))
+            .unwrap();
+        Ok(message_id)
+    }
+
+    /// Receives the next event that was emitted during the previous steps.
+    pub fn recv(&mut self) -> Option<SocketEvent> {
+        self.handler.event_receiver().try_recv().ok()
+    }
+
+    /// Gracefully closes the connection to the given address.
+    pub fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) {
+        self.handler.disconnect(addr, reason);
+    }
+
+    /// Returns the network statistics of the connection to the given address.
+    pub fn connection_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.handler.connection(&addr).map(VirtualConnection::stats)
+    }
+
+    /// Returns the number of active connections.
+    pub fn connection_count(&self) -> usize {
+        self.handler.connections_count()
+    }
+}
+
+// Packets that arrived at a socket, with the address they were sent from.
+type Inbox = VecDeque<(SocketAddr, Box<[u8]>)>;
+
+// State that is shared by all sockets of a simulator.
+#[derive(Debug, Default)]
+struct Network {
+    inboxes: HashMap<SocketAddr, Inbox>,
+    // Conditions of the packets sent from one address to another, ordered so that packets are
+    // released in the same order in every run
+    links: BTreeMap<(SocketAddr, SocketAddr), LinkConditioner>,
+}
+
+impl Network {
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, payload: &[u8], time: Instant) {
+        match self.links.get_mut(&(from, to)) {
+            Some(conditioner) => {
+                conditioner.send(to, payload, time);
+                self.release(from, to, time);
+            }
+            None => {
+                if let Some(inbox) = self.inboxes.get_mut(&to) {
+                    inbox.push_back((from, Box::from(payload)));
+                }
+            }
+        }
+    }
+
+    // Moves the packets of a link that arrived at the given time into the inbox of their receiver.
+    fn release(&mut self, from: SocketAddr, to: SocketAddr, time: Instant) {
+        let (conditioner, inbox) =
+            match (self.links.get_mut(&(from, to)), self.inboxes.get_mut(&to)) {
+                (Some(conditioner), Some(inbox)) => (conditioner, inbox),
+                _ => return,
+            };
+        while let Some((_, payload)) = conditioner.next_released(time) {
+            inbox.push_back((from, payload));
+        }
+    }
+
+    fn release_all(&mut self, to: SocketAddr, time: Instant) {
+        let senders: Vec<SocketAddr> = self
+            .links
+            .keys()
+            .filter(|link| link.1 == to)
+            .map(|link| link.0)
+            .collect();
+        for from in senders {
+            self.release(from, to, time);
+        }
+    }
+}
+
+// Socket of an endpoint, which exchanges packets with the other endpoints of the simulator.
+#[derive(Debug)]
+struct SimulatedSocket {
+    network: Rc<RefCell<Network>>,
+    address: SocketAddr,
+    // The time of the current poll
+    time: Instant,
+}
+
+impl DatagramSocket for SimulatedSocket {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        self.network
+            .borrow_mut()
+            .send(self.address, *addr, payload, self.time);
+        Ok(payload.len())
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        let packet = self
+            .network
+            .borrow_mut()
+            .inboxes
+            .get_mut(&self.address)
+            .and_then(VecDeque::pop_front);
+
+        match packet {
+            Some((addr, payload)) => {
+                // like a real socket, the part that does not fit into the buffer is discarded.
+                let length = payload.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&payload[..length]);
+                Ok((&buffer[..length], addr))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        false
+    }
+
+    fn poll(&mut self, time: Instant) {
+        self.time = time;
+        self.network.borrow_mut().release_all(self.address, time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use crate::{Config, LinkConditioner, LossModel, Packet, SocketEvent};
+
+    use super::Simulator;
+
+    fn server_address() -> SocketAddr {
+        "127.0.0.1:10001".parse().unwrap()
+    }
+
+    fn client_address(index: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 20000 + index))
+    }
+
+    fn received(simulator: &mut Simulator, address: SocketAddr) -> Vec<Vec<u8>> {
+        let endpoint = simulator.endpoint(address).unwrap();
+        std::iter::from_fn(|| endpoint.recv())
+            .filter_map(|event| match event {
+                SocketEvent::Packet(packet) => Some(packet.payload().to_vec()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Runs a server with three clients that each send reliable packets over a lossy link, returns
+    // the payloads in the order the server received them.
+    fn simulate(seed: u64) -> Vec<(SocketAddr, u8)> {
+        let mut simulator = Simulator::new(seed, Duration::from_millis(10));
+        simulator
+            .add_endpoint(server_address(), Config::default())
+            .unwrap();
+        for index in 0..3 {
+            simulator
+                .add_endpoint(client_address(index), Config::default())
+                .unwrap();
+
+            let mut conditioner = LinkConditioner::new();
+            conditioner.set_latency(Duration::from_millis(30));
+            conditioner.set_jitter(Duration::from_millis(20));
+            conditioner.set_packet_loss(0.2);
+            simulator.set_link(client_address(index), server_address(), Some(conditioner));
+        }
+
+        for i in 0..20 {
+            for index in 0..3 {
+                simulator
+                    .endpoint(client_address(index))
+                    .unwrap()
+                    .send(Packet::reliable_unordered(server_address(), vec![i]))
+                    .unwrap();
+            }
+            simulator.step();
+        }
+        simulator.run_for(Duration::from_secs(3));
+
+        let server = simulator.endpoint(server_address()).unwrap();
+        std::iter::from_fn(|| server.recv())
+            .filter_map(|event| match event {
+                SocketEvent::Packet(packet) => Some((packet.addr(), packet.payload()[0])),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simulations_with_the_same_seed_are_identical() {
+        let received = simulate(7);
+        assert_eq!(received.len(), 60);
+        assert_eq!(received, simulate(7));
+        assert_ne!(received, simulate(8));
+    }
+
+    #[test]
+    fn packets_arrive_after_the_latency_of_their_link() {
+        let mut simulator = Simulator::new(0, Duration::from_millis(10));
+        simulator
+            .add_endpoint(server_address(), Config::default())
+            .unwrap();
+        simulator
+            .add_endpoint(client_address(0), Config::default())
+            .unwrap();
+        assert!(simulator
+            .add_endpoint(client_address(0), Config::default())
+            .is_err());
+
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_latency(Duration::from_millis(100));
+        simulator.set_link(client_address(0), server_address(), Some(conditioner));
+
+        simulator
+            .endpoint(client_address(0))
+            .unwrap()
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        let start = simulator.now();
+
+        assert!(simulator.run_until(Duration::from_secs(1), |simulator| {
+            simulator
+                .endpoint(server_address())
+                .unwrap()
+                .connection_count()
+                == 1
+        }));
+        assert_eq!(simulator.now() - start, Duration::from_millis(110));
+        assert_eq!(received(&mut simulator, server_address()), vec![vec![1]]);
+
+        // the link in the other direction is not affected
+        simulator.set_link(client_address(0), server_address(), None);
+        let mut conditioner = LinkConditioner::new();
+        conditioner.set_loss_model(LossModel::Uniform(1.0));
+        simulator.set_link(server_address(), client_address(0), Some(conditioner));
+        simulator
+            .endpoint(client_address(0))
+            .unwrap()
+            .send(Packet::unreliable(server_address(), vec![2]))
+            .unwrap();
+        simulator
+            .endpoint(server_address())
+            .unwrap()
+            .send(Packet::unreliable(client_address(0), vec![3]))
+            .unwrap();
+        simulator.run_for(Duration::from_millis(100));
+
+        assert_eq!(received(&mut simulator, server_address()), vec![vec![2]]);
+        assert!(received(&mut simulator, client_address(0)).is_empty());
+    }
+}
@@ -1,29 +1,73 @@
-use std::{net::SocketAddr, time::Instant};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use crossbeam_channel::{Receiver, Sender};
 
-use crate::net::{ConnectionManager, LinkConditioner, VirtualConnection};
+use crate::net::{
+    ConnectionManager, ConnectionStats, DatagramSocket, DisconnectReason, DroppedDatagrams,
+    LinkConditioner, PacketSender, SocketCommand, VirtualConnection,
+};
 use crate::test_utils::*;
-use crate::{error::Result, Config, Packet, SocketEvent};
+use crate::{error::Result, Config, MessageId, Packet, SocketEvent};
 
 /// Provides a similar to the real a `Socket`, but with emulated socket implementation.
-pub struct FakeSocket {
-    handler: ConnectionManager<EmulatedSocket, VirtualConnection>,
+pub struct FakeSocket<S: DatagramSocket = EmulatedSocket> {
+    handler: ConnectionManager<S, VirtualConnection>,
+    packet_sender: PacketSender,
 }
 
 impl FakeSocket {
     /// Binds to the socket.
     pub fn bind(network: &NetworkEmulator, addr: SocketAddr, config: Config) -> Result<Self> {
-        Ok(Self {
-            handler: ConnectionManager::new(network.new_socket(addr)?, config),
-        })
+        Ok(Self::new(network.new_socket(addr)?, config))
+    }
+
+    /// Sets the link conditioner for this socket. See [LinkConditioner] for further details.
+    pub fn set_link_conditioner(&mut self, conditioner: Option<LinkConditioner>) {
+        self.handler.socket_mut().set_link_conditioner(conditioner);
+    }
+}
+
+impl FakeSocket<ThreadedEmulatedSocket> {
+    /// Binds to the socket of a thread-safe network, in blocking mode if the config enables it.
+    pub fn bind_threaded(
+        network: &ThreadedNetworkEmulator,
+        addr: SocketAddr,
+        config: Config,
+    ) -> Result<Self> {
+        let mut socket = network.new_socket(addr)?;
+        socket.set_blocking_mode(config.blocking_mode);
+        Ok(Self::new(socket, config))
+    }
+
+    /// Sets how long a poll in blocking mode waits for a packet, `None` waits until one arrives.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.handler.socket_mut().set_read_timeout(timeout);
+    }
+}
+
+impl<S: DatagramSocket> FakeSocket<S> {
+    fn new(socket: S, config: Config) -> Self {
+        let handler = ConnectionManager::new(socket, config);
+        let packet_sender = PacketSender::new(handler.event_sender().clone());
+        Self {
+            handler,
+            packet_sender,
+        }
     }
 
     /// Returns a handle to the packet sender which provides a thread-safe way to enqueue packets
     /// to be processed. This should be used when the socket is busy running its polling loop in a
     /// separate thread.
-    pub fn get_packet_sender(&self) -> Sender<Packet> {
-        self.handler.event_sender().clone()
+    pub fn get_packet_sender(&self) -> PacketSender {
+        self.packet_sender.clone()
+    }
+
+    /// Returns a handle to the command sender which provides a thread-safe way to enqueue commands.
+    pub fn get_command_sender(&self) -> Sender<SocketCommand> {
+        self.handler.command_sender().clone()
     }
 
     /// Returns a handle to the event receiver which provides a thread-safe way to retrieve events
@@ -33,20 +77,40 @@ impl FakeSocket {
         self.handler.event_receiver().clone()
     }
 
-    /// Sends a packet.
-    pub fn send(&mut self, packet: Packet) -> Result<()> {
+    /// Sends a packet, returns the id that delivery notifications refer to.
+    pub fn send(&mut self, packet: Packet) -> Result<MessageId> {
         // we can savely unwrap, because receiver will always exist
-        self.handler.event_sender().send(packet).unwrap();
-        Ok(())
+        Ok(self.packet_sender.send(packet).unwrap())
     }
 
     /// Receives a packet.
     pub fn recv(&mut self) -> Option<SocketEvent> {
-        if let Ok(event) = self.handler.event_receiver().try_recv() {
-            Some(event)
-        } else {
-            None
-        }
+        self.handler.event_receiver().try_recv().ok()
+    }
+
+    /// Gracefully closes the connection to the given address.
+    pub fn disconnect(&self, addr: SocketAddr, reason: DisconnectReason) {
+        self.handler.disconnect(addr, reason);
+    }
+
+    /// Returns the network statistics of the connection to the given address.
+    pub fn connection_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.handler.connection(&addr).map(VirtualConnection::stats)
+    }
+
+    /// Drops all datagrams received from the given IP address for the given duration.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.handler.ban(ip, duration);
+    }
+
+    /// Exempts the given IP address from the rate limits.
+    pub fn allow(&self, ip: IpAddr) {
+        self.handler.allow(ip);
+    }
+
+    /// Returns the number of received datagrams that were dropped before they reached a connection.
+    pub fn dropped_datagrams(&self) -> DroppedDatagrams {
+        self.handler.dropped_datagrams()
     }
 
     /// Processes any inbound/outbound packets and handle idle clients.
@@ -58,9 +122,4 @@ impl FakeSocket {
     pub fn connection_count(&self) -> usize {
         self.handler.connections_count()
     }
-
-    /// Sets the link conditioner for this socket. See [LinkConditioner] for further details.
-    pub fn set_link_conditioner(&mut self, conditioner: Option<LinkConditioner>) {
-        self.handler.socket_mut().set_link_conditioner(conditioner);
-    }
 }
@@ -1,5 +1,7 @@
 pub use fake_socket::FakeSocket;
 pub use network_emulator::{EmulatedSocket, NetworkEmulator};
+pub use threaded_network_emulator::{ThreadedEmulatedSocket, ThreadedNetworkEmulator};
 
 mod fake_socket;
 mod network_emulator;
+mod threaded_network_emulator;
@@ -5,8 +5,11 @@ use std::{
     io::Result,
     net::SocketAddr,
     rc::Rc,
+    time::Instant,
 };
 
+use rand::{seq::SliceRandom, Rng};
+
 use crate::net::{DatagramSocket, LinkConditioner};
 
 /// This type allows to share global state between all sockets, created from the same instance of `NetworkEmulator`.
@@ -33,6 +36,7 @@ impl NetworkEmulator {
                     network: self.network.clone(),
                     address,
                     conditioner: Default::default(),
+                    time: None,
                 })
             }
         }
@@ -44,6 +48,40 @@ impl NetworkEmulator {
             packets.clear();
         }
     }
+
+    /// Removes the packet at the given position from the packets waiting to be received by a socket
+    /// that is bound to provided address, to emulate its loss.
+    pub fn drop_packet(&self, addr: SocketAddr, index: usize) -> Option<Vec<u8>> {
+        self.network
+            .borrow_mut()
+            .get_mut(&addr)
+            .and_then(|packets| packets.remove(index))
+            .map(|(_, payload)| payload)
+    }
+
+    /// Shuffles the packets waiting to be received by a socket that is bound to provided address,
+    /// to emulate their reordering in the network.
+    pub fn shuffle_packets<R: Rng>(&self, addr: SocketAddr, rng: &mut R) {
+        if let Some(packets) = self.network.borrow_mut().get_mut(&addr) {
+            packets.make_contiguous().shuffle(rng);
+        }
+    }
+
+    /// Delivers a packet to a socket that is bound to provided address, as if it was sent from
+    /// another address, to emulate packets that were forged or replayed by a third party.
+    pub fn spoof_packet(&self, from: SocketAddr, addr: SocketAddr, payload: Vec<u8>) {
+        if let Some(packets) = self.network.borrow_mut().get_mut(&addr) {
+            packets.push_back((from, payload));
+        }
+    }
+
+    /// Returns the number of packets waiting to be received by a socket that is bound to provided address.
+    pub fn packet_count(&self, addr: SocketAddr) -> usize {
+        self.network
+            .borrow()
+            .get(&addr)
+            .map_or(0, |packets| packets.len())
+    }
 }
 
 /// Implementation of a socket, that is created by `NetworkEmulator`.
@@ -52,30 +90,39 @@ pub struct EmulatedSocket {
     network: GlobalBindings,
     address: SocketAddr,
     conditioner: Option<LinkConditioner>,
+    // The time of the current poll, packets that pass the link conditioner are sent at this time.
+    time: Option<Instant>,
 }
 
 impl EmulatedSocket {
+    /// Sets the link conditioner for this socket. See [LinkConditioner] for further details.
     pub fn set_link_conditioner(&mut self, conditioner: Option<LinkConditioner>) {
         self.conditioner = conditioner;
     }
+
+    // Sends the packets that arrived at the given time to the sockets they are addressed to.
+    fn send_released_packets(&mut self, time: Instant) {
+        if let Some(ref mut conditioner) = self.conditioner {
+            while let Some((addr, payload)) = conditioner.next_released(time) {
+                if let Some(binded) = self.network.borrow_mut().get_mut(&addr) {
+                    binded.push_back((self.address, payload.to_vec()));
+                }
+            }
+        }
+    }
 }
 
 impl DatagramSocket for EmulatedSocket {
     /// Sends a packet to and address if there is a socket bound to it. Otherwise it will simply be ignored.
     fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> Result<usize> {
-        let send = if let Some(ref mut conditioner) = self.conditioner {
-            conditioner.should_send()
-        } else {
-            true
-        };
-        if send {
-            if let Some(binded) = self.network.borrow_mut().get_mut(addr) {
-                binded.push_back((self.address, payload.to_vec()));
-            }
-            Ok(payload.len())
-        } else {
-            Ok(0)
+        if let Some(ref mut conditioner) = self.conditioner {
+            let time = self.time.unwrap_or_else(Instant::now);
+            conditioner.send(*addr, payload, time);
+            self.send_released_packets(time);
+        } else if let Some(binded) = self.network.borrow_mut().get_mut(addr) {
+            binded.push_back((self.address, payload.to_vec()));
         }
+        Ok(payload.len())
     }
 
     /// Receives a packet from this socket.
@@ -103,4 +150,10 @@ impl DatagramSocket for EmulatedSocket {
     fn is_blocking_mode(&self) -> bool {
         false
     }
+
+    /// Sends the packets that were held back by the link conditioner and arrived by now.
+    fn poll(&mut self, time: Instant) {
+        self.time = Some(time);
+        self.send_released_packets(time);
+    }
 }
@@ -0,0 +1,242 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+
+use crate::net::DatagramSocket;
+
+/// Senders to the sockets that are bound to an address, shared between all sockets created from the same instance of `ThreadedNetworkEmulator`.
+type GlobalBindings = Arc<Mutex<HashMap<SocketAddr, Sender<(SocketAddr, Vec<u8>)>>>>;
+
+/// Thread-safe variant of `NetworkEmulator`, its sockets can be moved into the thread that runs their polling loop.
+#[derive(Debug, Default)]
+pub struct ThreadedNetworkEmulator {
+    network: GlobalBindings,
+}
+
+impl ThreadedNetworkEmulator {
+    /// Creates an emulated socket by binding to an address.
+    /// If other socket already was bound to this address, error will be returned instead.
+    pub fn new_socket(&self, address: SocketAddr) -> Result<ThreadedEmulatedSocket> {
+        let (sender, receiver) = unbounded();
+        match self.network.lock().unwrap().entry(address) {
+            Entry::Occupied(_) => Err(Error::new(ErrorKind::AddrInUse, "Cannot bind to address")),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+                Ok(ThreadedEmulatedSocket {
+                    network: self.network.clone(),
+                    address,
+                    receiver,
+                    is_blocking_mode: false,
+                    read_timeout: None,
+                })
+            }
+        }
+    }
+}
+
+/// Implementation of a socket, that is created by `ThreadedNetworkEmulator`.
+#[derive(Debug)]
+pub struct ThreadedEmulatedSocket {
+    network: GlobalBindings,
+    address: SocketAddr,
+    receiver: Receiver<(SocketAddr, Vec<u8>)>,
+    is_blocking_mode: bool,
+    read_timeout: Option<Duration>,
+}
+
+impl ThreadedEmulatedSocket {
+    /// Makes receives wait for a packet to arrive, up to the read timeout.
+    pub fn set_blocking_mode(&mut self, is_blocking_mode: bool) {
+        self.is_blocking_mode = is_blocking_mode;
+    }
+
+    /// Sets how long a receive in blocking mode waits for a packet, `None` waits until one arrives.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl DatagramSocket for ThreadedEmulatedSocket {
+    /// Sends a packet to and address if there is a socket bound to it. Otherwise it will simply be ignored.
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> Result<usize> {
+        if let Some(binded) = self.network.lock().unwrap().get(addr) {
+            // the receiving socket may be dropped by now, like with UDP the packet is lost then.
+            let _ = binded.send((self.address, payload.to_vec()));
+        }
+        Ok(payload.len())
+    }
+
+    /// Receives a packet from this socket, waiting for it in blocking mode.
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> Result<(&'a [u8], SocketAddr)> {
+        let packet = if !self.is_blocking_mode {
+            self.receiver.try_recv().map_err(|err| match err {
+                TryRecvError::Empty => ErrorKind::WouldBlock,
+                TryRecvError::Disconnected => ErrorKind::NotConnected,
+            })
+        } else if let Some(timeout) = self.read_timeout {
+            self.receiver
+                .recv_timeout(timeout)
+                .map_err(|err| match err {
+                    RecvTimeoutError::Timeout => ErrorKind::WouldBlock,
+                    RecvTimeoutError::Disconnected => ErrorKind::NotConnected,
+                })
+        } else {
+            self.receiver.recv().map_err(|_| ErrorKind::NotConnected)
+        };
+
+        let (addr, payload) = packet?;
+        let slice = &mut buffer[..payload.len()];
+        slice.copy_from_slice(payload.as_ref());
+        Ok((slice, addr))
+    }
+
+    /// Returns the socket address that this socket was created from.
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.address)
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.is_blocking_mode
+    }
+}
+
+impl Drop for ThreadedEmulatedSocket {
+    /// Frees the address, so that another socket can be bound to it.
+    fn drop(&mut self) {
+        if let Ok(mut network) = self.network.lock() {
+            network.remove(&self.address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::test_utils::FakeSocket;
+    use crate::{Config, Packet, SocketEvent};
+
+    use super::ThreadedNetworkEmulator;
+
+    fn server_address() -> SocketAddr {
+        "127.0.0.1:10001".parse().unwrap()
+    }
+
+    fn client_address() -> SocketAddr {
+        "127.0.0.1:10002".parse().unwrap()
+    }
+
+    #[test]
+    fn echo_server_runs_in_polling_thread() {
+        let network = ThreadedNetworkEmulator::default();
+        let mut server =
+            FakeSocket::bind_threaded(&network, server_address(), Config::default()).unwrap();
+        let mut client =
+            FakeSocket::bind_threaded(&network, client_address(), Config::default()).unwrap();
+
+        let sender = server.get_packet_sender();
+        let receiver = server.get_event_receiver();
+        let stop = Arc::new(AtomicBool::new(false));
+        let polling = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    server.manual_poll(Instant::now());
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        // ends once the polling thread dropped the socket and with it the event sender
+        let echoing = thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    let echo = Packet::reliable_unordered(packet.addr(), packet.payload().to_vec());
+                    if sender.send(echo).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let events = client.get_event_receiver();
+        client
+            .send(Packet::reliable_unordered(server_address(), vec![1, 2, 3]))
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut echo = None;
+        while echo.is_none() && Instant::now() < deadline {
+            client.manual_poll(Instant::now());
+            if let Ok(SocketEvent::Packet(packet)) = events.recv_timeout(Duration::from_millis(10))
+            {
+                echo = Some(packet);
+            }
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        polling.join().unwrap();
+        echoing.join().unwrap();
+        assert_eq!(
+            echo,
+            Some(Packet::reliable_unordered(server_address(), vec![1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn blocking_poll_waits_for_packets_up_to_the_read_timeout() {
+        let network = ThreadedNetworkEmulator::default();
+        let config = Config {
+            blocking_mode: true,
+            ..Config::default()
+        };
+        let mut server = FakeSocket::bind_threaded(&network, server_address(), config).unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(50)));
+        let mut client =
+            FakeSocket::bind_threaded(&network, client_address(), Config::default()).unwrap();
+
+        let start = Instant::now();
+        server.manual_poll(start);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(server.recv(), None);
+
+        let polling = thread::spawn(move || {
+            server.set_read_timeout(None);
+            server.manual_poll(Instant::now());
+            server.recv()
+        });
+        client
+            .send(Packet::unreliable(server_address(), vec![1]))
+            .unwrap();
+        client.manual_poll(Instant::now());
+
+        assert_eq!(
+            polling.join().unwrap(),
+            Some(SocketEvent::Packet(Packet::unreliable(
+                client_address(),
+                vec![1]
+            )))
+        );
+    }
+
+    #[test]
+    fn address_is_freed_when_socket_is_dropped() {
+        let network = ThreadedNetworkEmulator::default();
+        let socket = network.new_socket(server_address()).unwrap();
+        assert!(network.new_socket(server_address()).is_err());
+
+        drop(socket);
+        assert!(network.new_socket(server_address()).is_ok());
+    }
+}
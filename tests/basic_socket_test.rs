@@ -1,8 +1,13 @@
-use std::{collections::HashSet, net::SocketAddr, time::Instant};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "tester")]
 use laminar::LinkConditioner;
-use laminar::{Config, Packet, Socket, SocketEvent};
+use laminar::{Config, Packet, PcapReader, Socket, SocketEvent};
 
 #[test]
 fn binding_to_any() {
@@ -60,6 +65,58 @@ fn local_addr() {
     assert_eq!(port, socket.local_addr().unwrap().port());
 }
 
+#[test]
+fn capture_records_sent_and_received_datagrams() {
+    let mut client = Socket::bind_any().unwrap();
+    let mut server = Socket::bind_any().unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let path = std::env::temp_dir().join(format!("laminar-capture-{}.pcap", server_addr.port()));
+    server
+        .start_capture(std::fs::File::create(&path).unwrap())
+        .unwrap();
+
+    client
+        .send(Packet::unreliable(server_addr, b"Hello".to_vec()))
+        .unwrap();
+    client.manual_poll(Instant::now());
+
+    // the datagram travels through the real network, so it may take a few polls to arrive
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received = None;
+    while received.is_none() && Instant::now() < deadline {
+        server.manual_poll(Instant::now());
+        received = server.recv();
+        sleep(Duration::from_millis(1));
+    }
+    assert!(matches!(received, Some(SocketEvent::Packet(_))));
+
+    server
+        .send(Packet::unreliable(client_addr, b"world!".to_vec()))
+        .unwrap();
+    server.manual_poll(Instant::now());
+    server.stop_capture().unwrap();
+
+    let datagrams: Vec<_> = PcapReader::new(std::fs::File::open(&path).unwrap())
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(datagrams.len(), 2);
+    assert_eq!(
+        (datagrams[0].source, datagrams[0].destination),
+        (client_addr, server_addr)
+    );
+    assert!(datagrams[0].payload.ends_with(b"Hello"));
+    assert_eq!(
+        (datagrams[1].source, datagrams[1].destination),
+        (server_addr, client_addr)
+    );
+    assert!(datagrams[1].payload.ends_with(b"world!"));
+}
+
 #[test]
 #[cfg(feature = "tester")]
 fn use_link_conditioner() {